@@ -1,4 +1,6 @@
 use std::env;
+use std::fmt::Write;
+use std::fs;
 use std::path::PathBuf;
 
 fn main() {
@@ -36,4 +38,40 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    write_method_table(&out_path);
+}
+
+/// Writes a table of every function exported by `liblnd.h` together with its name,
+/// so FFI function pointers can be mapped back to lnd method names at runtime.
+fn write_method_table(out_path: &std::path::Path) {
+    let header = fs::read_to_string("./liblnd.h").expect("Unable to read liblnd.h");
+
+    let mut table = String::from(
+        "pub(crate) static LND_METHODS: std::sync::LazyLock<Vec<(&str, usize)>> =\n    \
+         std::sync::LazyLock::new(|| vec![\n",
+    );
+    for line in header.lines() {
+        let Some(declaration) = line.strip_prefix("extern ") else {
+            continue;
+        };
+        let Some(paren) = declaration.find('(') else {
+            continue;
+        };
+        let name = declaration[..paren]
+            .rsplit([' ', '*'])
+            .next()
+            .unwrap_or_default();
+        if !name.is_empty() {
+            writeln!(
+                table,
+                "        (\"{0}\", crate::{0} as *const () as usize),",
+                name
+            )
+            .unwrap();
+        }
+    }
+    table.push_str("    ]);\n");
+
+    fs::write(out_path.join("lnd_methods.rs"), table).expect("Couldn't write method table!");
 }
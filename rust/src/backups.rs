@@ -0,0 +1,313 @@
+use crate::{
+    exportAllChannelBackups, restoreChannelBackups, subscribeChannelBackups, verifyChanBackup,
+    LndClient, SubscriptionId,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
+use lnd_grpc_rust::lnrpc::restore_chan_backup_request::Backup;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+type EventHandler = Box<dyn Fn(Result<lnrpc::MultiChanBackup, String>) + Send + Sync>;
+type StoreCallback = Box<dyn Fn(&lnrpc::MultiChanBackup) -> Result<()> + Send + Sync>;
+
+/// Where a `BackupManager` keeps the channel backups it verified.
+pub trait BackupSink: Send + Sync {
+    /// Stores a verified multi-channel backup, replacing the previous one.
+    fn store(&self, backup: &lnrpc::MultiChanBackup) -> Result<()>;
+
+    /// Returns the latest stored backup, if the sink can read it back.
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+impl<S: BackupSink + ?Sized> BackupSink for Arc<S> {
+    fn store(&self, backup: &lnrpc::MultiChanBackup) -> Result<()> {
+        (**self).store(backup)
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        (**self).load()
+    }
+}
+
+/// Keeps the backup in a file, replaced atomically, along with the previous versions.
+///
+/// With `keep` set to 2, storing a backup to `channel.backup` first copies the current
+/// file to `channel.backup.1`, and that one to `channel.backup.2`.
+#[derive(Debug, Clone)]
+pub struct FileBackupSink {
+    path: PathBuf,
+    keep: usize,
+}
+
+impl FileBackupSink {
+    /// Creates a sink that writes to `path` and keeps no previous versions.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            keep: 0,
+        }
+    }
+
+    /// Keeps the given number of previous versions next to the file.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Returns the path of a previous version, counting from 1 for the most recent one.
+    pub fn rotated(&self, version: usize) -> PathBuf {
+        self.with_suffix(&version.to_string())
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".");
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for version in (1..self.keep).rev() {
+            let from = self.rotated(version);
+            if from.exists() {
+                fs::rename(&from, self.rotated(version + 1))
+                    .context("Failed to rotate channel backup")?;
+            }
+        }
+        // Copied rather than moved, so a backup file exists at all times.
+        fs::copy(&self.path, self.rotated(1)).context("Failed to rotate channel backup")?;
+        Ok(())
+    }
+}
+
+impl BackupSink for FileBackupSink {
+    fn store(&self, backup: &lnrpc::MultiChanBackup) -> Result<()> {
+        let temp = self.with_suffix("tmp");
+        let mut file = fs::File::create(&temp).context("Failed to write channel backup")?;
+        file.write_all(&backup.multi_chan_backup)
+            .and_then(|()| file.sync_all())
+            .context("Failed to write channel backup")?;
+        self.rotate()?;
+        fs::rename(&temp, &self.path).context("Failed to replace channel backup")
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(backup) => Ok(Some(backup)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read channel backup"),
+        }
+    }
+}
+
+/// Hands every backup to a callback, e.g. to upload it.
+pub struct CallbackBackupSink {
+    callback: StoreCallback,
+}
+
+impl CallbackBackupSink {
+    /// Creates a sink that calls `callback` with every verified backup.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&lnrpc::MultiChanBackup) -> Result<()> + Send + Sync + 'static,
+    {
+        Self {
+            callback: Box::new(callback),
+        }
+    }
+}
+
+impl BackupSink for CallbackBackupSink {
+    fn store(&self, backup: &lnrpc::MultiChanBackup) -> Result<()> {
+        (self.callback)(backup)
+    }
+}
+
+/// Wraps a multi-channel backup for `verifyChanBackup` or the `channel_backups` of
+/// `initWallet`, to restore channels while the wallet is created.
+pub fn multi_backup_snapshot(multi_chan_backup: Vec<u8>) -> lnrpc::ChanBackupSnapshot {
+    lnrpc::ChanBackupSnapshot {
+        single_chan_backups: None,
+        multi_chan_backup: Some(lnrpc::MultiChanBackup {
+            chan_points: Vec::new(),
+            multi_chan_backup,
+        }),
+    }
+}
+
+struct Inner {
+    client: LndClient,
+    sink: Box<dyn BackupSink>,
+    handler: EventHandler,
+    /// Held while a backup is exported and stored, so an older one never replaces a newer.
+    storing: Mutex<()>,
+    subscription: Mutex<Option<SubscriptionId>>,
+}
+
+impl Inner {
+    fn verify(&self, multi_chan_backup: &[u8]) -> Result<()> {
+        let _: lnrpc::VerifyChanBackupResponse = self
+            .client
+            .call_lnd_method(
+                multi_backup_snapshot(multi_chan_backup.to_vec()),
+                verifyChanBackup,
+            )
+            .context("Channel backup failed verification")?;
+        Ok(())
+    }
+
+    fn store(&self, backup: Option<lnrpc::MultiChanBackup>) -> Result<lnrpc::MultiChanBackup> {
+        let backup = backup.context("Channel backup snapshot without multi-channel backup")?;
+        self.verify(&backup.multi_chan_backup)?;
+        self.sink.store(&backup)?;
+        Ok(backup)
+    }
+
+    fn on_snapshot(&self, snapshot: Result<lnrpc::ChanBackupSnapshot, String>) {
+        let _storing = self.storing.lock().unwrap_or_else(PoisonError::into_inner);
+        let stored = match snapshot {
+            Ok(snapshot) => self
+                .store(snapshot.multi_chan_backup)
+                .map_err(|e| format!("{:#}", e)),
+            Err(error) => {
+                *self
+                    .subscription
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = None;
+                Err(error)
+            }
+        };
+        (self.handler)(stored);
+    }
+}
+
+/// Keeps an up-to-date static channel backup.
+///
+/// Every backup lnd produces when channels change is verified with `verifyChanBackup`
+/// before it is handed to the `BackupSink`, so a sink never holds an unusable backup.
+#[derive(Clone)]
+pub struct BackupManager {
+    inner: Arc<Inner>,
+}
+
+impl BackupManager {
+    /// Creates a backup manager. Nothing is stored until `start` or `backup_now`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to back up.
+    /// * `sink` - Where backups are stored.
+    /// * `on_backup` - Receives every stored backup, and failures as `Err`.
+    pub fn new<S, F>(client: LndClient, sink: S, on_backup: F) -> Self
+    where
+        S: BackupSink + 'static,
+        F: Fn(Result<lnrpc::MultiChanBackup, String>) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                sink: Box::new(sink),
+                handler: Box::new(on_backup),
+                storing: Mutex::new(()),
+                subscription: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Stores the current backup and then every new one lnd produces.
+    ///
+    /// Call it again after the stream reported an error to resume.
+    pub fn start(&self) -> Result<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let id = self
+            .inner
+            .client
+            .subscribe_events::<lnrpc::ChanBackupSnapshot, lnrpc::ChannelBackupSubscription>(
+                subscribeChannelBackups,
+            )
+            .on_event(move |snapshot| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.on_snapshot(snapshot);
+                }
+            })
+            .with_request(lnrpc::ChannelBackupSubscription {})
+            .subscribe()?;
+        let previous = self
+            .inner
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(id);
+        if let Some(previous) = previous {
+            self.inner.client.unsubscribe(previous);
+        }
+
+        // Changes made before subscribing are only covered by an explicit export.
+        self.backup_now().map(|_| ())
+    }
+
+    /// Stops following new backups.
+    pub fn stop(&self) {
+        let subscription = self
+            .inner
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.inner.client.unsubscribe(id);
+        }
+    }
+
+    /// Exports, verifies and stores the current backup with `exportAllChannelBackups`.
+    pub fn backup_now(&self) -> Result<lnrpc::MultiChanBackup> {
+        let _storing = self
+            .inner
+            .storing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let snapshot: lnrpc::ChanBackupSnapshot = self
+            .inner
+            .client
+            .call_lnd_method(lnrpc::ChanBackupExportRequest {}, exportAllChannelBackups)?;
+        let stored = self.inner.store(snapshot.multi_chan_backup);
+        (self.inner.handler)(stored.as_ref().map_err(|e| format!("{:#}", e)).cloned());
+        stored
+    }
+
+    /// Restores the channels of a multi-channel backup into an unlocked wallet.
+    ///
+    /// The backup is verified first, so nothing is restored from a corrupt backup. To
+    /// restore while creating the wallet, pass `multi_backup_snapshot` to `initWallet`.
+    pub fn restore(&self, multi_chan_backup: Vec<u8>) -> Result<()> {
+        self.inner.verify(&multi_chan_backup)?;
+        let _: lnrpc::RestoreBackupResponse = self.inner.client.call_lnd_method(
+            lnrpc::RestoreChanBackupRequest {
+                backup: Some(Backup::MultiChanBackup(multi_chan_backup)),
+            },
+            restoreChannelBackups,
+        )?;
+        Ok(())
+    }
+
+    /// Restores the latest backup held by the sink.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the sink has no backup to restore.
+    pub fn restore_from_sink(&self) -> Result<bool> {
+        match self.inner.sink.load()? {
+            Some(backup) => self.restore(backup).map(|()| true),
+            None => Ok(false),
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use crate::lnd_client::StreamOptions;
 use crate::CRecvStream;
 use crate::LndClient;
+use crate::PanicPolicy;
 use anyhow::Result;
 use lnd_grpc_rust::prost::Message;
 use std::marker::PhantomData;
@@ -13,6 +15,7 @@ pub struct BidiStreamBuilder<'a, Req, Resp> {
     stream_func: unsafe extern "C" fn(CRecvStream) -> usize,
     on_request: Option<OnRequest<Req>>,
     get_response: Option<GetResponse<Req, Resp>>,
+    options: StreamOptions,
     _phantom: PhantomData<(Req, Resp)>,
 }
 
@@ -30,6 +33,7 @@ where
             stream_func,
             on_request: None,
             get_response: None,
+            options: StreamOptions::default(),
             _phantom: PhantomData,
         }
     }
@@ -52,6 +56,15 @@ where
         self
     }
 
+    /// Sets what happens to the stream when one of its callbacks panics.
+    ///
+    /// The panic is always reported to the hook set with `set_error_hook`. Defaults to
+    /// `PanicPolicy::Close`, which stops the stream.
+    pub fn on_panic(mut self, policy: PanicPolicy) -> Self {
+        self.options.panic_policy = policy;
+        self
+    }
+
     /// Builds and starts the bidirectional stream.
     ///
     /// # Returns
@@ -65,7 +78,11 @@ where
             .get_response
             .ok_or_else(|| anyhow::anyhow!("get_response callback not set"))?;
 
-        self.client
-            .setup_bidirectional_stream(self.stream_func, on_request, get_response)
+        self.client.setup_bidirectional_stream(
+            self.stream_func,
+            on_request,
+            get_response,
+            self.options,
+        )
     }
 }
@@ -0,0 +1,90 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, PoisonError, RwLock};
+
+type ErrorHook = Arc<dyn Fn(&CallbackPanic) + Send + Sync>;
+
+static ERROR_HOOK: RwLock<Option<ErrorHook>> = RwLock::new(None);
+
+/// A panic that was caught in a callback invoked by lnd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallbackPanic {
+    /// The lnd method whose callback panicked.
+    pub method: String,
+    /// The panic message, if it was a string.
+    pub message: String,
+}
+
+impl fmt::Display for CallbackPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Callback for {} panicked: {}", self.method, self.message)
+    }
+}
+
+impl std::error::Error for CallbackPanic {}
+
+/// What happens to a stream after one of its callbacks panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Stop delivering messages and close the stream.
+    #[default]
+    Close,
+    /// Keep the stream open and continue with the next message.
+    Resume,
+}
+
+/// Sets the hook that receives every panic caught in a callback invoked by lnd.
+///
+/// Callbacks run on threads owned by lnd, where an unwinding panic would abort the
+/// whole process, so they are always caught and reported here instead.
+pub fn set_error_hook<F>(hook: F)
+where
+    F: Fn(&CallbackPanic) + Send + Sync + 'static,
+{
+    *ERROR_HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(hook));
+}
+
+/// Removes the hook set with `set_error_hook`.
+pub fn clear_error_hook() {
+    *ERROR_HOOK.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// Runs `f`, catching any panic and reporting it to the error hook.
+///
+/// # Returns
+///
+/// `None` if `f` panicked.
+pub(crate) fn guard<R>(method: &str, f: impl FnOnce() -> R) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            report(CallbackPanic {
+                method: method.to_string(),
+                message: panic_message(payload.as_ref()),
+            });
+            None
+        }
+    }
+}
+
+fn report(panic: CallbackPanic) {
+    let hook = ERROR_HOOK
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let Some(hook) = hook {
+        // A panicking hook must not unwind into lnd either.
+        let _ = catch_unwind(AssertUnwindSafe(|| hook(&panic)));
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
@@ -0,0 +1,428 @@
+use crate::channels::txid_to_string;
+use crate::{
+    chainNotifierRegisterBlockEpochNtfn, chainNotifierRegisterConfirmationsNtfn,
+    chainNotifierRegisterSpendNtfn, CRecvStream, LndClient, SubscriptionId,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::chainrpc;
+use lnd_grpc_rust::chainrpc::conf_event::Event as ConfUpdate;
+use lnd_grpc_rust::chainrpc::spend_event::Event as SpendUpdate;
+use lnd_grpc_rust::prost::Message;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+/// Converts a txid as shown in block explorers to the byte order lnd expects.
+pub(crate) fn txid_to_bytes(txid: &str) -> Result<Vec<u8>> {
+    let mut bytes = hex::decode(txid).with_context(|| format!("Invalid txid {}", txid))?;
+    if bytes.len() != 32 {
+        return Err(anyhow::anyhow!("Invalid txid {}", txid));
+    }
+    bytes.reverse();
+    Ok(bytes)
+}
+
+/// Where a watched transaction confirmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Confirmation {
+    /// The hash of the block the transaction is in.
+    pub block_hash: String,
+    /// The height of that block.
+    pub block_height: u32,
+    /// The position of the transaction in the block.
+    pub tx_index: u32,
+    /// The raw confirmed transaction.
+    pub raw_tx: Vec<u8>,
+}
+
+/// How a watched output was spent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spend {
+    /// The id of the spending transaction.
+    pub spending_txid: String,
+    /// The input of the spending transaction that spends the output.
+    pub spending_input_index: u32,
+    /// The height of the block the spend confirmed in.
+    pub spending_height: u32,
+    /// The raw spending transaction.
+    pub raw_spending_tx: Vec<u8>,
+}
+
+/// The state of a confirmation or spend watched through a `ChainNotifier`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchState<T> {
+    /// Not seen on chain yet.
+    Waiting,
+    /// Seen on chain.
+    Done(T),
+    /// Seen on chain before, but the block was reorganized out. lnd reports it again
+    /// once it is back on chain.
+    Reorged,
+    /// The notification stream failed.
+    Failed(String),
+}
+
+struct Shared<T> {
+    client: LndClient,
+    state: Mutex<WatchState<T>>,
+    changed: Condvar,
+    reorgs: AtomicU32,
+    subscription: Mutex<Option<SubscriptionId>>,
+}
+
+impl<T> Shared<T> {
+    fn update(&self, next: WatchState<T>) {
+        if matches!(next, WatchState::Reorged) {
+            self.reorgs.fetch_add(1, Ordering::AcqRel);
+        }
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = next;
+        self.changed.notify_all();
+    }
+
+    fn stop(&self) {
+        let subscription = self
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.client.unsubscribe(id);
+        }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A confirmation or spend followed until the last handle is dropped or `cancel` is
+/// called, so reorgs after the first notification are still seen.
+pub struct ChainWatch<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for ChainWatch<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Clone> ChainWatch<T> {
+    /// Returns the latest state.
+    pub fn state(&self) -> WatchState<T> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns how often the notification was reorganized out so far.
+    pub fn reorgs(&self) -> u32 {
+        self.shared.reorgs.load(Ordering::Acquire)
+    }
+
+    /// Waits until the notification is on chain.
+    ///
+    /// # Returns
+    ///
+    /// The details, or an error after `timeout` or if the stream failed.
+    pub fn wait(&self, timeout: Duration) -> Result<T> {
+        let state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| {
+                matches!(state, WatchState::Waiting | WatchState::Reorged)
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        match &*state {
+            WatchState::Done(details) => Ok(details.clone()),
+            WatchState::Failed(error) => Err(anyhow::anyhow!(error.clone())),
+            _ => Err(anyhow::anyhow!("Timed out waiting for the chain")),
+        }
+    }
+
+    /// Waits until the notification is reorganized out.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a reorg happened within `timeout`.
+    pub fn wait_for_reorg(&self, timeout: Duration) -> bool {
+        let reorgs = self.reorgs();
+        let state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (_state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| {
+                self.reorgs() == reorgs && !matches!(state, WatchState::Failed(_))
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        self.reorgs() != reorgs
+    }
+
+    /// Stops following the notification.
+    pub fn cancel(&self) {
+        self.shared.stop();
+    }
+}
+
+/// A change of the best block seen by a `BlockWatcher`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockEvent {
+    /// A block extended the chain.
+    Connected {
+        /// The height of the block.
+        height: u32,
+        /// The hash of the block.
+        hash: String,
+    },
+    /// A block replaced the tip at the same or a lower height.
+    Reorg {
+        /// The height of the tip that was replaced.
+        previous_height: u32,
+        /// The height of the new tip.
+        height: u32,
+        /// The hash of the new tip.
+        hash: String,
+    },
+}
+
+/// Follows the best block until dropped or stopped.
+pub struct BlockWatcher {
+    client: LndClient,
+    tip: Arc<Mutex<Option<(u32, String)>>>,
+    subscription: Mutex<Option<SubscriptionId>>,
+}
+
+impl BlockWatcher {
+    /// Returns the height and hash of the best block seen so far.
+    pub fn tip(&self) -> Option<(u32, String)> {
+        self.tip
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Stops following blocks.
+    pub fn stop(&self) {
+        let subscription = self
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.client.unsubscribe(id);
+        }
+    }
+}
+
+impl Drop for BlockWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Typed access to lnd's chain notifier.
+#[derive(Clone)]
+pub struct ChainNotifier {
+    client: LndClient,
+}
+
+impl ChainNotifier {
+    /// Creates a chain notifier.
+    pub fn new(client: LndClient) -> Self {
+        Self { client }
+    }
+
+    /// Watches for a transaction to confirm.
+    ///
+    /// # Arguments
+    ///
+    /// * `txid` - The transaction id as shown in block explorers.
+    /// * `script` - An output script of the transaction, required by light clients.
+    /// * `num_confs` - How many confirmations to wait for.
+    /// * `height_hint` - The earliest height the transaction could have confirmed at.
+    pub fn wait_for_confirmations(
+        &self,
+        txid: &str,
+        script: &[u8],
+        num_confs: u32,
+        height_hint: u32,
+    ) -> Result<ChainWatch<Confirmation>> {
+        let request = chainrpc::ConfRequest {
+            txid: txid_to_bytes(txid)?,
+            script: script.to_vec(),
+            num_confs: num_confs.max(1),
+            height_hint,
+            include_block: false,
+        };
+        self.watch(
+            chainNotifierRegisterConfirmationsNtfn,
+            request,
+            |event: chainrpc::ConfEvent| match event.event {
+                Some(ConfUpdate::Conf(details)) => Some(WatchState::Done(Confirmation {
+                    block_hash: txid_to_string(&details.block_hash),
+                    block_height: details.block_height,
+                    tx_index: details.tx_index,
+                    raw_tx: details.raw_tx,
+                })),
+                Some(ConfUpdate::Reorg(_)) => Some(WatchState::Reorged),
+                None => None,
+            },
+        )
+    }
+
+    /// Watches for an output to be spent.
+    ///
+    /// # Arguments
+    ///
+    /// * `outpoint` - The output as `txid:output_index`.
+    /// * `script` - The output's script, required by light clients.
+    /// * `height_hint` - The earliest height the output could have been spent at.
+    pub fn wait_for_spend(
+        &self,
+        outpoint: &str,
+        script: &[u8],
+        height_hint: u32,
+    ) -> Result<ChainWatch<Spend>> {
+        let (txid, index) = outpoint
+            .split_once(':')
+            .with_context(|| format!("Invalid outpoint {}", outpoint))?;
+        let request = chainrpc::SpendRequest {
+            outpoint: Some(chainrpc::Outpoint {
+                hash: txid_to_bytes(txid)?,
+                index: index
+                    .parse()
+                    .with_context(|| format!("Invalid outpoint {}", outpoint))?,
+            }),
+            script: script.to_vec(),
+            height_hint,
+        };
+        self.watch(
+            chainNotifierRegisterSpendNtfn,
+            request,
+            |event: chainrpc::SpendEvent| match event.event {
+                Some(SpendUpdate::Spend(details)) => Some(WatchState::Done(Spend {
+                    spending_txid: txid_to_string(&details.spending_tx_hash),
+                    spending_input_index: details.spending_input_index,
+                    spending_height: details.spending_height,
+                    raw_spending_tx: details.raw_spending_tx,
+                })),
+                Some(SpendUpdate::Reorg(_)) => Some(WatchState::Reorged),
+                None => None,
+            },
+        )
+    }
+
+    /// Follows the best block, reporting every new block and every reorg to `on_block`.
+    ///
+    /// A reorg is detected when a block arrives that does not extend the previous tip's
+    /// height. Stream errors are passed as `Err`.
+    pub fn watch_blocks<F>(&self, on_block: F) -> Result<BlockWatcher>
+    where
+        F: Fn(Result<BlockEvent, String>) + Send + Sync + 'static,
+    {
+        let tip = Arc::new(Mutex::new(None::<(u32, String)>));
+        let latest = tip.clone();
+        let id = self
+            .client
+            .subscribe_events::<chainrpc::BlockEpoch, chainrpc::BlockEpoch>(
+                chainNotifierRegisterBlockEpochNtfn,
+            )
+            .on_event(move |block| {
+                let block = match block {
+                    Ok(block) => block,
+                    Err(error) => return on_block(Err(error)),
+                };
+                let hash = txid_to_string(&block.hash);
+                let previous = latest
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .replace((block.height, hash.clone()));
+                let event = match previous {
+                    Some((previous_height, previous_hash))
+                        if block.height <= previous_height && hash != previous_hash =>
+                    {
+                        BlockEvent::Reorg {
+                            previous_height,
+                            height: block.height,
+                            hash,
+                        }
+                    }
+                    _ => BlockEvent::Connected {
+                        height: block.height,
+                        hash,
+                    },
+                };
+                on_block(Ok(event));
+            })
+            .with_request(chainrpc::BlockEpoch::default())
+            .subscribe()?;
+        Ok(BlockWatcher {
+            client: self.client.clone(),
+            tip,
+            subscription: Mutex::new(Some(id)),
+        })
+    }
+
+    fn watch<E, R, T, F>(
+        &self,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        request: R,
+        convert: F,
+    ) -> Result<ChainWatch<T>>
+    where
+        E: Message + Default + 'static,
+        R: Message + 'static,
+        T: Send + 'static,
+        F: Fn(E) -> Option<WatchState<T>> + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            client: self.client.clone(),
+            state: Mutex::new(WatchState::Waiting),
+            changed: Condvar::new(),
+            reorgs: AtomicU32::new(0),
+            subscription: Mutex::new(None),
+        });
+
+        // The stream only holds a weak reference, so dropping the handles ends it.
+        let weak: Weak<Shared<T>> = Arc::downgrade(&shared);
+        let id = self
+            .client
+            .subscribe_events::<E, R>(subscribe_func)
+            .on_event(move |event| {
+                let Some(shared) = weak.upgrade() else {
+                    return;
+                };
+                match event {
+                    Ok(event) => {
+                        if let Some(state) = convert(event) {
+                            shared.update(state);
+                        }
+                    }
+                    Err(error) => shared.update(WatchState::Failed(error)),
+                }
+            })
+            .with_request(request)
+            .subscribe()?;
+        *shared
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(id);
+        Ok(ChainWatch { shared })
+    }
+}
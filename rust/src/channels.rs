@@ -0,0 +1,702 @@
+use crate::{
+    closeChannel, listChannels, openChannel, pendingChannels, subscribeChannelEvents, LndClient,
+    SubscriptionId,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
+use lnd_grpc_rust::lnrpc::channel_event_update::Channel as ChannelEvent;
+use lnd_grpc_rust::lnrpc::channel_point::FundingTxid;
+use lnd_grpc_rust::lnrpc::close_status_update::Update as CloseUpdate;
+use lnd_grpc_rust::lnrpc::open_status_update::Update as OpenUpdate;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long a channel event for a channel that is not followed is kept, waiting for
+/// the `openChannel` stream to report the channel point.
+const SEEN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Receives the PSBT funding request of a shimmed open, or the error that ended it first.
+pub(crate) type PsbtHook = Box<dyn Fn(Result<lnrpc::ReadyForPsbtFunding, String>) + Send + Sync>;
+
+/// The state of a channel followed by a `ChannelManager`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelState {
+    /// The open was requested, but no funding transaction was published yet.
+    Opening,
+    /// The funding transaction was published and waits for confirmations.
+    Pending {
+        /// The funding transaction id.
+        txid: String,
+    },
+    /// The funding transaction is confirmed deeply enough to use the channel.
+    Confirmed,
+    /// The channel is open and the peer is online.
+    Active,
+    /// The closing transaction was published and waits for confirmation.
+    Closing {
+        /// The closing transaction id.
+        closing_txid: String,
+    },
+    /// The channel is closed.
+    Closed {
+        /// The closing transaction id.
+        closing_txid: String,
+    },
+    /// Opening the channel failed before the funding transaction was published.
+    Failed(String),
+}
+
+impl ChannelState {
+    /// Returns `true` once the channel is closed or the operation failed.
+    pub fn is_final(&self) -> bool {
+        matches!(self, ChannelState::Closed { .. } | ChannelState::Failed(_))
+    }
+
+    /// The position of the state in a channel's life, which only moves forward.
+    fn rank(&self) -> u8 {
+        match self {
+            ChannelState::Opening => 0,
+            ChannelState::Pending { .. } => 1,
+            ChannelState::Confirmed => 2,
+            ChannelState::Active => 3,
+            ChannelState::Closing { .. } => 4,
+            ChannelState::Closed { .. } | ChannelState::Failed(_) => 5,
+        }
+    }
+}
+
+/// Parses a `txid:output_index` channel point.
+pub fn parse_channel_point(channel_point: &str) -> Result<lnrpc::ChannelPoint> {
+    let (txid, output_index) = channel_point
+        .split_once(':')
+        .with_context(|| format!("Invalid channel point {}", channel_point))?;
+    Ok(lnrpc::ChannelPoint {
+        output_index: output_index
+            .parse()
+            .with_context(|| format!("Invalid channel point {}", channel_point))?,
+        funding_txid: Some(FundingTxid::FundingTxidStr(txid.to_string())),
+    })
+}
+
+/// Formats a channel point as `txid:output_index`.
+pub(crate) fn format_channel_point(channel_point: &lnrpc::ChannelPoint) -> String {
+    let txid = match &channel_point.funding_txid {
+        Some(FundingTxid::FundingTxidStr(txid)) => txid.clone(),
+        Some(FundingTxid::FundingTxidBytes(bytes)) => txid_to_string(bytes),
+        None => String::new(),
+    };
+    format!("{}:{}", txid, channel_point.output_index)
+}
+
+/// Converts a txid from the byte order lnd sends to the one shown in block explorers.
+pub(crate) fn txid_to_string(bytes: &[u8]) -> String {
+    let mut bytes = bytes.to_vec();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+/// The `openChannel` or `closeChannel` stream of a channel.
+#[derive(Default)]
+struct Stream {
+    id: Option<SubscriptionId>,
+    /// Whether the stream had nothing more to say, possibly before its id was known.
+    ended: bool,
+}
+
+/// A channel followed by a `ChannelManager`.
+struct Tracked {
+    channel_point: Mutex<Option<String>>,
+    state: Mutex<ChannelState>,
+    changed: Condvar,
+    stream: Mutex<Stream>,
+    /// The error of the last close that failed before the closing transaction was
+    /// published, which leaves the channel as it was.
+    close_error: Mutex<Option<String>>,
+}
+
+impl Tracked {
+    fn new(channel_point: Option<String>, state: ChannelState) -> Arc<Self> {
+        Arc::new(Self {
+            channel_point: Mutex::new(channel_point),
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            stream: Mutex::new(Stream::default()),
+            close_error: Mutex::new(None),
+        })
+    }
+
+    fn close_error(&self) -> Option<String> {
+        self.close_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Records a failed close and wakes the waiters, without changing the state.
+    fn fail_close(&self, error: String) {
+        *self
+            .close_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(error);
+        let _state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.changed.notify_all();
+    }
+
+    fn state(&self) -> ChannelState {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn channel_point(&self) -> Option<String> {
+        self.channel_point
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Moves to `next`, ignoring updates that arrive out of order.
+    fn advance(&self, next: ChannelState) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if next.rank() > state.rank() {
+            *state = next;
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// A handle to a channel opened, closed or recovered through a `ChannelManager`.
+#[derive(Clone)]
+pub struct ChannelHandle {
+    tracked: Arc<Tracked>,
+}
+
+impl ChannelHandle {
+    /// Returns the channel point, once the funding transaction is known.
+    pub fn channel_point(&self) -> Option<String> {
+        self.tracked.channel_point()
+    }
+
+    /// Returns the latest known state.
+    pub fn state(&self) -> ChannelState {
+        self.tracked.state()
+    }
+
+    /// Returns the error of the last close that failed before the closing transaction
+    /// was published. The channel then keeps its state.
+    pub fn close_error(&self) -> Option<String> {
+        self.tracked.close_error()
+    }
+
+    /// Waits until `done` returns `true` for the channel's state.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait.
+    /// * `done` - Decides whether the state is the one waited for.
+    ///
+    /// # Returns
+    ///
+    /// The state that satisfied `done`, or an error after `timeout`, if the channel
+    /// reached a final state that does not satisfy it or if a close failed.
+    pub fn wait_until<F>(&self, timeout: Duration, done: F) -> Result<ChannelState>
+    where
+        F: Fn(&ChannelState) -> bool,
+    {
+        let state = self
+            .tracked
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (state, _) = self
+            .tracked
+            .changed
+            .wait_timeout_while(state, timeout, |state| {
+                !done(state) && !state.is_final() && self.tracked.close_error().is_none()
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        if done(&state) {
+            return Ok(state.clone());
+        }
+        match self.tracked.close_error() {
+            Some(error) => Err(anyhow::anyhow!("Failed to close the channel: {}", error)),
+            None => Err(anyhow::anyhow!("Channel is {:?}", *state)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Channels {
+    /// Followed channels with a known channel point.
+    tracked: HashMap<String, Arc<Tracked>>,
+    /// The latest state reported by channel events for channels not followed (yet), as
+    /// they may arrive before the `openChannel` stream reports the channel point.
+    seen: HashMap<String, (ChannelState, Instant)>,
+}
+
+impl Channels {
+    /// Follows `tracked` under `channel_point`, applying any state seen for it before.
+    fn insert(&mut self, channel_point: String, tracked: Arc<Tracked>) {
+        if let Some((state, _)) = self.seen.remove(&channel_point) {
+            tracked.advance(state);
+        }
+        if !tracked.state().is_final() {
+            self.tracked.insert(channel_point, tracked);
+        }
+    }
+
+    fn update(&mut self, channel_point: String, state: ChannelState) {
+        self.seen.retain(|_, (_, at)| at.elapsed() < SEEN_TTL);
+        match self.tracked.get(&channel_point) {
+            Some(tracked) => {
+                tracked.advance(state);
+                if tracked.state().is_final() {
+                    self.tracked.remove(&channel_point);
+                }
+            }
+            // A closed channel is never opened through the manager anymore.
+            None if state.is_final() => {
+                self.seen.remove(&channel_point);
+            }
+            None => {
+                let (seen, at) = self
+                    .seen
+                    .entry(channel_point)
+                    .or_insert((ChannelState::Opening, Instant::now()));
+                if state.rank() > seen.rank() {
+                    *seen = state;
+                    *at = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Stops following a channel that reached a final state.
+    fn remove(&mut self, tracked: &Tracked) {
+        if let Some(channel_point) = tracked.channel_point() {
+            let followed = self
+                .tracked
+                .get(&channel_point)
+                .is_some_and(|followed| std::ptr::eq(followed.as_ref(), tracked));
+            if followed {
+                self.tracked.remove(&channel_point);
+            }
+        }
+    }
+}
+
+struct Inner {
+    channels: Mutex<Channels>,
+    events: Mutex<Option<SubscriptionId>>,
+}
+
+/// Opens and closes channels, and follows them until they are active or closed.
+///
+/// The `openChannel` and `closeChannel` streams are correlated with
+/// `subscribeChannelEvents` by channel point, so `recover` can hand out handles for
+/// channels that were still pending when the app last stopped.
+#[derive(Clone)]
+pub struct ChannelManager {
+    client: LndClient,
+    inner: Arc<Inner>,
+}
+
+impl ChannelManager {
+    /// Creates a channel manager.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            inner: Arc::new(Inner {
+                channels: Mutex::new(Channels::default()),
+                events: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Returns the client the manager opens channels through.
+    pub(crate) fn client(&self) -> &LndClient {
+        &self.client
+    }
+
+    /// Returns the channel with the given channel point, if it is followed.
+    pub fn get(&self, channel_point: &str) -> Option<ChannelHandle> {
+        self.inner
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .tracked
+            .get(channel_point)
+            .map(|tracked| ChannelHandle {
+                tracked: tracked.clone(),
+            })
+    }
+
+    /// Opens a channel.
+    ///
+    /// # Returns
+    ///
+    /// A handle that moves through `Pending`, `Confirmed` and `Active`, or to `Failed`.
+    pub fn open(&self, request: lnrpc::OpenChannelRequest) -> Result<ChannelHandle> {
+        self.open_with(request, None)
+    }
+
+    /// Opens a channel like `open`, handing a PSBT funding request to `on_psbt`.
+    pub(crate) fn open_with(
+        &self,
+        request: lnrpc::OpenChannelRequest,
+        on_psbt: Option<PsbtHook>,
+    ) -> Result<ChannelHandle> {
+        self.watch_events()?;
+        let tracked = Tracked::new(None, ChannelState::Opening);
+
+        let manager = self.weak();
+        let stream = tracked.clone();
+        let id = self
+            .client
+            .subscribe_events::<lnrpc::OpenStatusUpdate, lnrpc::OpenChannelRequest>(openChannel)
+            .on_event(move |update| {
+                let Some(manager) = manager() else {
+                    return;
+                };
+                match update.map(|update| update.update) {
+                    Ok(Some(OpenUpdate::ChanPending(pending))) => {
+                        let txid = txid_to_string(&pending.txid);
+                        manager.register(&stream, format!("{}:{}", txid, pending.output_index));
+                        stream.advance(ChannelState::Pending { txid });
+                    }
+                    Ok(Some(OpenUpdate::ChanOpen(open))) => {
+                        if let Some(channel_point) = &open.channel_point {
+                            manager.register(&stream, format_channel_point(channel_point));
+                        }
+                        stream.advance(ChannelState::Confirmed);
+                        manager.release(&stream);
+                    }
+                    Ok(Some(OpenUpdate::PsbtFund(ready))) => {
+                        if let Some(on_psbt) = &on_psbt {
+                            on_psbt(Ok(ready));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(error) if stream.state() == ChannelState::Opening => {
+                        if let Some(on_psbt) = &on_psbt {
+                            on_psbt(Err(error.clone()));
+                        }
+                        stream.advance(ChannelState::Failed(error));
+                        manager.release(&stream);
+                        manager.forget(&stream);
+                    }
+                    // Once the funding transaction is out, channel events report the rest.
+                    Err(_) => manager.release(&stream),
+                }
+            })
+            .with_request(request)
+            .subscribe()?;
+        self.attach(&tracked, id);
+
+        Ok(ChannelHandle { tracked })
+    }
+
+    /// Closes a channel.
+    ///
+    /// # Returns
+    ///
+    /// A handle that moves through `Closing` to `Closed`. If the close fails before the
+    /// closing transaction is published, the channel keeps its state and
+    /// `ChannelHandle::close_error` tells why. If the channel is already followed, its
+    /// existing handle is returned.
+    pub fn close(&self, request: lnrpc::CloseChannelRequest) -> Result<ChannelHandle> {
+        self.watch_events()?;
+        let channel_point = request
+            .channel_point
+            .as_ref()
+            .map(format_channel_point)
+            .context("Channel point not set")?;
+        let known = self.get(&channel_point).map(|handle| handle.tracked);
+        let tracked = match known {
+            Some(tracked) => tracked,
+            None => {
+                let tracked =
+                    Tracked::new(Some(channel_point.clone()), self.lookup(&channel_point)?);
+                self.register(&tracked, channel_point);
+                tracked
+            }
+        };
+
+        self.begin(&tracked);
+        let stream = tracked.clone();
+        let manager = self.weak();
+        let id = self
+            .client
+            .subscribe_events::<lnrpc::CloseStatusUpdate, lnrpc::CloseChannelRequest>(closeChannel)
+            .on_event(move |update| {
+                let Some(manager) = manager() else {
+                    return;
+                };
+                match update.map(|update| update.update) {
+                    Ok(Some(CloseUpdate::ClosePending(pending))) => {
+                        stream.advance(ChannelState::Closing {
+                            closing_txid: txid_to_string(&pending.txid),
+                        });
+                    }
+                    Ok(Some(CloseUpdate::ChanClose(close))) => {
+                        stream.advance(ChannelState::Closed {
+                            closing_txid: txid_to_string(&close.closing_txid),
+                        });
+                        manager.release(&stream);
+                        manager.forget(&stream);
+                    }
+                    Ok(_) => {}
+                    // The channel is still open, e.g. because the peer is offline.
+                    Err(error) if !matches!(stream.state(), ChannelState::Closing { .. }) => {
+                        stream.fail_close(error);
+                        manager.release(&stream);
+                    }
+                    // The closing transaction is out, channel events report the rest.
+                    Err(_) => manager.release(&stream),
+                }
+            })
+            .with_request(request)
+            .subscribe()?;
+        self.attach(&tracked, id);
+
+        Ok(ChannelHandle { tracked })
+    }
+
+    /// Picks up the channels lnd still reports as pending, e.g. after a restart.
+    ///
+    /// # Returns
+    ///
+    /// Handles to the channels that are still being opened or closed.
+    pub fn recover(&self) -> Result<Vec<ChannelHandle>> {
+        self.watch_events()?;
+        let pending: lnrpc::PendingChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::PendingChannelsRequest::default(), pendingChannels)?;
+
+        let opening = pending.pending_open_channels.into_iter().map(|channel| {
+            let channel_point = channel.channel.unwrap_or_default().channel_point;
+            let txid = channel_point
+                .split_once(':')
+                .map(|(txid, _)| txid.to_string())
+                .unwrap_or_default();
+            (channel_point, ChannelState::Pending { txid })
+        });
+        let closing = pending
+            .waiting_close_channels
+            .into_iter()
+            .map(|channel| (channel.channel, channel.closing_txid))
+            .chain(
+                pending
+                    .pending_force_closing_channels
+                    .into_iter()
+                    .map(|channel| (channel.channel, channel.closing_txid)),
+            )
+            .map(|(channel, closing_txid)| {
+                (
+                    channel.unwrap_or_default().channel_point,
+                    ChannelState::Closing { closing_txid },
+                )
+            });
+
+        Ok(opening
+            .chain(closing)
+            .filter(|(channel_point, _)| !channel_point.is_empty())
+            .map(|(channel_point, state)| {
+                let tracked = match self.get(&channel_point) {
+                    Some(handle) => handle.tracked,
+                    None => {
+                        let tracked = Tracked::new(Some(channel_point.clone()), state.clone());
+                        self.register(&tracked, channel_point);
+                        tracked
+                    }
+                };
+                tracked.advance(state);
+                ChannelHandle { tracked }
+            })
+            .collect())
+    }
+
+    /// Follows a channel whose funding transaction was published outside of `open`.
+    pub(crate) fn track_pending(&self, pending: &lnrpc::PendingUpdate) -> Result<ChannelHandle> {
+        self.watch_events()?;
+        let txid = txid_to_string(&pending.txid);
+        let channel_point = format!("{}:{}", txid, pending.output_index);
+        let tracked = match self.get(&channel_point) {
+            Some(handle) => handle.tracked,
+            None => {
+                let tracked = Tracked::new(None, ChannelState::Opening);
+                self.register(&tracked, channel_point);
+                tracked
+            }
+        };
+        tracked.advance(ChannelState::Pending { txid });
+        Ok(ChannelHandle { tracked })
+    }
+
+    /// Returns the state of an open channel that is not followed yet.
+    fn lookup(&self, channel_point: &str) -> Result<ChannelState> {
+        let response: lnrpc::ListChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListChannelsRequest::default(), listChannels)?;
+        let channel = response
+            .channels
+            .into_iter()
+            .find(|channel| channel.channel_point == channel_point)
+            .with_context(|| format!("Channel {} is not open", channel_point))?;
+        Ok(if channel.active {
+            ChannelState::Active
+        } else {
+            ChannelState::Confirmed
+        })
+    }
+
+    /// Makes `tracked` reachable by its channel point for channel events.
+    fn register(&self, tracked: &Arc<Tracked>, channel_point: String) {
+        *tracked
+            .channel_point
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(channel_point.clone());
+        self.inner
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(channel_point, tracked.clone());
+    }
+
+    /// Stops following a channel that reached a final state.
+    fn forget(&self, tracked: &Tracked) {
+        self.inner
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(tracked);
+    }
+
+    /// Returns a function that recreates the manager while the client is alive, so
+    /// stream callbacks do not keep the client from dropping.
+    fn weak(&self) -> impl Fn() -> Option<ChannelManager> + Send + Sync + 'static {
+        let client = self.client.downgrade();
+        let inner = Arc::downgrade(&self.inner);
+        move || {
+            Some(ChannelManager {
+                client: client.upgrade()?,
+                inner: inner.upgrade()?,
+            })
+        }
+    }
+
+    /// Drops the stream of an earlier close before a new one starts, and clears the
+    /// error it may have left.
+    fn begin(&self, tracked: &Tracked) {
+        *tracked
+            .close_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        let previous = {
+            let mut stream = tracked
+                .stream
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            stream.ended = false;
+            stream.id.take()
+        };
+        if let Some(id) = previous {
+            self.client.unsubscribe(id);
+        }
+    }
+
+    /// Stores the id of a new `openChannel` or `closeChannel` stream, or drops the
+    /// stream right away if it already ended before the id was known.
+    fn attach(&self, tracked: &Tracked, id: SubscriptionId) {
+        let ended = {
+            let mut stream = tracked
+                .stream
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if !stream.ended {
+                stream.id = Some(id);
+            }
+            stream.ended
+        };
+        if ended {
+            self.client.unsubscribe(id);
+        }
+    }
+
+    /// Drops the `openChannel` or `closeChannel` stream once it has nothing more to say.
+    fn release(&self, tracked: &Tracked) {
+        let subscription = {
+            let mut stream = tracked
+                .stream
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            stream.ended = true;
+            stream.id.take()
+        };
+        if let Some(id) = subscription {
+            self.client.unsubscribe(id);
+        }
+    }
+
+    /// Follows `subscribeChannelEvents`, once.
+    fn watch_events(&self) -> Result<()> {
+        let mut events = self
+            .inner
+            .events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if events.is_some() {
+            return Ok(());
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+        let id = self
+            .client
+            .subscribe_events::<lnrpc::ChannelEventUpdate, lnrpc::ChannelEventSubscription>(
+                subscribeChannelEvents,
+            )
+            .on_event(move |event| {
+                let (Some(inner), Ok(event)) = (inner.upgrade(), event) else {
+                    return;
+                };
+                let (channel_point, state) = match event.channel {
+                    Some(ChannelEvent::PendingOpenChannel(pending)) => {
+                        let txid = txid_to_string(&pending.txid);
+                        (
+                            format!("{}:{}", txid, pending.output_index),
+                            ChannelState::Pending { txid },
+                        )
+                    }
+                    Some(ChannelEvent::OpenChannel(channel)) => {
+                        let state = if channel.active {
+                            ChannelState::Active
+                        } else {
+                            ChannelState::Confirmed
+                        };
+                        (channel.channel_point, state)
+                    }
+                    Some(ChannelEvent::ActiveChannel(channel_point)) => {
+                        (format_channel_point(&channel_point), ChannelState::Active)
+                    }
+                    Some(ChannelEvent::ClosedChannel(summary)) => (
+                        summary.channel_point,
+                        ChannelState::Closed {
+                            closing_txid: summary.closing_tx_hash,
+                        },
+                    ),
+                    _ => return,
+                };
+                inner
+                    .channels
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .update(channel_point, state);
+            })
+            .with_request(lnrpc::ChannelEventSubscription {})
+            .subscribe()?;
+        *events = Some(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use crate::callback_panic::guard;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// A unit of work handed to a dispatcher's executor.
+pub type Task = Box<dyn FnOnce() + Send + 'static>;
+
+type Spawner = Box<dyn Fn(Task) + Send + Sync>;
+
+/// How many messages of one stream are handled before its worker is handed back to the
+/// executor, so a busy stream cannot starve the others.
+const BATCH_SIZE: usize = 32;
+
+/// A snapshot of a dispatcher's queues.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DispatcherMetrics {
+    /// Messages waiting to be handled, across all streams.
+    pub queued: usize,
+    /// The most messages that have been waiting on a single stream at once.
+    pub peak_stream_depth: usize,
+    /// Messages handled so far.
+    pub handled: u64,
+    /// The lnd method and queue depth of every stream with waiting messages.
+    pub stream_depths: Vec<(String, usize)>,
+}
+
+struct StreamQueue {
+    method: &'static str,
+    tasks: VecDeque<Task>,
+    scheduled: bool,
+}
+
+struct DispatcherInner {
+    spawn: Spawner,
+    queues: Mutex<HashMap<usize, StreamQueue>>,
+    peak_stream_depth: AtomicUsize,
+    handled: AtomicU64,
+}
+
+/// Moves stream callbacks off the threads lnd delivers messages on.
+///
+/// Each message is copied and queued per stream, so callbacks for one stream still run
+/// one at a time and in order, while different streams are handled in parallel.
+#[derive(Clone)]
+pub struct Dispatcher {
+    inner: Arc<DispatcherInner>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher backed by a pool of `workers` threads.
+    pub fn thread_pool(workers: usize) -> Self {
+        let (tx, rx) = channel::<Task>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..workers.max(1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("lnd-dispatch-{}", i))
+                .spawn(move || loop {
+                    let task = rx.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    match task {
+                        Ok(task) => task(),
+                        // The dispatcher was dropped.
+                        Err(_) => break,
+                    }
+                })
+                .expect("Failed to spawn dispatcher thread");
+        }
+
+        Self::with_spawner(move |task| {
+            let _ = tx.send(task);
+        })
+    }
+
+    /// Creates a dispatcher that hands work to a custom executor, e.g.
+    /// `|task| { tokio::task::spawn_blocking(task); }`.
+    pub fn with_spawner<F>(spawn: F) -> Self
+    where
+        F: Fn(Task) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(DispatcherInner {
+                spawn: Box::new(spawn),
+                queues: Mutex::new(HashMap::new()),
+                peak_stream_depth: AtomicUsize::new(0),
+                handled: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the current queue depths.
+    pub fn metrics(&self) -> DispatcherMetrics {
+        let queues = self
+            .inner
+            .queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let stream_depths: Vec<(String, usize)> = queues
+            .values()
+            .filter(|queue| !queue.tasks.is_empty())
+            .map(|queue| (queue.method.to_string(), queue.tasks.len()))
+            .collect();
+
+        DispatcherMetrics {
+            queued: stream_depths.iter().map(|(_, depth)| depth).sum(),
+            peak_stream_depth: self.inner.peak_stream_depth.load(Ordering::Relaxed),
+            handled: self.inner.handled.load(Ordering::Relaxed),
+            stream_depths,
+        }
+    }
+
+    /// Queues `task` behind the earlier tasks of the same stream.
+    pub(crate) fn dispatch(&self, stream: usize, method: &'static str, task: Task) {
+        let schedule = {
+            let mut queues = self
+                .inner
+                .queues
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let queue = queues.entry(stream).or_insert_with(|| StreamQueue {
+                method,
+                tasks: VecDeque::new(),
+                scheduled: false,
+            });
+            queue.tasks.push_back(task);
+            self.inner
+                .peak_stream_depth
+                .fetch_max(queue.tasks.len(), Ordering::Relaxed);
+            !std::mem::replace(&mut queue.scheduled, true)
+        };
+
+        if schedule {
+            self.schedule(stream);
+        }
+    }
+
+    fn schedule(&self, stream: usize) {
+        let dispatcher = self.clone();
+        (self.inner.spawn)(Box::new(move || dispatcher.drain(stream)));
+    }
+
+    fn drain(&self, stream: usize) {
+        for _ in 0..BATCH_SIZE {
+            let task = {
+                let mut queues = self
+                    .inner
+                    .queues
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                match queues.get_mut(&stream).and_then(|q| q.tasks.pop_front()) {
+                    Some(task) => task,
+                    None => {
+                        queues.remove(&stream);
+                        return;
+                    }
+                }
+            };
+            guard("dispatcher", task);
+            self.inner.handled.fetch_add(1, Ordering::Relaxed);
+        }
+        // Give other streams a turn before continuing with this one.
+        self.schedule(stream);
+    }
+}
@@ -0,0 +1,21 @@
+use std::fmt;
+
+/// Errors returned by `LndClient` itself, as opposed to errors reported by lnd.
+///
+/// They are wrapped in `anyhow::Error` like all other errors of this crate, and can be
+/// told apart with `error.downcast_ref::<ClientError>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    /// The client has been shut down and no longer accepts calls.
+    NotRunning,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NotRunning => write!(f, "LND client is not running"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,454 @@
+use crate::{
+    chainNotifierRegisterBlockEpochNtfn, routerSubscribeHtlcEvents, subscribeChannelEvents,
+    subscribeChannelGraph, subscribeInvoices, subscribePeerEvents, subscribeTransactions,
+    CRecvStream, LndClient, ResubscribePolicy, SubscriptionId,
+};
+use anyhow::Result;
+use lnd_grpc_rust::prost::Message;
+use lnd_grpc_rust::{chainrpc, lnrpc, routerrpc};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A stream an `EventBus` can follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EventSource {
+    /// `subscribePeerEvents`
+    Peers,
+    /// `subscribeChannelEvents`
+    Channels,
+    /// `subscribeInvoices`
+    Invoices,
+    /// `subscribeTransactions`
+    Transactions,
+    /// `routerSubscribeHtlcEvents`
+    Htlcs,
+    /// `subscribeChannelGraph`
+    Graph,
+    /// `chainNotifierRegisterBlockEpochNtfn`
+    Blocks,
+}
+
+impl EventSource {
+    /// Every source, in declaration order.
+    pub const ALL: [EventSource; 7] = [
+        EventSource::Peers,
+        EventSource::Channels,
+        EventSource::Invoices,
+        EventSource::Transactions,
+        EventSource::Htlcs,
+        EventSource::Graph,
+        EventSource::Blocks,
+    ];
+}
+
+/// An event from any of the streams followed by an `EventBus`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    /// A peer connected or disconnected.
+    Peer(lnrpc::PeerEvent),
+    /// A channel changed state.
+    Channel(lnrpc::ChannelEventUpdate),
+    /// An invoice was added or changed.
+    Invoice(lnrpc::Invoice),
+    /// A wallet transaction was seen or confirmed.
+    Transaction(lnrpc::Transaction),
+    /// An HTLC was forwarded, settled or failed.
+    Htlc(routerrpc::HtlcEvent),
+    /// The channel graph changed.
+    Graph(lnrpc::GraphTopologyUpdate),
+    /// A new block was connected.
+    Block(chainrpc::BlockEpoch),
+    /// A stream ended with an error.
+    StreamError {
+        /// The stream that ended.
+        source: EventSource,
+        /// The error it ended with.
+        error: String,
+    },
+}
+
+impl NodeEvent {
+    /// Returns the stream the event came from.
+    pub fn source(&self) -> EventSource {
+        match self {
+            NodeEvent::Peer(_) => EventSource::Peers,
+            NodeEvent::Channel(_) => EventSource::Channels,
+            NodeEvent::Invoice(_) => EventSource::Invoices,
+            NodeEvent::Transaction(_) => EventSource::Transactions,
+            NodeEvent::Htlc(_) => EventSource::Htlcs,
+            NodeEvent::Graph(_) => EventSource::Graph,
+            NodeEvent::Block(_) => EventSource::Blocks,
+            NodeEvent::StreamError { source, .. } => *source,
+        }
+    }
+}
+
+/// A message type carried by a `NodeEvent`, for listeners registered with `EventBus::on`.
+pub trait TypedEvent: Sized + 'static {
+    /// The source the type comes from.
+    const SOURCE: EventSource;
+
+    /// Returns the message if `event` carries this type.
+    fn from_event(event: &NodeEvent) -> Option<&Self>;
+}
+
+macro_rules! typed_event {
+    ($type:ty, $variant:ident, $source:ident) => {
+        impl TypedEvent for $type {
+            const SOURCE: EventSource = EventSource::$source;
+
+            fn from_event(event: &NodeEvent) -> Option<&Self> {
+                match event {
+                    NodeEvent::$variant(message) => Some(message),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+typed_event!(lnrpc::PeerEvent, Peer, Peers);
+typed_event!(lnrpc::ChannelEventUpdate, Channel, Channels);
+typed_event!(lnrpc::Invoice, Invoice, Invoices);
+typed_event!(lnrpc::Transaction, Transaction, Transactions);
+typed_event!(routerrpc::HtlcEvent, Htlc, Htlcs);
+typed_event!(lnrpc::GraphTopologyUpdate, Graph, Graph);
+typed_event!(chainrpc::BlockEpoch, Block, Blocks);
+
+type Predicate = Box<dyn Fn(&NodeEvent) -> bool + Send + Sync>;
+
+/// Selects the events a listener receives.
+#[derive(Default)]
+pub struct EventFilter {
+    sources: Option<Vec<EventSource>>,
+    predicates: Vec<Predicate>,
+    errors: bool,
+}
+
+impl EventFilter {
+    /// Matches every event, including stream errors.
+    pub fn all() -> Self {
+        Self {
+            errors: true,
+            ..Default::default()
+        }
+    }
+
+    /// Matches the events of the given sources, including their stream errors.
+    pub fn sources(sources: &[EventSource]) -> Self {
+        Self {
+            sources: Some(sources.to_vec()),
+            errors: true,
+            ..Default::default()
+        }
+    }
+
+    /// Only matches events for which `predicate` returns `true` as well.
+    ///
+    /// Stream errors are still passed unless `without_errors` is used.
+    pub fn matching<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&NodeEvent) -> bool + Send + Sync + 'static,
+    {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    /// Does not match stream errors.
+    pub fn without_errors(mut self) -> Self {
+        self.errors = false;
+        self
+    }
+
+    /// Returns `true` if the filter lets `event` through.
+    pub fn matches(&self, event: &NodeEvent) -> bool {
+        if let Some(sources) = &self.sources {
+            if !sources.contains(&event.source()) {
+                return false;
+            }
+        }
+        if let NodeEvent::StreamError { .. } = event {
+            return self.errors;
+        }
+        self.predicates.iter().all(|predicate| predicate(event))
+    }
+}
+
+/// Identifies a listener registered with an `EventBus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(u64);
+
+struct Listener {
+    id: ListenerId,
+    filter: EventFilter,
+    callback: Box<dyn Fn(&NodeEvent) + Send + Sync>,
+}
+
+struct Inner {
+    listeners: Mutex<Vec<Arc<Listener>>>,
+    next_listener: AtomicU64,
+}
+
+impl Inner {
+    fn publish(&self, event: NodeEvent) {
+        // Listeners run without the lock, so they may add or remove listeners.
+        let listeners = self
+            .listeners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for listener in listeners {
+            if listener.filter.matches(&event) {
+                (listener.callback)(&event);
+            }
+        }
+    }
+}
+
+/// Follows several lnd streams and fans their events out to listeners as `NodeEvent`s.
+///
+/// The bus owns its subscriptions. Listeners can be added and removed at any time, and
+/// only receive the events their `EventFilter` matches, in the order of each stream.
+#[derive(Clone)]
+pub struct EventBus {
+    client: LndClient,
+    resubscribe: Option<ResubscribePolicy>,
+    inner: Arc<Inner>,
+    /// The followed sources, with no id yet while `start` subscribes.
+    subscriptions: Arc<Mutex<HashMap<EventSource, Option<SubscriptionId>>>>,
+}
+
+impl EventBus {
+    /// Creates a bus that does not follow any stream yet.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            resubscribe: None,
+            inner: Arc::new(Inner {
+                listeners: Mutex::new(Vec::new()),
+                next_listener: AtomicU64::new(1),
+            }),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reopens streams that ended with `policy`, reporting only the final failure.
+    pub fn resubscribe(mut self, policy: ResubscribePolicy) -> Self {
+        self.resubscribe = Some(policy);
+        self
+    }
+
+    /// Starts following the given sources. Sources already followed are left as they are.
+    pub fn start(&self, sources: &[EventSource]) -> Result<()> {
+        for &source in sources {
+            if !self.reserve(source) {
+                continue;
+            }
+            // Subscribe without holding the lock, which the stream's error callback takes.
+            let id = match source {
+                EventSource::Peers => self.follow(
+                    source,
+                    subscribePeerEvents,
+                    lnrpc::PeerEventSubscription {},
+                    NodeEvent::Peer,
+                ),
+                EventSource::Channels => self.follow(
+                    source,
+                    subscribeChannelEvents,
+                    lnrpc::ChannelEventSubscription {},
+                    NodeEvent::Channel,
+                ),
+                EventSource::Invoices => self.follow(
+                    source,
+                    subscribeInvoices,
+                    lnrpc::InvoiceSubscription::default(),
+                    NodeEvent::Invoice,
+                ),
+                EventSource::Transactions => self.follow(
+                    source,
+                    subscribeTransactions,
+                    lnrpc::GetTransactionsRequest::default(),
+                    NodeEvent::Transaction,
+                ),
+                EventSource::Htlcs => self.follow(
+                    source,
+                    routerSubscribeHtlcEvents,
+                    routerrpc::SubscribeHtlcEventsRequest {},
+                    NodeEvent::Htlc,
+                ),
+                EventSource::Graph => self.follow(
+                    source,
+                    subscribeChannelGraph,
+                    lnrpc::GraphTopologySubscription {},
+                    NodeEvent::Graph,
+                ),
+                EventSource::Blocks => self.follow(
+                    source,
+                    chainNotifierRegisterBlockEpochNtfn,
+                    chainrpc::BlockEpoch::default(),
+                    NodeEvent::Block,
+                ),
+            };
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => {
+                    self.subscriptions
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(&source);
+                    return Err(e);
+                }
+            };
+            let mut subscriptions = self
+                .subscriptions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match subscriptions.get_mut(&source) {
+                Some(reserved) => *reserved = Some(id),
+                // The stream already gave up, or `stop` was called in the meantime.
+                None => {
+                    drop(subscriptions);
+                    self.client.unsubscribe(id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks `source` as followed before subscribing to it, so the stream's error
+    /// callback can undo it. Returns `false` if it is already followed.
+    fn reserve(&self, source: EventSource) -> bool {
+        match self
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(source)
+        {
+            Entry::Vacant(entry) => {
+                entry.insert(None);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Stops following every source. Listeners stay registered.
+    pub fn stop(&self) {
+        let subscriptions: Vec<_> = self
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain()
+            .collect();
+        for id in subscriptions.into_iter().filter_map(|(_, id)| id) {
+            self.client.unsubscribe(id);
+        }
+    }
+
+    /// Returns the sources currently followed.
+    pub fn sources(&self) -> Vec<EventSource> {
+        let mut sources: Vec<_> = self
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .copied()
+            .collect();
+        sources.sort();
+        sources
+    }
+
+    /// Registers a listener for the events `filter` matches.
+    pub fn listen<F>(&self, filter: EventFilter, callback: F) -> ListenerId
+    where
+        F: Fn(&NodeEvent) + Send + Sync + 'static,
+    {
+        let id = ListenerId(self.inner.next_listener.fetch_add(1, Ordering::Relaxed));
+        self.inner
+            .listeners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::new(Listener {
+                id,
+                filter,
+                callback: Box::new(callback),
+            }));
+        id
+    }
+
+    /// Registers a listener for the messages of one type, e.g. `lnrpc::Invoice`.
+    ///
+    /// Stream errors are not passed to typed listeners.
+    pub fn on<T, F>(&self, callback: F) -> ListenerId
+    where
+        T: TypedEvent,
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.listen(
+            EventFilter::sources(&[T::SOURCE]).without_errors(),
+            move |event| {
+                if let Some(message) = T::from_event(event) {
+                    callback(message);
+                }
+            },
+        )
+    }
+
+    /// Removes a listener. Returns `false` if it was not registered.
+    pub fn remove_listener(&self, id: ListenerId) -> bool {
+        let mut listeners = self
+            .inner
+            .listeners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let before = listeners.len();
+        listeners.retain(|listener| listener.id != id);
+        listeners.len() != before
+    }
+
+    fn follow<E, R>(
+        &self,
+        source: EventSource,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        request: R,
+        wrap: fn(E) -> NodeEvent,
+    ) -> Result<SubscriptionId>
+    where
+        E: Message + Default + 'static,
+        R: Message + 'static,
+    {
+        let inner = Arc::downgrade(&self.inner);
+        let subscriptions = Arc::downgrade(&self.subscriptions);
+        let mut builder = self
+            .client
+            .subscribe_events::<E, R>(subscribe_func)
+            .on_event(move |event| {
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let event = match event {
+                    Ok(message) => wrap(message),
+                    Err(error) => {
+                        // The stream is gone, even when resubscribing, as this is only
+                        // reported once retrying gave up. `start` may follow it again.
+                        if let Some(subscriptions) = subscriptions.upgrade() {
+                            subscriptions
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .remove(&source);
+                        }
+                        NodeEvent::StreamError { source, error }
+                    }
+                };
+                inner.publish(event);
+            })
+            .with_request(request);
+        if let Some(policy) = self.resubscribe {
+            builder = builder.resubscribe(policy);
+        }
+        builder.subscribe()
+    }
+}
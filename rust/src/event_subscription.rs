@@ -1,5 +1,8 @@
+use crate::lnd_client::StreamOptions;
+use crate::resubscribe::{self, LifecycleHook, ResubscribePolicy, RewriteHook, StreamLifecycle};
 use crate::CRecvStream;
 use crate::LndClient;
+use crate::{PanicPolicy, SubscriptionId};
 use anyhow::Result;
 use lnd_grpc_rust::prost::Message;
 use std::ffi::c_char;
@@ -14,13 +17,17 @@ pub struct EventSubscriptionBuilder<'a, E, R> {
     subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
     callback: Option<EventCallback<E>>,
     request: Option<R>,
+    options: StreamOptions,
+    resubscribe: Option<ResubscribePolicy>,
+    rewrite: Option<RewriteHook<R>>,
+    lifecycle: Option<LifecycleHook>,
     _phantom: PhantomData<E>,
 }
 
 impl<'a, E, R> EventSubscriptionBuilder<'a, E, R>
 where
     E: Message + Default + 'static,
-    R: Message,
+    R: Message + 'static,
 {
     pub(crate) fn new(
         client: &'a LndClient,
@@ -31,6 +38,10 @@ where
             subscribe_func,
             callback: None,
             request: None,
+            options: StreamOptions::default(),
+            resubscribe: None,
+            rewrite: None,
+            lifecycle: None,
             _phantom: PhantomData,
         }
     }
@@ -50,12 +61,51 @@ where
         self
     }
 
+    /// Sets what happens to the subscription when the event callback panics.
+    ///
+    /// The panic is always reported to the hook set with `set_error_hook`. Defaults to
+    /// `PanicPolicy::Close`.
+    pub fn on_panic(mut self, policy: PanicPolicy) -> Self {
+        self.options.panic_policy = policy;
+        self
+    }
+
+    /// Reopens the subscription with `policy` whenever its stream ends, e.g. when lnd
+    /// restarts its RPC server after the wallet was unlocked.
+    ///
+    /// Stream errors are then reported as `StreamLifecycle::Disconnected` instead of being
+    /// passed to the event callback, which only receives an error once the policy gives up.
+    /// The subscription keeps its id across reconnects.
+    pub fn resubscribe(mut self, policy: ResubscribePolicy) -> Self {
+        self.resubscribe = Some(policy);
+        self
+    }
+
+    /// Sets a hook that may change the request before the subscription is reopened, e.g.
+    /// to continue from the last seen index.
+    pub fn rewrite_request<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut R) + Send + Sync + 'static,
+    {
+        self.rewrite = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the callback for connection changes of a resubscribing stream.
+    pub fn on_lifecycle<F>(mut self, f: F) -> Self
+    where
+        F: Fn(StreamLifecycle) + Send + Sync + 'static,
+    {
+        self.lifecycle = Some(Box::new(f));
+        self
+    }
+
     /// Builds and starts the event subscription.
     ///
     /// # Returns
     ///
-    /// A `Result` indicating success or failure.
-    pub fn subscribe(self) -> Result<()> {
+    /// A `Result` containing the id of the new subscription or an error.
+    pub fn subscribe(self) -> Result<SubscriptionId> {
         let callback = self
             .callback
             .ok_or_else(|| anyhow::anyhow!("Event callback not set"))?;
@@ -63,7 +113,23 @@ where
             .request
             .ok_or_else(|| anyhow::anyhow!("Subscription request not set"))?;
 
-        self.client
-            .subscribe_to_events(self.subscribe_func, callback, request)
+        match self.resubscribe {
+            Some(policy) => resubscribe::subscribe(
+                self.client,
+                self.subscribe_func,
+                callback,
+                request,
+                self.options,
+                policy,
+                self.rewrite,
+                self.lifecycle,
+            ),
+            None => self.client.subscribe_with_options(
+                self.subscribe_func,
+                callback,
+                request,
+                self.options,
+            ),
+        }
     }
 }
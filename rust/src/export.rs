@@ -0,0 +1,85 @@
+/// A value in an exported record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    /// A signed number.
+    Int(i64),
+    /// An unsigned number.
+    UInt(u64),
+    /// Text.
+    Text(String),
+}
+
+/// A record that can be exported with `to_csv` and `to_json`.
+pub trait ExportRecord {
+    /// Returns the named fields of the record, in column order.
+    fn fields(&self) -> Vec<(&'static str, Field)>;
+}
+
+/// Exports records as CSV with a header row, quoting text as needed.
+pub fn to_csv<T: ExportRecord>(records: &[T]) -> String {
+    let mut out = String::new();
+    let Some(first) = records.first() else {
+        return out;
+    };
+    let header: Vec<&str> = first.fields().iter().map(|(name, _)| *name).collect();
+    out.push_str(&header.join(","));
+    out.push('\n');
+    for record in records {
+        let row: Vec<String> = record
+            .fields()
+            .into_iter()
+            .map(|(_, field)| match field {
+                Field::Int(value) => value.to_string(),
+                Field::UInt(value) => value.to_string(),
+                Field::Text(text) if text.contains([',', '"', '\n', '\r']) => {
+                    format!("\"{}\"", text.replace('"', "\"\""))
+                }
+                Field::Text(text) => text,
+            })
+            .collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Exports records as a JSON array of objects.
+pub fn to_json<T: ExportRecord>(records: &[T]) -> String {
+    let objects: Vec<String> = records
+        .iter()
+        .map(|record| {
+            let fields: Vec<String> = record
+                .fields()
+                .into_iter()
+                .map(|(name, field)| {
+                    let value = match field {
+                        Field::Int(value) => value.to_string(),
+                        Field::UInt(value) => value.to_string(),
+                        Field::Text(text) => json_string(&text),
+                    };
+                    format!("{}:{}", json_string(name), value)
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+    format!("[{}]", objects.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
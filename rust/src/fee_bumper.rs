@@ -0,0 +1,471 @@
+use crate::onchain::{format_outpoint, parse_outpoint};
+use crate::{
+    getTransactions, subscribeTransactions, walletKitBumpFee, walletKitEstimateFee,
+    walletKitListSweeps, walletKitPendingSweeps, FeePreference, LndClient, SubscriptionId,
+};
+use anyhow::Result;
+use lnd_grpc_rust::{lnrpc, walletrpc};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type EventHandler = Arc<dyn Fn(FeeBumpEvent) + Send + Sync>;
+
+/// When and how far a `FeeBumper` bumps fees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeBumpPolicy {
+    /// The number of blocks a transaction should confirm within.
+    pub target_conf: u32,
+    /// The highest fee rate to bump to, in sat/vbyte.
+    pub max_sat_per_vbyte: u64,
+    /// The most lnd's sweeper may spend on fees for a bumped input, in satoshis, or
+    /// lnd's default when zero.
+    pub budget_sat: u64,
+    /// How long a wallet transaction may stay unconfirmed before it is bumped.
+    pub stuck_after: Duration,
+    /// How often `start` checks for transactions to bump.
+    pub poll_interval: Duration,
+}
+
+impl Default for FeeBumpPolicy {
+    fn default() -> Self {
+        Self {
+            target_conf: 6,
+            max_sat_per_vbyte: 100,
+            budget_sat: 0,
+            stuck_after: Duration::from_secs(30 * 60),
+            poll_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Something a `FeeBumper` did or noticed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeeBumpEvent {
+    /// The fee of a sweep, or of a stuck transaction through a child paying for it, was
+    /// bumped.
+    Bumped {
+        /// The input that was bumped, as `txid:output_index`.
+        outpoint: String,
+        /// The new fee rate.
+        sat_per_vbyte: u64,
+        /// Whether the rate was held at `max_sat_per_vbyte`, below the estimate for
+        /// `target_conf`.
+        capped: bool,
+        /// lnd's answer to the bump.
+        status: String,
+    },
+    /// A transaction that was unconfirmed when seen got confirmed.
+    Confirmed {
+        /// The confirmed transaction.
+        txid: String,
+    },
+    /// A check or bump failed.
+    Error(String),
+}
+
+/// An unconfirmed wallet transaction.
+struct Unconfirmed {
+    first_seen: Instant,
+    /// One of the wallet's outputs, which a child transaction can spend to bump it.
+    own_output: Option<String>,
+    /// The rate it was last bumped to.
+    bumped_to: u64,
+}
+
+struct Inner {
+    client: LndClient,
+    policy: FeeBumpPolicy,
+    unconfirmed: Mutex<HashMap<String, Unconfirmed>>,
+    /// The height of the newest block with a wallet transaction, from which wallet
+    /// transactions and sweeps are listed again.
+    scanned_height: Mutex<i32>,
+    on_event: Mutex<Option<EventHandler>>,
+    subscription: Mutex<Option<SubscriptionId>>,
+    running: AtomicBool,
+    /// Counts the calls to `start`, so a thread left over from before a `stop` exits
+    /// instead of running next to the new one.
+    generation: AtomicU64,
+}
+
+impl Inner {
+    fn emit(&self, event: FeeBumpEvent) {
+        let on_event = self
+            .on_event
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Some(on_event) = on_event {
+            on_event(event);
+        }
+    }
+
+    fn on_transaction(&self, transaction: lnrpc::Transaction) {
+        let mut unconfirmed = self
+            .unconfirmed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if transaction.num_confirmations > 0 {
+            if unconfirmed.remove(&transaction.tx_hash).is_some() {
+                drop(unconfirmed);
+                self.emit(FeeBumpEvent::Confirmed {
+                    txid: transaction.tx_hash,
+                });
+            }
+            return;
+        }
+        let own_output = transaction
+            .output_details
+            .iter()
+            .find(|output| output.is_our_address)
+            .map(|output| format!("{}:{}", transaction.tx_hash, output.output_index));
+        unconfirmed
+            .entry(transaction.tx_hash)
+            .or_insert_with(|| Unconfirmed {
+                first_seen: Instant::now(),
+                own_output,
+                bumped_to: 0,
+            });
+    }
+
+    /// Applies the wallet transactions listed since the scanned height. The ones in
+    /// `tracked`, which were tracked before they were listed, but are missing from them
+    /// were replaced or evicted, as lnd stops listing those, and are dropped.
+    fn scan(&self, transactions: Vec<lnrpc::Transaction>, tracked: &HashSet<String>) {
+        let mut scanned_height = self
+            .scanned_height
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for transaction in &transactions {
+            *scanned_height = (*scanned_height).max(transaction.block_height);
+        }
+        drop(scanned_height);
+        let listed: HashSet<&str> = transactions
+            .iter()
+            .map(|transaction| transaction.tx_hash.as_str())
+            .collect();
+        self.unconfirmed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|txid, _| !tracked.contains(txid) || listed.contains(txid.as_str()));
+        for transaction in transactions {
+            self.on_transaction(transaction);
+        }
+    }
+}
+
+/// Watches pending sweeps and unconfirmed wallet transactions, and bumps their fees
+/// when they fall behind the estimate for the policy's confirmation target.
+///
+/// Sweeps are bumped directly. Other wallet transactions are bumped once they have been
+/// unconfirmed for `stuck_after`, by having lnd sweep one of their outputs with a child
+/// transaction paying for both.
+#[derive(Clone)]
+pub struct FeeBumper {
+    inner: Arc<Inner>,
+}
+
+impl FeeBumper {
+    /// Creates a fee bumper following the given policy.
+    pub fn new(client: LndClient, policy: FeeBumpPolicy) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                policy,
+                unconfirmed: Mutex::new(HashMap::new()),
+                scanned_height: Mutex::new(0),
+                on_event: Mutex::new(None),
+                subscription: Mutex::new(None),
+                running: AtomicBool::new(false),
+                generation: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the inputs lnd's sweeper is trying to sweep.
+    pub fn pending_sweeps(&self) -> Result<Vec<walletrpc::PendingSweep>> {
+        let response: walletrpc::PendingSweepsResponse = self
+            .inner
+            .client
+            .call_lnd_method(walletrpc::PendingSweepsRequest {}, walletKitPendingSweeps)?;
+        Ok(response.pending_sweeps)
+    }
+
+    /// Returns the txids of the sweep transactions published since `start_height`.
+    pub fn sweeps(&self, start_height: i32) -> Result<Vec<String>> {
+        let response: walletrpc::ListSweepsResponse = self.inner.client.call_lnd_method(
+            walletrpc::ListSweepsRequest {
+                verbose: false,
+                start_height,
+            },
+            walletKitListSweeps,
+        )?;
+        Ok(match response.sweeps {
+            Some(walletrpc::list_sweeps_response::Sweeps::TransactionIds(ids)) => {
+                ids.transaction_ids
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Returns the fee rate estimated to confirm within `target_conf` blocks, in
+    /// sat/vbyte.
+    pub fn estimate(&self, target_conf: u32) -> Result<u64> {
+        let response: walletrpc::EstimateFeeResponse = self.inner.client.call_lnd_method(
+            walletrpc::EstimateFeeRequest {
+                conf_target: target_conf as i32,
+            },
+            walletKitEstimateFee,
+        )?;
+        // 1 vbyte is 4 weight units, so 250 vbytes make a kiloweight.
+        Ok((response.sat_per_kw.max(0) as u64).div_ceil(250))
+    }
+
+    /// Bumps the fee of a single input, as `txid:output_index`, with the policy's
+    /// budget.
+    ///
+    /// # Returns
+    ///
+    /// lnd's answer to the bump.
+    pub fn bump(&self, outpoint: &str, target: FeePreference) -> Result<String> {
+        let policy = &self.inner.policy;
+        let (target_conf, sat_per_vbyte) = match target {
+            FeePreference::SatPerVbyte(rate) if rate > policy.max_sat_per_vbyte => {
+                return Err(anyhow::anyhow!(
+                    "{} sat/vbyte is above the maximum of {}",
+                    rate,
+                    policy.max_sat_per_vbyte
+                ))
+            }
+            FeePreference::SatPerVbyte(rate) => (0, rate),
+            FeePreference::TargetConf(blocks) => (blocks, 0),
+        };
+        let response: walletrpc::BumpFeeResponse = self.inner.client.call_lnd_method(
+            walletrpc::BumpFeeRequest {
+                outpoint: Some(parse_outpoint(outpoint)?),
+                target_conf,
+                sat_per_vbyte,
+                budget: policy.budget_sat,
+                ..Default::default()
+            },
+            walletKitBumpFee,
+        )?;
+        Ok(response.status)
+    }
+
+    /// Bumps everything that fell behind the fee estimate, once.
+    ///
+    /// # Returns
+    ///
+    /// The bumps made. A failed bump is reported as an error event without stopping
+    /// the others.
+    pub fn check(&self) -> Result<Vec<FeeBumpEvent>> {
+        let policy = self.inner.policy;
+        let estimate = self.estimate(policy.target_conf)?;
+        let rate = estimate.min(policy.max_sat_per_vbyte);
+        let capped = estimate > policy.max_sat_per_vbyte;
+        let mut events = Vec::new();
+
+        let mut swept = HashSet::new();
+        for sweep in self.pending_sweeps()? {
+            let Some(outpoint) = sweep.outpoint.as_ref().map(format_outpoint) else {
+                continue;
+            };
+            swept.insert(outpoint.clone());
+            if sweep.sat_per_vbyte.max(sweep.requested_sat_per_vbyte) < rate {
+                events.push(self.bump_to(outpoint, rate, capped));
+            }
+        }
+
+        let tracking = !self
+            .inner
+            .unconfirmed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty();
+        if tracking {
+            self.scan_transactions()?;
+        }
+        let stuck: Vec<(String, String)> = self
+            .inner
+            .unconfirmed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, tx)| tx.first_seen.elapsed() >= policy.stuck_after && tx.bumped_to < rate)
+            .filter_map(|(txid, tx)| Some((txid.clone(), tx.own_output.clone()?)))
+            .filter(|(_, output)| !swept.contains(output))
+            .collect();
+        if stuck.is_empty() {
+            return Ok(events);
+        }
+        // Sweeps are bumped above through their inputs, not with another child.
+        // Unconfirmed sweeps are listed whatever the start height.
+        let sweeps: HashSet<String> = self.sweeps(self.scanned_height())?.into_iter().collect();
+        for (txid, output) in stuck {
+            if sweeps.contains(&txid) {
+                continue;
+            }
+            let event = self.bump_to(output, rate, capped);
+            if matches!(event, FeeBumpEvent::Bumped { .. }) {
+                if let Some(tx) = self
+                    .inner
+                    .unconfirmed
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get_mut(&txid)
+                {
+                    tx.bumped_to = rate;
+                }
+            }
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    /// Follows the wallet's transactions and runs `check` every `poll_interval` on a
+    /// background thread until `stop` is called or the client shuts down.
+    pub fn start<F>(&self, on_event: F) -> Result<()>
+    where
+        F: Fn(FeeBumpEvent) + Send + Sync + 'static,
+    {
+        if self.inner.running.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        // A thread from before a `stop` exits when it sees this.
+        let started = self.inner.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        *self
+            .inner
+            .on_event
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(on_event));
+
+        let weak = Arc::downgrade(&self.inner);
+        let subscription = self
+            .inner
+            .client
+            .subscribe_events::<lnrpc::Transaction, lnrpc::GetTransactionsRequest>(
+                subscribeTransactions,
+            )
+            .on_event(move |transaction| {
+                let Some(inner) = weak.upgrade() else {
+                    return;
+                };
+                match transaction {
+                    Ok(transaction) => inner.on_transaction(transaction),
+                    Err(e) => inner.emit(FeeBumpEvent::Error(e)),
+                }
+            })
+            .with_request(lnrpc::GetTransactionsRequest::default())
+            .subscribe();
+        let subscription = match subscription {
+            Ok(id) => id,
+            Err(e) => {
+                self.inner.running.store(false, Ordering::Release);
+                return Err(e);
+            }
+        };
+        *self
+            .inner
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(subscription);
+
+        if let Err(e) = self.scan_transactions() {
+            self.stop();
+            return Err(e);
+        }
+
+        let weak = Arc::downgrade(&self.inner);
+        thread::spawn(move || run(weak, started));
+        Ok(())
+    }
+
+    /// Stops what `start` started.
+    pub fn stop(&self) {
+        self.inner.running.store(false, Ordering::Release);
+        let subscription = self
+            .inner
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.inner.client.unsubscribe(id);
+        }
+    }
+
+    /// Lists the wallet transactions since the scanned height, which tracks the ones
+    /// that were unconfirmed before the stream started and catches the confirmations,
+    /// replacements and evictions it missed.
+    fn scan_transactions(&self) -> Result<()> {
+        // Transactions the stream adds during the call are not in the list yet.
+        let tracked: HashSet<String> = self
+            .inner
+            .unconfirmed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        let details: lnrpc::TransactionDetails = self.inner.client.call_lnd_method(
+            lnrpc::GetTransactionsRequest {
+                start_height: self.scanned_height(),
+                end_height: -1,
+                ..Default::default()
+            },
+            getTransactions,
+        )?;
+        self.inner.scan(details.transactions, &tracked);
+        Ok(())
+    }
+
+    fn scanned_height(&self) -> i32 {
+        *self
+            .inner
+            .scanned_height
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn bump_to(&self, outpoint: String, sat_per_vbyte: u64, capped: bool) -> FeeBumpEvent {
+        match self.bump(&outpoint, FeePreference::SatPerVbyte(sat_per_vbyte)) {
+            Ok(status) => FeeBumpEvent::Bumped {
+                outpoint,
+                sat_per_vbyte,
+                capped,
+                status,
+            },
+            Err(e) => FeeBumpEvent::Error(format!("Failed to bump {}: {}", outpoint, e)),
+        }
+    }
+}
+
+/// The background checks of `FeeBumper::start` number `started`, which stop once the
+/// bumper is dropped or started again.
+fn run(inner: Weak<Inner>, started: u64) {
+    loop {
+        let Some(strong) = inner.upgrade() else {
+            break;
+        };
+        if strong.generation.load(Ordering::Acquire) != started {
+            break;
+        }
+        if !strong.running.load(Ordering::Acquire) || !strong.client.is_running() {
+            strong.running.store(false, Ordering::Release);
+            break;
+        }
+        let poll_interval = strong.policy.poll_interval;
+        let bumper = FeeBumper { inner: strong };
+        match bumper.check() {
+            Ok(events) => events
+                .into_iter()
+                .for_each(|event| bumper.inner.emit(event)),
+            Err(e) => bumper.inner.emit(FeeBumpEvent::Error(e.to_string())),
+        }
+        drop(bumper);
+        thread::sleep(poll_interval);
+    }
+}
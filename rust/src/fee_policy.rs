@@ -0,0 +1,517 @@
+use crate::lnd_client::WeakLndClient;
+use crate::{
+    feeReport, getChanInfo, listChannels, parse_channel_point, updateChannelPolicy,
+    ForwardingHistory, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
+use lnd_grpc_rust::lnrpc::policy_update_request::Scope;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type AppliedHandler = Box<dyn Fn(Result<&AppliedPolicy, String>) + Send + Sync>;
+
+/// A rule shaping the policy of a channel. Later rules override earlier ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyRule {
+    /// Sets the fee rate from the share of the capacity on our side: `max_ppm` when the
+    /// channel is drained, `min_ppm` when all funds are ours, following
+    /// `(1 - local_ratio) ^ exponent` in between. An exponent above 1 keeps fees low
+    /// until the channel is mostly drained. `min_ppm` may not be above `max_ppm`, and the
+    /// exponent must be finite and not negative.
+    LiquidityCurve {
+        /// The fee rate with all funds on our side, in parts per million.
+        min_ppm: u32,
+        /// The fee rate with no funds on our side, in parts per million.
+        max_ppm: u32,
+        /// The shape of the curve, 1 for linear.
+        exponent: f64,
+    },
+    /// Sets a fixed fee rate, in parts per million.
+    FeeRate(u32),
+    /// Sets the base fee, in millisatoshis.
+    BaseFee(i64),
+    /// Sets the smallest HTLC forwarded, in millisatoshis.
+    MinHtlc(u64),
+    /// Sets the largest HTLC forwarded, in millisatoshis.
+    MaxHtlc(u64),
+    /// Sets the largest HTLC forwarded to a share of the local balance, so payments
+    /// that cannot fit are not routed through the channel.
+    MaxHtlcRatio(f64),
+    /// Sets the time-lock delta, in blocks.
+    TimeLockDelta(u32),
+}
+
+/// What a rule sees of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// The short channel id.
+    pub chan_id: u64,
+    /// The funding outpoint, as `txid:output_index`.
+    pub channel_point: String,
+    /// The peer.
+    pub remote_pubkey: String,
+    /// The total capacity, in satoshis.
+    pub capacity: i64,
+    /// Our balance, in satoshis.
+    pub local_balance: i64,
+    /// The current base fee, in millisatoshis.
+    pub base_fee_msat: i64,
+    /// The current fee rate, in parts per million.
+    pub fee_rate_ppm: u32,
+    /// The amount forwarded out through the channel within the forwarding window, in
+    /// millisatoshis.
+    pub forwarded_out_msat: u64,
+    /// The fees earned on those forwards, in millisatoshis.
+    pub fees_earned_msat: u64,
+}
+
+impl ChannelStats {
+    /// Returns the share of the capacity on our side, from 0 to 1.
+    pub fn local_ratio(&self) -> f64 {
+        if self.capacity <= 0 {
+            return 0.0;
+        }
+        (self.local_balance as f64 / self.capacity as f64).clamp(0.0, 1.0)
+    }
+}
+
+/// The routing policy of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelPolicy {
+    /// The base fee, in millisatoshis.
+    pub base_fee_msat: i64,
+    /// The fee rate, in parts per million.
+    pub fee_rate_ppm: u32,
+    /// The time-lock delta, in blocks.
+    pub time_lock_delta: u32,
+    /// The smallest HTLC forwarded, or unchanged when `None`.
+    pub min_htlc_msat: Option<u64>,
+    /// The largest HTLC forwarded, or unchanged when `None`.
+    pub max_htlc_msat: Option<u64>,
+}
+
+/// A policy the engine applied, or would have applied in dry-run mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedPolicy {
+    /// The short channel id.
+    pub chan_id: u64,
+    /// The funding outpoint, as `txid:output_index`.
+    pub channel_point: String,
+    /// The policy before the change, as announced for our side of the channel.
+    pub previous: ChannelPolicy,
+    /// The new policy.
+    pub policy: ChannelPolicy,
+    /// When it was applied, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Whether it was only computed because of `dry_run`.
+    pub dry_run: bool,
+    /// Why lnd rejected it, if it did.
+    pub error: Option<String>,
+}
+
+/// The rules and limits of a `FeePolicyEngine`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeePolicyConfig {
+    /// The rules applied to every channel.
+    pub rules: Vec<PolicyRule>,
+    /// Rules applied to single channels after `rules`, by short channel id.
+    pub channel_rules: HashMap<u64, Vec<PolicyRule>>,
+    /// The time-lock delta used unless a rule sets one. lnd refuses values below 18.
+    pub time_lock_delta: u32,
+    /// Only log what would change instead of applying it.
+    pub dry_run: bool,
+    /// The shortest time between two updates of the same channel, since every update is
+    /// gossiped to the whole network.
+    pub min_update_interval: Duration,
+    /// Changes of the fee rate smaller than this are not applied.
+    pub min_fee_rate_change_ppm: u32,
+    /// How far back forwards are counted in `ChannelStats`.
+    pub forwarding_window: Duration,
+    /// How many applied policies are kept in the history.
+    pub history_limit: usize,
+    /// How often `start` evaluates the rules.
+    pub poll_interval: Duration,
+}
+
+impl FeePolicyConfig {
+    /// Checks that every rule can be evaluated.
+    fn validate(&self) -> Result<()> {
+        for rule in self
+            .rules
+            .iter()
+            .chain(self.channel_rules.values().flatten())
+        {
+            if let PolicyRule::LiquidityCurve {
+                min_ppm,
+                max_ppm,
+                exponent,
+            } = *rule
+            {
+                if min_ppm > max_ppm {
+                    anyhow::bail!(
+                        "Liquidity curve minimum of {} ppm is above its maximum of {} ppm",
+                        min_ppm,
+                        max_ppm
+                    );
+                }
+                if !exponent.is_finite() || exponent < 0.0 {
+                    anyhow::bail!(
+                        "Liquidity curve exponent {} must be finite and not negative",
+                        exponent
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for FeePolicyConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            channel_rules: HashMap::new(),
+            time_lock_delta: 80,
+            dry_run: false,
+            min_update_interval: Duration::from_secs(60 * 60),
+            min_fee_rate_change_ppm: 10,
+            forwarding_window: Duration::from_secs(7 * 24 * 60 * 60),
+            history_limit: 1000,
+            poll_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    last_update: HashMap<u64, Instant>,
+    history: VecDeque<AppliedPolicy>,
+}
+
+/// Evaluates fee rules per channel and applies the results with `updateChannelPolicy`.
+///
+/// The fees are compared with lnd's fee report. It has no time-lock delta or HTLC
+/// limits, so those are compared with our side of the channel in lnd's graph.
+#[derive(Clone)]
+pub struct FeePolicyEngine {
+    client: LndClient,
+    config: Arc<FeePolicyConfig>,
+    state: Arc<Mutex<State>>,
+    running: Arc<AtomicBool>,
+    /// Counts the calls to `start`, so a thread left over from before a `stop` exits
+    /// instead of running next to the new one.
+    generation: Arc<AtomicU64>,
+}
+
+impl FeePolicyEngine {
+    /// Creates an engine with the given rules, or fails if a rule is invalid.
+    pub fn new(client: LndClient, config: FeePolicyConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            client,
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(State::default())),
+            running: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns the channels as the rules see them.
+    pub fn channel_stats(&self) -> Result<Vec<ChannelStats>> {
+        let channels: lnrpc::ListChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListChannelsRequest::default(), listChannels)?;
+        let report: lnrpc::FeeReportResponse = self
+            .client
+            .call_lnd_method(lnrpc::FeeReportRequest {}, feeReport)?;
+        let fees: HashMap<u64, lnrpc::ChannelFeeReport> = report
+            .channel_fees
+            .into_iter()
+            .map(|fees| (fees.chan_id, fees))
+            .collect();
+        let forwards = self.forwards_by_channel()?;
+
+        Ok(channels
+            .channels
+            .into_iter()
+            .map(|channel| {
+                let fees = fees.get(&channel.chan_id);
+                let (forwarded_out_msat, fees_earned_msat) =
+                    forwards.get(&channel.chan_id).copied().unwrap_or_default();
+                ChannelStats {
+                    chan_id: channel.chan_id,
+                    channel_point: channel.channel_point,
+                    remote_pubkey: channel.remote_pubkey,
+                    capacity: channel.capacity,
+                    local_balance: channel.local_balance,
+                    base_fee_msat: fees.map_or(0, |fees| fees.base_fee_msat),
+                    fee_rate_ppm: fees.map_or(0, |fees| fees.fee_per_mil as u32),
+                    forwarded_out_msat,
+                    fees_earned_msat,
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the policy the rules give a channel.
+    pub fn evaluate(&self, channel: &ChannelStats) -> ChannelPolicy {
+        let mut policy = ChannelPolicy {
+            base_fee_msat: channel.base_fee_msat,
+            fee_rate_ppm: channel.fee_rate_ppm,
+            time_lock_delta: self.config.time_lock_delta,
+            min_htlc_msat: None,
+            max_htlc_msat: None,
+        };
+        let channel_rules = self.config.channel_rules.get(&channel.chan_id);
+        for rule in self
+            .config
+            .rules
+            .iter()
+            .chain(channel_rules.into_iter().flatten())
+        {
+            match *rule {
+                PolicyRule::LiquidityCurve {
+                    min_ppm,
+                    max_ppm,
+                    exponent,
+                } => {
+                    let scale = (1.0 - channel.local_ratio()).powf(exponent);
+                    let range = max_ppm.saturating_sub(min_ppm) as f64;
+                    policy.fee_rate_ppm = min_ppm
+                        .saturating_add((range * scale).round() as u32)
+                        .min(max_ppm.max(min_ppm));
+                }
+                PolicyRule::FeeRate(ppm) => policy.fee_rate_ppm = ppm,
+                PolicyRule::BaseFee(msat) => policy.base_fee_msat = msat,
+                PolicyRule::MinHtlc(msat) => policy.min_htlc_msat = Some(msat),
+                PolicyRule::MaxHtlc(msat) => policy.max_htlc_msat = Some(msat),
+                PolicyRule::MaxHtlcRatio(ratio) => {
+                    let msat = (channel.local_balance.max(0) as f64 * 1000.0 * ratio) as u64;
+                    // lnd refuses a maximum of zero.
+                    policy.max_htlc_msat = Some(msat.max(1));
+                }
+                PolicyRule::TimeLockDelta(delta) => policy.time_lock_delta = delta,
+            }
+        }
+        policy
+    }
+
+    /// Evaluates every channel once and applies the changes that are due.
+    ///
+    /// # Returns
+    ///
+    /// The policies applied, or that would have been in dry-run mode. Channels updated
+    /// within `min_update_interval` are skipped.
+    pub fn run_once(&self) -> Result<Vec<AppliedPolicy>> {
+        let mut applied = Vec::new();
+        for channel in self.channel_stats()? {
+            let recent = self
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .last_update
+                .get(&channel.chan_id)
+                .is_some_and(|at| at.elapsed() < self.config.min_update_interval);
+            if recent {
+                continue;
+            }
+            let policy = self.evaluate(&channel);
+            let previous = self.current_policy(&channel)?;
+            if !self.is_significant(&previous, &policy) {
+                continue;
+            }
+
+            let error = if self.config.dry_run {
+                None
+            } else {
+                self.apply(&channel.channel_point, &policy).err()
+            };
+            let record = AppliedPolicy {
+                chan_id: channel.chan_id,
+                channel_point: channel.channel_point,
+                previous,
+                policy,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                dry_run: self.config.dry_run,
+                error,
+            };
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            // Dry runs are rate limited too, so they report what a real run would do.
+            if record.error.is_none() {
+                state.last_update.insert(record.chan_id, Instant::now());
+            }
+            state.history.push_back(record.clone());
+            while state.history.len() > self.config.history_limit {
+                state.history.pop_front();
+            }
+            applied.push(record);
+        }
+        Ok(applied)
+    }
+
+    /// Returns the policies applied so far, oldest first.
+    pub fn history(&self) -> Vec<AppliedPolicy> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Runs `run_once` every `poll_interval` on a background thread until `stop` is
+    /// called or the client shuts down. `on_applied` receives each applied change, or
+    /// the error of an evaluation that failed.
+    pub fn start<F>(&self, on_applied: F)
+    where
+        F: Fn(Result<&AppliedPolicy, String>) + Send + Sync + 'static,
+    {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let on_applied: AppliedHandler = Box::new(on_applied);
+        let scheduler = Scheduler {
+            client: self.client.downgrade(),
+            config: self.config.clone(),
+            state: self.state.clone(),
+            running: self.running.clone(),
+            generation: self.generation.clone(),
+            started: self.generation.fetch_add(1, Ordering::AcqRel) + 1,
+        };
+        thread::spawn(move || scheduler.run(on_applied));
+    }
+
+    /// Stops the evaluations started by `start`.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    /// Returns the policy of our side of the channel, with the fees from the fee report
+    /// and the rest from `getChanInfo`.
+    fn current_policy(&self, channel: &ChannelStats) -> Result<ChannelPolicy> {
+        let edge: lnrpc::ChannelEdge = self.client.call_lnd_method(
+            lnrpc::ChanInfoRequest {
+                chan_id: channel.chan_id,
+                ..Default::default()
+            },
+            getChanInfo,
+        )?;
+        let ours = if edge.node1_pub == channel.remote_pubkey {
+            edge.node2_policy
+        } else {
+            edge.node1_policy
+        }
+        .with_context(|| format!("No policy of ours for channel {}", channel.chan_id))?;
+        Ok(ChannelPolicy {
+            base_fee_msat: channel.base_fee_msat,
+            fee_rate_ppm: channel.fee_rate_ppm,
+            time_lock_delta: ours.time_lock_delta,
+            min_htlc_msat: Some(ours.min_htlc.max(0) as u64),
+            max_htlc_msat: Some(ours.max_htlc_msat),
+        })
+    }
+
+    fn is_significant(&self, previous: &ChannelPolicy, policy: &ChannelPolicy) -> bool {
+        let rate_change = previous.fee_rate_ppm.abs_diff(policy.fee_rate_ppm);
+        // HTLC limits no rule sets stay as they are.
+        let changed = |previous: Option<u64>, next: Option<u64>| {
+            next.is_some_and(|next| previous != Some(next))
+        };
+        (rate_change > 0 && rate_change >= self.config.min_fee_rate_change_ppm)
+            || previous.base_fee_msat != policy.base_fee_msat
+            || previous.time_lock_delta != policy.time_lock_delta
+            || changed(previous.min_htlc_msat, policy.min_htlc_msat)
+            || changed(previous.max_htlc_msat, policy.max_htlc_msat)
+    }
+
+    fn apply(&self, channel_point: &str, policy: &ChannelPolicy) -> Result<(), String> {
+        let chan_point = parse_channel_point(channel_point).map_err(|e| e.to_string())?;
+        let response: lnrpc::PolicyUpdateResponse = self
+            .client
+            .call_lnd_method(
+                lnrpc::PolicyUpdateRequest {
+                    base_fee_msat: policy.base_fee_msat,
+                    fee_rate_ppm: policy.fee_rate_ppm,
+                    time_lock_delta: policy.time_lock_delta,
+                    min_htlc_msat: policy.min_htlc_msat.unwrap_or_default(),
+                    min_htlc_msat_specified: policy.min_htlc_msat.is_some(),
+                    // Zero leaves the maximum unchanged.
+                    max_htlc_msat: policy.max_htlc_msat.unwrap_or_default(),
+                    scope: Some(Scope::ChanPoint(chan_point)),
+                    ..Default::default()
+                },
+                updateChannelPolicy,
+            )
+            .map_err(|e| e.to_string())?;
+        match response.failed_updates.first() {
+            Some(failed) => Err(failed.update_error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Sums the forwards out of each channel within the forwarding window.
+    fn forwards_by_channel(&self) -> Result<HashMap<u64, (u64, u64)>> {
+        let start = SystemTime::now() - self.config.forwarding_window;
+        let mut forwards: HashMap<u64, (u64, u64)> = HashMap::new();
+        for event in ForwardingHistory::new(self.client.clone()).since(start) {
+            let event = event?;
+            let totals = forwards.entry(event.chan_id_out).or_default();
+            totals.0 += event.amt_out_msat;
+            totals.1 += event.fee_msat;
+        }
+        Ok(forwards)
+    }
+}
+
+/// The background side of `FeePolicyEngine::start`, which must not keep the client
+/// alive.
+struct Scheduler {
+    client: WeakLndClient,
+    config: Arc<FeePolicyConfig>,
+    state: Arc<Mutex<State>>,
+    running: Arc<AtomicBool>,
+    generation: Arc<AtomicU64>,
+    /// The generation this thread belongs to.
+    started: u64,
+}
+
+impl Scheduler {
+    fn is_current(&self) -> bool {
+        self.generation.load(Ordering::Acquire) == self.started
+    }
+
+    fn run(self, on_applied: AppliedHandler) {
+        while self.running.load(Ordering::Acquire) && self.is_current() {
+            let Some(client) = self.client.upgrade() else {
+                break;
+            };
+            if !client.is_running() {
+                break;
+            }
+            let engine = FeePolicyEngine {
+                client,
+                config: self.config.clone(),
+                state: self.state.clone(),
+                running: self.running.clone(),
+                generation: self.generation.clone(),
+            };
+            match engine.run_once() {
+                Ok(applied) => applied.iter().for_each(|change| on_applied(Ok(change))),
+                Err(e) => on_applied(Err(format!("Fee policy evaluation failed: {}", e))),
+            }
+            drop(engine);
+            thread::sleep(self.config.poll_interval);
+        }
+        if self.is_current() {
+            self.running.store(false, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use crate::export::{ExportRecord, Field};
+use crate::pagination::{unix_seconds, Pages};
+use crate::{
+    closedChannels, forwardingHistory, listChannels, routerSubscribeHtlcEvents, LndClient,
+    SubscriptionId,
+};
+use anyhow::Result;
+use lnd_grpc_rust::{lnrpc, routerrpc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// The default number of events fetched per `forwardingHistory` call, which is also
+/// lnd's maximum.
+const DEFAULT_PAGE_SIZE: u32 = 50_000;
+
+/// Iterates over all forwarding events, fetching them page by page.
+///
+/// Iteration ends after the last page or the first error.
+pub struct ForwardingHistory {
+    client: LndClient,
+    start_time: u64,
+    end_time: u64,
+    page_size: u32,
+    index_offset: u32,
+    pages: Pages<lnrpc::ForwardingEvent>,
+}
+
+impl ForwardingHistory {
+    /// Creates an iterator over the whole history.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            start_time: 0,
+            end_time: 0,
+            page_size: DEFAULT_PAGE_SIZE,
+            index_offset: 0,
+            pages: Pages::new(),
+        }
+    }
+
+    /// Only returns the events between `start` and `end`.
+    pub fn between(mut self, start: SystemTime, end: SystemTime) -> Self {
+        self.start_time = unix_seconds(start);
+        self.end_time = unix_seconds(end);
+        self
+    }
+
+    /// Only returns the events since `start`.
+    pub fn since(mut self, start: SystemTime) -> Self {
+        self.start_time = unix_seconds(start);
+        self
+    }
+
+    /// Sets how many events each call fetches.
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size.clamp(1, DEFAULT_PAGE_SIZE);
+        self
+    }
+
+    fn fetch_page(&mut self) -> Result<(Vec<lnrpc::ForwardingEvent>, bool)> {
+        let response: lnrpc::ForwardingHistoryResponse = self.client.call_lnd_method(
+            lnrpc::ForwardingHistoryRequest {
+                start_time: self.start_time,
+                end_time: self.end_time,
+                index_offset: self.index_offset,
+                num_max_events: self.page_size,
+                peer_alias_lookup: false,
+            },
+            forwardingHistory,
+        )?;
+        let last = response.forwarding_events.len() < self.page_size as usize
+            || response.last_offset_index <= self.index_offset;
+        self.index_offset = response.last_offset_index;
+        Ok((response.forwarding_events, last))
+    }
+}
+
+impl Iterator for ForwardingHistory {
+    type Item = Result<lnrpc::ForwardingEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut pages = std::mem::replace(&mut self.pages, Pages::new());
+        let item = pages.next(|| self.fetch_page());
+        self.pages = pages;
+        item
+    }
+}
+
+/// The forwards out of one channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelEarnings {
+    /// The short channel id.
+    pub chan_id: u64,
+    /// How many HTLCs were forwarded out of it.
+    pub forwards: u64,
+    /// The amount forwarded out of it, in millisatoshis.
+    pub volume_msat: u64,
+    /// The fees earned, in millisatoshis.
+    pub fee_msat: u64,
+}
+
+/// The forwards out of the channels with one peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerEarnings {
+    /// The peer, or empty for channels that are no longer known.
+    pub pubkey: String,
+    /// How many HTLCs were forwarded to the peer.
+    pub forwards: u64,
+    /// The amount forwarded to the peer, in millisatoshis.
+    pub volume_msat: u64,
+    /// The fees earned, in millisatoshis.
+    pub fee_msat: u64,
+}
+
+/// The forwards within one time bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VolumeBucket {
+    /// The start of the bucket, in seconds since the Unix epoch.
+    pub start: u64,
+    /// How many HTLCs were forwarded.
+    pub forwards: u64,
+    /// The amount forwarded, in millisatoshis.
+    pub volume_msat: u64,
+    /// The fees earned, in millisatoshis.
+    pub fee_msat: u64,
+}
+
+/// Sums the events per outgoing channel, which is the channel the fees are earned on.
+///
+/// # Returns
+///
+/// The channels, highest earning first.
+pub fn fees_by_channel(events: &[lnrpc::ForwardingEvent]) -> Vec<ChannelEarnings> {
+    let mut channels: HashMap<u64, ChannelEarnings> = HashMap::new();
+    for event in events {
+        let earnings = channels
+            .entry(event.chan_id_out)
+            .or_insert_with(|| ChannelEarnings {
+                chan_id: event.chan_id_out,
+                ..Default::default()
+            });
+        earnings.forwards += 1;
+        earnings.volume_msat += event.amt_out_msat;
+        earnings.fee_msat += event.fee_msat;
+    }
+    let mut channels: Vec<ChannelEarnings> = channels.into_values().collect();
+    channels.sort_by(|a, b| b.fee_msat.cmp(&a.fee_msat).then(a.chan_id.cmp(&b.chan_id)));
+    channels
+}
+
+/// Sums the events per time bucket of `bucket` length, aligned to the Unix epoch.
+///
+/// # Returns
+///
+/// The buckets that have events, oldest first.
+pub fn volume_by_bucket(events: &[lnrpc::ForwardingEvent], bucket: Duration) -> Vec<VolumeBucket> {
+    let bucket = bucket.as_secs().max(1);
+    let mut buckets: BTreeMap<u64, VolumeBucket> = BTreeMap::new();
+    for event in events {
+        let timestamp = event.timestamp_ns / 1_000_000_000;
+        let start = timestamp - timestamp % bucket;
+        let totals = buckets.entry(start).or_insert_with(|| VolumeBucket {
+            start,
+            ..Default::default()
+        });
+        totals.forwards += 1;
+        totals.volume_msat += event.amt_out_msat;
+        totals.fee_msat += event.fee_msat;
+    }
+    buckets.into_values().collect()
+}
+
+/// Forwarding statistics that need more than the events themselves.
+#[derive(Clone)]
+pub struct ForwardingAnalytics {
+    client: LndClient,
+}
+
+impl ForwardingAnalytics {
+    /// Creates the analytics.
+    pub fn new(client: LndClient) -> Self {
+        Self { client }
+    }
+
+    /// Returns an iterator over the whole forwarding history.
+    pub fn history(&self) -> ForwardingHistory {
+        ForwardingHistory::new(self.client.clone())
+    }
+
+    /// Sums the events per peer of the outgoing channel, looking up closed channels too.
+    ///
+    /// # Returns
+    ///
+    /// The peers, highest earning first.
+    pub fn fees_by_peer(&self, events: &[lnrpc::ForwardingEvent]) -> Result<Vec<PeerEarnings>> {
+        let open: lnrpc::ListChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListChannelsRequest::default(), listChannels)?;
+        let closed: lnrpc::ClosedChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ClosedChannelsRequest::default(), closedChannels)?;
+        let peers: HashMap<u64, String> = open
+            .channels
+            .into_iter()
+            .map(|channel| (channel.chan_id, channel.remote_pubkey))
+            .chain(
+                closed
+                    .channels
+                    .into_iter()
+                    .map(|channel| (channel.chan_id, channel.remote_pubkey)),
+            )
+            .collect();
+
+        let mut earnings: HashMap<String, PeerEarnings> = HashMap::new();
+        for channel in fees_by_channel(events) {
+            let pubkey = peers.get(&channel.chan_id).cloned().unwrap_or_default();
+            let peer = earnings
+                .entry(pubkey.clone())
+                .or_insert_with(|| PeerEarnings {
+                    pubkey,
+                    ..Default::default()
+                });
+            peer.forwards += channel.forwards;
+            peer.volume_msat += channel.volume_msat;
+            peer.fee_msat += channel.fee_msat;
+        }
+        let mut earnings: Vec<PeerEarnings> = earnings.into_values().collect();
+        earnings.sort_by(|a, b| b.fee_msat.cmp(&a.fee_msat).then(a.pubkey.cmp(&b.pubkey)));
+        Ok(earnings)
+    }
+}
+
+/// The failed forwards between two channels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FailedForwards {
+    /// The channel the HTLC came in on.
+    pub incoming_chan_id: u64,
+    /// The channel it was to be forwarded on.
+    pub outgoing_chan_id: u64,
+    /// How many failed at our node, e.g. for lack of outgoing liquidity.
+    pub link_failures: u64,
+    /// How many failed further down the route after being forwarded.
+    pub downstream_failures: u64,
+    /// The reason of the last failure at our node.
+    pub last_failure: String,
+}
+
+/// Counts failed forwards from `routerSubscribeHtlcEvents`, which
+/// `forwardingHistory` does not report.
+#[derive(Clone)]
+pub struct FailedForwardCounter {
+    client: LndClient,
+    counts: Arc<Mutex<HashMap<(u64, u64), FailedForwards>>>,
+    subscription: Arc<Mutex<Option<SubscriptionId>>>,
+}
+
+impl FailedForwardCounter {
+    /// Creates a counter. Nothing is counted until `start` is called.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            subscription: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts counting.
+    pub fn start(&self) -> Result<()> {
+        let counts = Arc::downgrade(&self.counts);
+        let id = self
+            .client
+            .subscribe_events::<routerrpc::HtlcEvent, routerrpc::SubscribeHtlcEventsRequest>(
+                routerSubscribeHtlcEvents,
+            )
+            .on_event(move |event| {
+                let (Ok(event), Some(counts)) = (event, counts.upgrade()) else {
+                    return;
+                };
+                if event.event_type() != routerrpc::htlc_event::EventType::Forward {
+                    return;
+                }
+                let mut counts = counts.lock().unwrap_or_else(PoisonError::into_inner);
+                let key = (event.incoming_channel_id, event.outgoing_channel_id);
+                let entry = || FailedForwards {
+                    incoming_chan_id: key.0,
+                    outgoing_chan_id: key.1,
+                    ..Default::default()
+                };
+                match event.event {
+                    Some(routerrpc::htlc_event::Event::LinkFailEvent(failure)) => {
+                        let failed = counts.entry(key).or_insert_with(entry);
+                        failed.link_failures += 1;
+                        failed.last_failure = failure.failure_string;
+                    }
+                    Some(routerrpc::htlc_event::Event::ForwardFailEvent(_)) => {
+                        counts.entry(key).or_insert_with(entry).downstream_failures += 1;
+                    }
+                    _ => {}
+                }
+            })
+            .with_request(routerrpc::SubscribeHtlcEventsRequest {})
+            .subscribe()?;
+        let previous = self
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(id);
+        if let Some(previous) = previous {
+            self.client.unsubscribe(previous);
+        }
+        Ok(())
+    }
+
+    /// Stops counting. The counts are kept.
+    pub fn stop(&self) {
+        let subscription = self
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.client.unsubscribe(id);
+        }
+    }
+
+    /// Returns the failures counted so far, most failures first.
+    pub fn counts(&self) -> Vec<FailedForwards> {
+        let mut counts: Vec<FailedForwards> = self
+            .counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        counts.sort_by_key(|failed| {
+            (
+                std::cmp::Reverse(failed.link_failures + failed.downstream_failures),
+                failed.incoming_chan_id,
+                failed.outgoing_chan_id,
+            )
+        });
+        counts
+    }
+}
+
+impl ExportRecord for lnrpc::ForwardingEvent {
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        vec![
+            ("timestamp_ns", Field::UInt(self.timestamp_ns)),
+            ("chan_id_in", Field::UInt(self.chan_id_in)),
+            ("chan_id_out", Field::UInt(self.chan_id_out)),
+            ("amt_in_msat", Field::UInt(self.amt_in_msat)),
+            ("amt_out_msat", Field::UInt(self.amt_out_msat)),
+            ("fee_msat", Field::UInt(self.fee_msat)),
+        ]
+    }
+}
+
+impl ExportRecord for ChannelEarnings {
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        vec![
+            ("chan_id", Field::UInt(self.chan_id)),
+            ("forwards", Field::UInt(self.forwards)),
+            ("volume_msat", Field::UInt(self.volume_msat)),
+            ("fee_msat", Field::UInt(self.fee_msat)),
+        ]
+    }
+}
+
+impl ExportRecord for PeerEarnings {
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        vec![
+            ("pubkey", Field::Text(self.pubkey.clone())),
+            ("forwards", Field::UInt(self.forwards)),
+            ("volume_msat", Field::UInt(self.volume_msat)),
+            ("fee_msat", Field::UInt(self.fee_msat)),
+        ]
+    }
+}
+
+impl ExportRecord for VolumeBucket {
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        vec![
+            ("start", Field::UInt(self.start)),
+            ("forwards", Field::UInt(self.forwards)),
+            ("volume_msat", Field::UInt(self.volume_msat)),
+            ("fee_msat", Field::UInt(self.fee_msat)),
+        ]
+    }
+}
+
+impl ExportRecord for FailedForwards {
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        vec![
+            ("incoming_chan_id", Field::UInt(self.incoming_chan_id)),
+            ("outgoing_chan_id", Field::UInt(self.outgoing_chan_id)),
+            ("link_failures", Field::UInt(self.link_failures)),
+            ("downstream_failures", Field::UInt(self.downstream_failures)),
+            ("last_failure", Field::Text(self.last_failure.clone())),
+        ]
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{listInvoices, subscribeInvoices, LndClient, SubscriptionId};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// How many invoices are requested per `listInvoices` call while backfilling.
+const DEFAULT_PAGE_SIZE: u64 = 100;
+
+type EventHandler = Box<dyn Fn(Result<InvoiceEvent, String>) + Send + Sync>;
+
+/// The position of an `InvoiceFeed` in lnd's invoice history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InvoiceIndices {
+    /// The `add_index` of the last invoice reported as added.
+    pub add_index: u64,
+    /// The `settle_index` of the last invoice reported as settled.
+    pub settle_index: u64,
+}
+
+/// Persists the indices of an `InvoiceFeed`, so it can resume after a restart.
+pub trait IndexStore: Send + Sync {
+    /// Returns the saved indices, or zeroes if none were saved yet.
+    fn load(&self) -> Result<InvoiceIndices>;
+
+    /// Saves the indices after an event was handled.
+    fn save(&self, indices: InvoiceIndices) -> Result<()>;
+}
+
+impl<S: IndexStore + ?Sized> IndexStore for Arc<S> {
+    fn load(&self) -> Result<InvoiceIndices> {
+        (**self).load()
+    }
+
+    fn save(&self, indices: InvoiceIndices) -> Result<()> {
+        (**self).save(indices)
+    }
+}
+
+/// Keeps the indices in memory only.
+#[derive(Debug, Default)]
+pub struct MemoryIndexStore {
+    indices: Mutex<InvoiceIndices>,
+}
+
+impl MemoryIndexStore {
+    /// Creates a store starting at the given indices.
+    pub fn new(indices: InvoiceIndices) -> Self {
+        Self {
+            indices: Mutex::new(indices),
+        }
+    }
+}
+
+impl IndexStore for MemoryIndexStore {
+    fn load(&self) -> Result<InvoiceIndices> {
+        Ok(*self.indices.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn save(&self, indices: InvoiceIndices) -> Result<()> {
+        *self.indices.lock().unwrap_or_else(PoisonError::into_inner) = indices;
+        Ok(())
+    }
+}
+
+/// Keeps the indices in a small text file, replaced atomically on every save.
+#[derive(Debug, Clone)]
+pub struct FileIndexStore {
+    path: PathBuf,
+}
+
+impl FileIndexStore {
+    /// Creates a store backed by the file at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl IndexStore for FileIndexStore {
+    fn load(&self) -> Result<InvoiceIndices> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(InvoiceIndices::default())
+            }
+            Err(e) => return Err(e).context("Failed to read invoice indices"),
+        };
+        let mut values = contents.split_whitespace().map(str::parse::<u64>);
+        match (values.next(), values.next()) {
+            (Some(Ok(add_index)), Some(Ok(settle_index))) => Ok(InvoiceIndices {
+                add_index,
+                settle_index,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Invalid invoice indices in {}",
+                self.path.display()
+            )),
+        }
+    }
+
+    fn save(&self, indices: InvoiceIndices) -> Result<()> {
+        let temp = self.path.with_extension("tmp");
+        fs::write(
+            &temp,
+            format!("{} {}\n", indices.add_index, indices.settle_index),
+        )
+        .context("Failed to write invoice indices")?;
+        fs::rename(&temp, &self.path).context("Failed to replace invoice indices")
+    }
+}
+
+/// An event of an `InvoiceFeed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvoiceEvent {
+    /// An invoice was created, reported in `add_index` order.
+    Added(lnrpc::Invoice),
+    /// An invoice was settled, reported in `settle_index` order.
+    Settled(lnrpc::Invoice),
+}
+
+struct FeedState {
+    indices: InvoiceIndices,
+    /// Live updates that arrived while backfilling, handled once the backfill is done.
+    buffered: Option<Vec<lnrpc::Invoice>>,
+    /// Settlements found while backfilling, reported in `settle_index` order once the
+    /// backfill is done.
+    settlements: Vec<lnrpc::Invoice>,
+    subscription: Option<SubscriptionId>,
+}
+
+struct Inner {
+    client: LndClient,
+    store: Box<dyn IndexStore>,
+    handler: EventHandler,
+    page_size: u64,
+    state: Mutex<FeedState>,
+}
+
+impl Inner {
+    /// Reports the parts of `invoice` that are newer than the stored indices.
+    fn process(&self, state: &mut FeedState, invoice: lnrpc::Invoice) {
+        self.report_added(state, &invoice);
+        self.report_settled(state, invoice);
+    }
+
+    /// Reports the addition of `invoice` and queues its settlement, if it is newer
+    /// than the stored indices. Used while backfilling, where invoices arrive in
+    /// `add_index` order but settlements must be reported in `settle_index` order.
+    fn queue(&self, state: &mut FeedState, invoice: lnrpc::Invoice) {
+        self.report_added(state, &invoice);
+        if invoice.settle_index > state.indices.settle_index {
+            state.settlements.push(invoice);
+        }
+    }
+
+    fn report_added(&self, state: &mut FeedState, invoice: &lnrpc::Invoice) {
+        if invoice.add_index > state.indices.add_index {
+            state.indices.add_index = invoice.add_index;
+            (self.handler)(Ok(InvoiceEvent::Added(invoice.clone())));
+            self.save(state.indices);
+        }
+    }
+
+    fn report_settled(&self, state: &mut FeedState, invoice: lnrpc::Invoice) {
+        if invoice.settle_index > state.indices.settle_index {
+            state.indices.settle_index = invoice.settle_index;
+            let indices = state.indices;
+            (self.handler)(Ok(InvoiceEvent::Settled(invoice)));
+            self.save(indices);
+        }
+    }
+
+    fn save(&self, indices: InvoiceIndices) {
+        if let Err(e) = self.store.save(indices) {
+            (self.handler)(Err(format!("Failed to save invoice indices: {}", e)));
+        }
+    }
+
+    fn on_live(&self, event: Result<lnrpc::Invoice, String>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match event {
+            Ok(invoice) => match &mut state.buffered {
+                Some(buffered) => buffered.push(invoice),
+                None => self.process(&mut state, invoice),
+            },
+            Err(error) => {
+                state.subscription = None;
+                (self.handler)(Err(error));
+            }
+        }
+    }
+}
+
+/// A stream of invoice additions and settlements that survives restarts.
+///
+/// The feed remembers the last `add_index` and `settle_index` it reported in an
+/// `IndexStore`. Each time it subscribes, it first backfills the invoices added since
+/// then with `listInvoices`, and lnd replays the settlements since the stored
+/// `settle_index`. Every addition and settlement is reported once and in order, as long
+/// as the store saves successfully.
+#[derive(Clone)]
+pub struct InvoiceFeed {
+    inner: Arc<Inner>,
+}
+
+impl InvoiceFeed {
+    /// Creates a feed. Nothing is delivered until `subscribe` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to subscribe through.
+    /// * `store` - Where the feed's position is kept.
+    /// * `on_event` - Receives every event, and stream errors as `Err`.
+    pub fn new<S, F>(client: LndClient, store: S, on_event: F) -> Self
+    where
+        S: IndexStore + 'static,
+        F: Fn(Result<InvoiceEvent, String>) + Send + Sync + 'static,
+    {
+        Self::with_page_size(client, store, on_event, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Like `new`, but backfills with pages of `page_size` invoices.
+    pub fn with_page_size<S, F>(client: LndClient, store: S, on_event: F, page_size: u64) -> Self
+    where
+        S: IndexStore + 'static,
+        F: Fn(Result<InvoiceEvent, String>) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                store: Box::new(store),
+                handler: Box::new(on_event),
+                page_size: page_size.max(1),
+                state: Mutex::new(FeedState {
+                    indices: InvoiceIndices::default(),
+                    buffered: None,
+                    settlements: Vec::new(),
+                    subscription: None,
+                }),
+            }),
+        }
+    }
+
+    /// Returns the indices of the last reported events.
+    pub fn indices(&self) -> InvoiceIndices {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .indices
+    }
+
+    /// Subscribes to lnd, backfilling everything missed since the stored indices.
+    ///
+    /// Call it again after the stream reported an error to resume where it stopped.
+    pub fn subscribe(&self) -> Result<()> {
+        let indices = self.inner.store.load()?;
+        {
+            let mut state = self
+                .inner
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(id) = state.subscription.take() {
+                self.inner.client.unsubscribe(id);
+            }
+            state.indices = indices;
+            state.buffered = Some(Vec::new());
+            state.settlements.clear();
+        }
+
+        // Subscribe before backfilling, so nothing added in between is missed.
+        let inner = Arc::downgrade(&self.inner);
+        let subscription = self
+            .inner
+            .client
+            .subscribe_events::<lnrpc::Invoice, lnrpc::InvoiceSubscription>(subscribeInvoices)
+            .on_event(move |event| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.on_live(event);
+                }
+            })
+            .with_request(lnrpc::InvoiceSubscription {
+                add_index: indices.add_index,
+                settle_index: indices.settle_index,
+            })
+            .subscribe();
+        let subscription = match subscription {
+            Ok(id) => id,
+            Err(e) => {
+                self.stop_buffering();
+                return Err(e);
+            }
+        };
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .subscription = Some(subscription);
+
+        let backfilled = self.backfill(indices.add_index);
+        self.stop_buffering();
+        backfilled
+    }
+
+    /// Stops the feed. The stored indices are kept.
+    pub fn unsubscribe(&self) {
+        let subscription = self
+            .inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .subscription
+            .take();
+        if let Some(id) = subscription {
+            self.inner.client.unsubscribe(id);
+        }
+    }
+
+    fn backfill(&self, mut index_offset: u64) -> Result<()> {
+        loop {
+            let response: lnrpc::ListInvoiceResponse = self.inner.client.call_lnd_method(
+                lnrpc::ListInvoiceRequest {
+                    index_offset,
+                    num_max_invoices: self.inner.page_size,
+                    ..Default::default()
+                },
+                listInvoices,
+            )?;
+
+            let done = response.invoices.is_empty() || response.last_index_offset <= index_offset;
+            let mut state = self
+                .inner
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            for invoice in response.invoices {
+                self.inner.queue(&mut state, invoice);
+            }
+            if done {
+                return Ok(());
+            }
+            index_offset = response.last_index_offset;
+        }
+    }
+
+    /// Handles the live updates that arrived during the backfill and switches to
+    /// handling them as they arrive.
+    fn stop_buffering(&self) {
+        let mut state = self
+            .inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for invoice in state.buffered.take().unwrap_or_default() {
+            self.inner.queue(&mut state, invoice);
+        }
+        // The settlements of the backfill and of lnd's replay overlap and interleave.
+        let mut settlements = std::mem::take(&mut state.settlements);
+        settlements.sort_by_key(|invoice| invoice.settle_index);
+        for invoice in settlements {
+            self.inner.report_settled(&mut state, invoice);
+        }
+    }
+}
@@ -0,0 +1,547 @@
+use crate::{
+    addInvoice, chainNotifierRegisterBlockEpochNtfn, invoicesAddHoldInvoice, invoicesCancelInvoice,
+    invoicesLookupInvoiceV2, invoicesSettleInvoice, invoicesSubscribeSingleInvoice, LndClient,
+    SubscriptionId,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::invoicesrpc::lookup_invoice_msg::InvoiceRef;
+use lnd_grpc_rust::lnrpc::invoice::InvoiceState as LndInvoiceState;
+use lnd_grpc_rust::lnrpc::InvoiceHtlcState;
+use lnd_grpc_rust::{chainrpc, invoicesrpc, lnrpc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+/// The secret whose SHA-256 hash locks a payment.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Preimage([u8; 32]);
+
+impl Preimage {
+    /// Generates a random preimage.
+    pub fn generate() -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        openssl::rand::rand_bytes(&mut bytes).context("Failed to generate preimage")?;
+        Ok(Self(bytes))
+    }
+
+    /// Wraps an existing preimage.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the raw preimage.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns the payment hash locked by this preimage.
+    pub fn payment_hash(&self) -> [u8; 32] {
+        openssl::sha::sha256(&self.0)
+    }
+}
+
+impl fmt::Debug for Preimage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Anyone who sees the preimage can claim the payment, so keep it out of logs.
+        write!(f, "Preimage(..)")
+    }
+}
+
+/// The state of an invoice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceState {
+    /// Waiting for a payment.
+    Open,
+    /// A hold invoice received its payment, which can now be settled or canceled.
+    Accepted,
+    /// The payment was received.
+    Settled,
+    /// The invoice can no longer be paid.
+    Canceled,
+}
+
+impl InvoiceState {
+    /// Returns `true` once the invoice was settled or canceled.
+    pub fn is_final(self) -> bool {
+        matches!(self, InvoiceState::Settled | InvoiceState::Canceled)
+    }
+
+    /// Returns whether an invoice may move from this state to `next`.
+    pub fn can_transition_to(self, next: InvoiceState) -> bool {
+        matches!(
+            (self, next),
+            (InvoiceState::Open, InvoiceState::Accepted)
+                | (
+                    InvoiceState::Open | InvoiceState::Accepted,
+                    InvoiceState::Settled | InvoiceState::Canceled
+                )
+        )
+    }
+}
+
+impl From<LndInvoiceState> for InvoiceState {
+    fn from(state: LndInvoiceState) -> Self {
+        match state {
+            LndInvoiceState::Open => InvoiceState::Open,
+            LndInvoiceState::Accepted => InvoiceState::Accepted,
+            LndInvoiceState::Settled => InvoiceState::Settled,
+            LndInvoiceState::Canceled => InvoiceState::Canceled,
+        }
+    }
+}
+
+/// The parameters of a new invoice.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvoiceSpec {
+    /// A description shown to the payer.
+    pub memo: String,
+    /// The amount in millisatoshis, or 0 to let the payer choose.
+    pub value_msat: i64,
+    /// How long the invoice can be paid, or lnd's default when zero.
+    pub expiry: Duration,
+    /// The CLTV delta of the final hop, or lnd's default when zero.
+    pub cltv_expiry: u64,
+    /// Whether to include route hints for private channels.
+    pub private: bool,
+}
+
+/// An invoice followed by an `InvoiceManager`.
+struct Tracked {
+    payment_hash: [u8; 32],
+    payment_request: String,
+    preimage: Option<Preimage>,
+    hold: bool,
+    state: Mutex<InvoiceState>,
+    changed: Condvar,
+    /// The earliest block height at which an accepted HTLC expires.
+    expiry_height: Mutex<Option<u32>>,
+    cancel_requested: AtomicBool,
+    subscription: Mutex<Option<SubscriptionId>>,
+}
+
+impl Tracked {
+    fn state(&self) -> InvoiceState {
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Moves to `next`, ignoring updates that would move the invoice backwards.
+    fn transition(&self, next: InvoiceState) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !state.can_transition_to(next) {
+            return false;
+        }
+        *state = next;
+        self.changed.notify_all();
+        true
+    }
+
+    fn apply(&self, invoice: &lnrpc::Invoice) {
+        let expiry_height = invoice
+            .htlcs
+            .iter()
+            .filter(|htlc| htlc.state() == InvoiceHtlcState::Accepted)
+            .map(|htlc| htlc.expiry_height.max(0) as u32)
+            .min();
+        if expiry_height.is_some() {
+            *self
+                .expiry_height
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = expiry_height;
+        }
+        self.transition(invoice.state().into());
+    }
+}
+
+/// A handle to an invoice created or tracked through an `InvoiceManager`.
+#[derive(Clone)]
+pub struct InvoiceHandle {
+    tracked: Arc<Tracked>,
+}
+
+impl InvoiceHandle {
+    /// Returns the payment hash.
+    pub fn payment_hash(&self) -> &[u8; 32] {
+        &self.tracked.payment_hash
+    }
+
+    /// Returns the BOLT11 payment request, if the invoice was created by this manager.
+    pub fn payment_request(&self) -> &str {
+        &self.tracked.payment_request
+    }
+
+    /// Returns the preimage, if it is known.
+    pub fn preimage(&self) -> Option<&Preimage> {
+        self.tracked.preimage.as_ref()
+    }
+
+    /// Returns `true` for hold invoices.
+    pub fn is_hold(&self) -> bool {
+        self.tracked.hold
+    }
+
+    /// Returns the latest known state.
+    pub fn state(&self) -> InvoiceState {
+        self.tracked.state()
+    }
+
+    /// Waits until the invoice reaches `state`, or a final state it cannot leave.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The state to wait for.
+    /// * `timeout` - How long to wait.
+    ///
+    /// # Returns
+    ///
+    /// The state the invoice ended up in, or an error if it did not change in time.
+    pub fn wait_for(&self, state: InvoiceState, timeout: Duration) -> Result<InvoiceState> {
+        let current = self
+            .tracked
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (current, _) = self
+            .tracked
+            .changed
+            .wait_timeout_while(current, timeout, |current| {
+                *current != state && !current.is_final()
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        if *current == state || current.is_final() {
+            Ok(*current)
+        } else {
+            Err(anyhow::anyhow!(
+                "Invoice {} is still {:?}",
+                hex::encode(self.tracked.payment_hash),
+                *current
+            ))
+        }
+    }
+}
+
+struct Inner {
+    invoices: Mutex<HashMap<[u8; 32], Arc<Tracked>>>,
+    block_height: AtomicU32,
+    block_watch: Mutex<Option<SubscriptionId>>,
+}
+
+/// Creates invoices and follows them through their states.
+///
+/// Each invoice is followed with `invoicesSubscribeSingleInvoice` until it is settled or
+/// canceled. Hold invoices can be canceled automatically shortly before their accepted
+/// HTLCs expire, see `auto_cancel`.
+#[derive(Clone)]
+pub struct InvoiceManager {
+    client: LndClient,
+    auto_cancel: Option<u32>,
+    inner: Arc<Inner>,
+}
+
+impl InvoiceManager {
+    /// Creates an invoice manager.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            auto_cancel: None,
+            inner: Arc::new(Inner {
+                invoices: Mutex::new(HashMap::new()),
+                block_height: AtomicU32::new(0),
+                block_watch: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Cancels accepted hold invoices once the chain gets within `margin_blocks` of the
+    /// expiry of their earliest HTLC, so the channel is not force closed.
+    pub fn auto_cancel(mut self, margin_blocks: u32) -> Self {
+        self.auto_cancel = Some(margin_blocks);
+        self
+    }
+
+    /// Creates a regular invoice, which lnd settles as soon as it is paid.
+    pub fn create_invoice(&self, spec: &InvoiceSpec) -> Result<InvoiceHandle> {
+        let preimage = Preimage::generate()?;
+        let response: lnrpc::AddInvoiceResponse = self.client.call_lnd_method(
+            lnrpc::Invoice {
+                memo: spec.memo.clone(),
+                r_preimage: preimage.as_bytes().to_vec(),
+                value_msat: spec.value_msat,
+                expiry: spec.expiry.as_secs() as i64,
+                cltv_expiry: spec.cltv_expiry,
+                private: spec.private,
+                ..Default::default()
+            },
+            addInvoice,
+        )?;
+        self.follow(
+            preimage.payment_hash(),
+            response.payment_request,
+            Some(preimage),
+            false,
+            None,
+        )
+    }
+
+    /// Creates a hold invoice, which stays accepted until it is settled or canceled.
+    ///
+    /// # Arguments
+    ///
+    /// * `spec` - The invoice parameters.
+    /// * `preimage` - The preimage to lock the invoice with, or `None` to generate one.
+    pub fn create_hold_invoice(
+        &self,
+        spec: &InvoiceSpec,
+        preimage: Option<Preimage>,
+    ) -> Result<InvoiceHandle> {
+        let preimage = match preimage {
+            Some(preimage) => preimage,
+            None => Preimage::generate()?,
+        };
+        let payment_hash = preimage.payment_hash();
+        let response: invoicesrpc::AddHoldInvoiceResp = self.client.call_lnd_method(
+            invoicesrpc::AddHoldInvoiceRequest {
+                memo: spec.memo.clone(),
+                hash: payment_hash.to_vec(),
+                value_msat: spec.value_msat,
+                expiry: spec.expiry.as_secs() as i64,
+                cltv_expiry: spec.cltv_expiry,
+                private: spec.private,
+                ..Default::default()
+            },
+            invoicesAddHoldInvoice,
+        )?;
+        if self.auto_cancel.is_some() {
+            self.watch_blocks()?;
+        }
+        self.follow(
+            payment_hash,
+            response.payment_request,
+            Some(preimage),
+            true,
+            None,
+        )
+    }
+
+    /// Follows an invoice that was created elsewhere, e.g. before a restart.
+    pub fn track(&self, payment_hash: [u8; 32]) -> Result<InvoiceHandle> {
+        let invoice = self.lookup(&payment_hash)?;
+        // lnd only learns the preimage of a hold invoice when it is settled.
+        let hold = invoice.r_preimage.is_empty();
+        if hold && self.auto_cancel.is_some() {
+            self.watch_blocks()?;
+        }
+        self.follow(
+            payment_hash,
+            invoice.payment_request.clone(),
+            None,
+            hold,
+            Some(&invoice),
+        )
+    }
+
+    /// Looks up an invoice by payment hash.
+    pub fn lookup(&self, payment_hash: &[u8; 32]) -> Result<lnrpc::Invoice> {
+        self.client.call_lnd_method(
+            invoicesrpc::LookupInvoiceMsg {
+                invoice_ref: Some(InvoiceRef::PaymentHash(payment_hash.to_vec())),
+                ..Default::default()
+            },
+            invoicesLookupInvoiceV2,
+        )
+    }
+
+    /// Settles an accepted hold invoice with its preimage.
+    pub fn settle(&self, invoice: &InvoiceHandle) -> Result<()> {
+        let preimage = invoice
+            .preimage()
+            .context("The preimage of this invoice is not known")?;
+        let _: invoicesrpc::SettleInvoiceResp = self.client.call_lnd_method(
+            invoicesrpc::SettleInvoiceMsg {
+                preimage: preimage.as_bytes().to_vec(),
+            },
+            invoicesSettleInvoice,
+        )?;
+        invoice.tracked.transition(InvoiceState::Settled);
+        Ok(())
+    }
+
+    /// Cancels an open or accepted invoice.
+    pub fn cancel(&self, invoice: &InvoiceHandle) -> Result<()> {
+        cancel(&self.client, &invoice.tracked)
+    }
+
+    fn follow(
+        &self,
+        payment_hash: [u8; 32],
+        payment_request: String,
+        preimage: Option<Preimage>,
+        hold: bool,
+        current: Option<&lnrpc::Invoice>,
+    ) -> Result<InvoiceHandle> {
+        let tracked = Arc::new(Tracked {
+            payment_hash,
+            payment_request,
+            preimage,
+            hold,
+            state: Mutex::new(InvoiceState::Open),
+            changed: Condvar::new(),
+            expiry_height: Mutex::new(None),
+            cancel_requested: AtomicBool::new(false),
+            subscription: Mutex::new(None),
+        });
+        // A tracked invoice may already be past `Open`.
+        if let Some(invoice) = current {
+            tracked.apply(invoice);
+        }
+        self.inner
+            .invoices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(payment_hash, tracked.clone());
+
+        let manager = self.weak();
+        let stream = tracked.clone();
+        let id = self
+            .client
+            .subscribe_events::<lnrpc::Invoice, invoicesrpc::SubscribeSingleInvoiceRequest>(
+                invoicesSubscribeSingleInvoice,
+            )
+            .on_event(move |event| {
+                if let (Ok(invoice), Some(manager)) = (event, manager()) {
+                    stream.apply(&invoice);
+                    manager.check_expiry(&stream);
+                    manager.release(&stream);
+                }
+            })
+            .with_request(invoicesrpc::SubscribeSingleInvoiceRequest {
+                r_hash: payment_hash.to_vec(),
+            })
+            .subscribe()?;
+        *tracked
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(id);
+        // The invoice may have reached a final state before the id was known.
+        self.release(&tracked);
+
+        Ok(InvoiceHandle { tracked })
+    }
+
+    /// Stops following an invoice once it was settled or canceled.
+    fn release(&self, tracked: &Tracked) {
+        if !tracked.state().is_final() {
+            return;
+        }
+        let subscription = tracked
+            .subscription
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(id) = subscription {
+            self.client.unsubscribe(id);
+            self.inner
+                .invoices
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&tracked.payment_hash);
+        }
+    }
+
+    /// Follows the chain tip, once, to cancel hold invoices before they expire.
+    fn watch_blocks(&self) -> Result<()> {
+        let mut block_watch = self
+            .inner
+            .block_watch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if block_watch.is_some() {
+            return Ok(());
+        }
+
+        let manager = self.weak();
+        let id = self
+            .client
+            .subscribe_events::<chainrpc::BlockEpoch, chainrpc::BlockEpoch>(
+                chainNotifierRegisterBlockEpochNtfn,
+            )
+            .on_event(move |event| {
+                if let (Ok(block), Some(manager)) = (event, manager()) {
+                    manager
+                        .inner
+                        .block_height
+                        .fetch_max(block.height, Ordering::AcqRel);
+                    let invoices: Vec<_> = manager
+                        .inner
+                        .invoices
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .values()
+                        .cloned()
+                        .collect();
+                    for invoice in invoices {
+                        manager.check_expiry(&invoice);
+                        manager.release(&invoice);
+                    }
+                }
+            })
+            .with_request(chainrpc::BlockEpoch::default())
+            .subscribe()?;
+        *block_watch = Some(id);
+        Ok(())
+    }
+
+    /// Returns a function that recreates the manager while the client is alive, so
+    /// stream callbacks do not keep the client from dropping.
+    fn weak(&self) -> impl Fn() -> Option<InvoiceManager> + Send + Sync + 'static {
+        let client = self.client.downgrade();
+        let auto_cancel = self.auto_cancel;
+        let inner = self.inner.clone();
+        move || {
+            client.upgrade().map(|client| InvoiceManager {
+                client,
+                auto_cancel,
+                inner: inner.clone(),
+            })
+        }
+    }
+
+    /// Cancels an accepted hold invoice that is about to expire.
+    fn check_expiry(&self, tracked: &Tracked) {
+        let Some(margin) = self.auto_cancel else {
+            return;
+        };
+        if !tracked.hold || tracked.state() != InvoiceState::Accepted {
+            return;
+        }
+        let height = self.inner.block_height.load(Ordering::Acquire);
+        let expiry_height = *tracked
+            .expiry_height
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(expiry_height) = expiry_height else {
+            return;
+        };
+        if height == 0 || height.saturating_add(margin) < expiry_height {
+            return;
+        }
+        if !tracked.cancel_requested.swap(true, Ordering::AcqRel)
+            && cancel(&self.client, tracked).is_err()
+        {
+            // Try again on the next block.
+            tracked.cancel_requested.store(false, Ordering::Release);
+        }
+    }
+}
+
+fn cancel(client: &LndClient, tracked: &Tracked) -> Result<()> {
+    let _: invoicesrpc::CancelInvoiceResp = client.call_lnd_method(
+        invoicesrpc::CancelInvoiceMsg {
+            payment_hash: tracked.payment_hash.to_vec(),
+        },
+        invoicesCancelInvoice,
+    )?;
+    tracked.transition(InvoiceState::Canceled);
+    Ok(())
+}
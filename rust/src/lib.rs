@@ -3,14 +3,97 @@
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
+mod backups;
 mod bidi_stream;
+mod callback_panic;
+mod chain_notifier;
+mod channels;
+mod dispatcher;
+mod error;
+mod event_bus;
 mod event_subscription;
+mod export;
+mod fee_bumper;
+mod fee_policy;
+mod forwarding;
+mod invoice_feed;
+mod invoices;
 mod lnd_client;
+mod macaroons;
+mod mission_control;
+mod neutrino;
+mod onchain;
+mod pagination;
+mod payments;
+mod prober;
+mod psbt_funding;
+mod rebalancer;
+mod recording;
+mod registry;
+mod resubscribe;
+mod shutdown;
+mod watchtower;
 
+pub use backups::{
+    multi_backup_snapshot, BackupManager, BackupSink, CallbackBackupSink, FileBackupSink,
+};
 pub use bidi_stream::BidiStreamBuilder;
+pub use callback_panic::{clear_error_hook, set_error_hook, CallbackPanic, PanicPolicy};
+pub use chain_notifier::{
+    BlockEvent, BlockWatcher, ChainNotifier, ChainWatch, Confirmation, Spend, WatchState,
+};
+pub use channels::{parse_channel_point, ChannelHandle, ChannelManager, ChannelState};
+pub use dispatcher::{Dispatcher, DispatcherMetrics, Task};
+pub use error::ClientError;
+pub use event_bus::{EventBus, EventFilter, EventSource, ListenerId, NodeEvent, TypedEvent};
 pub use event_subscription::EventSubscriptionBuilder;
+pub use export::{to_csv, to_json, ExportRecord, Field};
+pub use fee_bumper::{FeeBumpEvent, FeeBumpPolicy, FeeBumper};
+pub use fee_policy::{
+    AppliedPolicy, ChannelPolicy, ChannelStats, FeePolicyConfig, FeePolicyEngine, PolicyRule,
+};
+pub use forwarding::{
+    fees_by_channel, volume_by_bucket, ChannelEarnings, FailedForwardCounter, FailedForwards,
+    ForwardingAnalytics, ForwardingHistory, PeerEarnings, VolumeBucket,
+};
+pub use invoice_feed::{
+    FileIndexStore, IndexStore, InvoiceEvent, InvoiceFeed, InvoiceIndices, MemoryIndexStore,
+};
+pub use invoices::{InvoiceHandle, InvoiceManager, InvoiceSpec, InvoiceState, Preimage};
 pub use lnd_client::LndClient;
 pub use lnd_grpc_rust;
+pub use macaroons::{
+    action, add_first_party_caveat, entity, Macaroons, Permission, PermissionSet,
+    PAYMENT_LIMIT_CAVEAT,
+};
+pub use mission_control::{
+    decode_pairs, encode_pairs, AprioriEstimator, BimodalEstimator, Estimator, ImportOptions,
+    ImportSummary, MissionControl, MissionControlSettings, PairRecord,
+    MISSION_CONTROL_FILE_VERSION,
+};
+pub use neutrino::{NeutrinoConfig, NeutrinoEvent, NeutrinoManager, SyncProgress};
+pub use onchain::{OnchainWallet, SendOutput, SendRequest, WalletBalance};
+pub use pagination::{ClosedChannelList, InvoiceList, PaymentList, TransactionList};
+pub use payments::{
+    FailureReason, PaymentHandle, PaymentLimits, PaymentManager, PaymentPolicy, PaymentStatus,
+};
+pub use prober::{FeeEstimate, ProbeOutcome, Prober};
+pub use psbt_funding::{FeePreference, FundingOutput, PsbtChannelFunding, PsbtFunding};
+pub use rebalancer::{
+    AttemptOutcome, RebalanceAttempt, RebalanceReport, RebalanceRequest, Rebalancer, RouteSource,
+};
+pub use recording::{EntryKind, Recorder, Replay, Session, SessionEntry};
+pub use registry::SubscriptionId;
+pub use resubscribe::{ResubscribePolicy, StreamLifecycle};
+pub use shutdown::ShutdownGuard;
+pub use watchtower::{
+    CoverageStats, HealthReport, SessionInfo, TowerInfo, TowerIssue, TowerPolicy, TowerPolicyInfo,
+    TowerUri, WatchtowerClient,
+};
 
+// The upstream test file keeps its tests in an inner `tests` module and fills some
+// messages with `..Default::default()` even when every field is set, so these lints
+// are allowed there rather than rewriting the existing tests.
 #[cfg(test)]
+#[allow(clippy::module_inception, clippy::needless_update)]
 mod tests;
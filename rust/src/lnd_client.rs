@@ -1,30 +1,128 @@
 use crate::bidi_stream::BidiStreamBuilder;
+use crate::callback_panic::{guard, PanicPolicy};
+use crate::dispatcher::Dispatcher;
+use crate::error::ClientError;
 use crate::event_subscription::EventSubscriptionBuilder;
-use crate::{start, CCallback, CRecvStream, SendStreamC, StopStreamC};
+use crate::recording::{method_name, EntryKind, RecordedStream, Recorder, Replay};
+use crate::registry::{Registry, SubscriptionId};
+use crate::shutdown::ShutdownGuard;
+use crate::{start, stopDaemon, subscribeState, CCallback, CRecvStream, SendStreamC, StopStreamC};
 use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
 use lnd_grpc_rust::prost::Message;
-use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
-use std::sync::{LazyLock, Once};
-use std::time::Duration;
+use std::sync::Once;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 static INIT: Once = Once::new();
 static mut CALLBACK: Option<CCallback> = None;
-type CallbackFn = Box<dyn Fn(Vec<u8>) + Send + Sync>;
+pub(crate) type CallbackFn = Box<dyn Fn(Result<Vec<u8>, String>) + Send + Sync>;
 
-static GLOBAL_CALLBACKS: LazyLock<Mutex<HashMap<usize, CallbackFn>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
-static NEXT_ID: LazyLock<Mutex<usize>> = LazyLock::new(|| Mutex::new(0));
+type OnRequest<Req> = Box<dyn Fn(Result<Req, String>) + Send + Sync>;
+type GetResponse<Req, Resp> = Box<dyn Fn(Option<Req>) -> Option<Resp> + Send + Sync>;
 
-type OnRequest<Req> = Arc<Mutex<dyn Fn(Result<Req, String>) + Send + Sync>>;
-type GetResponse<Req, Resp> = Arc<Mutex<dyn Fn(Option<Req>) -> Option<Resp> + Send + Sync>>;
+/// Where an `LndClient` sends its traffic.
+enum Backend {
+    Ffi,
+    Record(Arc<Recorder>),
+    Replay(Arc<Replay>),
+}
+
+/// Per-stream settings chosen through the stream builders.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StreamOptions {
+    pub(crate) panic_policy: PanicPolicy,
+}
+
+/// A live server stream subscription.
+struct Subscription {
+    callback: CallbackFn,
+    options: StreamOptions,
+}
+
+/// A live bidirectional stream, keyed by its handle.
+struct BidiStream {
+    close: Box<dyn Fn() + Send + Sync>,
+}
+
+/// State shared by all clones of an `LndClient`.
+struct ClientState {
+    backend: Backend,
+    running: AtomicBool,
+    subscriptions: Registry<Subscription>,
+    bidi_streams: Registry<BidiStream>,
+    dispatcher: RwLock<Option<Dispatcher>>,
+}
+
+impl ClientState {
+    fn dispatcher(&self) -> Option<Dispatcher> {
+        self.dispatcher
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// The context handed to lnd for a server stream.
+///
+/// lnd may invoke a stream's callbacks at any time, so this is intentionally leaked.
+/// It only holds a weak reference to the client, and events for clients that have been
+/// dropped or subscriptions that have been removed are discarded.
+struct StreamContext {
+    state: Weak<ClientState>,
+    id: u64,
+    method: String,
+    dispatcher: Option<Dispatcher>,
+    recording: Option<(Arc<Recorder>, u64)>,
+}
+
+impl StreamContext {
+    fn dispatch(&'static self, message: Result<Vec<u8>, String>) {
+        if let Some((recorder, id)) = &self.recording {
+            recorder.record_message(
+                *id,
+                &self.method,
+                message.as_deref().map_err(String::as_str),
+            );
+        }
+        match &self.dispatcher {
+            Some(dispatcher) => dispatcher.dispatch(
+                self as *const _ as usize,
+                &self.method,
+                Box::new(move || self.deliver(message)),
+            ),
+            None => self.deliver(message),
+        }
+    }
+
+    fn deliver(&self, message: Result<Vec<u8>, String>) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        // The registry lock is released before the callback runs, so a slow handler only
+        // holds up its own stream.
+        if let Some(subscription) = state.subscriptions.get(self.id) {
+            let delivered = guard(&self.method, || (subscription.callback)(message));
+            if delivered.is_none() && subscription.options.panic_policy == PanicPolicy::Close {
+                state.subscriptions.remove(self.id);
+            }
+        }
+    }
+}
 
 /// The main client for interacting with the LND node.
-pub struct LndClient;
+///
+/// Clones share the same subscriptions and backend.
+#[derive(Clone)]
+pub struct LndClient {
+    state: Arc<ClientState>,
+}
 
 impl Default for LndClient {
     fn default() -> Self {
@@ -35,7 +133,142 @@ impl Default for LndClient {
 impl LndClient {
     /// Creates a new instance of the LndClient.
     pub fn new() -> Self {
-        LndClient
+        Self::with_backend(Backend::Ffi)
+    }
+
+    /// Creates a client that talks to lnd and writes all traffic to `recorder`.
+    pub fn with_recorder(recorder: Recorder) -> Self {
+        Self::with_backend(Backend::Record(Arc::new(recorder)))
+    }
+
+    /// Creates a client that never calls lnd and serves responses from `replay` instead.
+    pub fn with_replay(replay: Replay) -> Self {
+        Self::with_backend(Backend::Replay(Arc::new(replay)))
+    }
+
+    fn with_backend(backend: Backend) -> Self {
+        LndClient {
+            state: Arc::new(ClientState {
+                backend,
+                running: AtomicBool::new(true),
+                subscriptions: Registry::new(),
+                bidi_streams: Registry::new(),
+                dispatcher: RwLock::new(None),
+            }),
+        }
+    }
+
+    pub(crate) fn ensure_running(&self) -> Result<()> {
+        if self.state.running.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(ClientError::NotRunning.into())
+        }
+    }
+
+    /// Returns `false` once `shutdown` has been called.
+    pub fn is_running(&self) -> bool {
+        self.state.running.load(Ordering::Acquire)
+    }
+
+    /// Stops lnd and closes everything opened through this client.
+    ///
+    /// All subscriptions are removed and all bidirectional streams are closed, then lnd is
+    /// asked to stop and this waits until `subscribeState` reports that it is down. From
+    /// then on, every call made through this client or its clones fails with
+    /// `ClientError::NotRunning`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for lnd to stop.
+    ///
+    /// # Returns
+    ///
+    /// An error if lnd refused to stop or did not stop within `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if !self.state.running.swap(false, Ordering::AcqRel) {
+            return Err(ClientError::NotRunning.into());
+        }
+        let deadline = Instant::now() + timeout;
+
+        self.state.subscriptions.drain();
+        for stream in self.state.bidi_streams.drain() {
+            (stream.close)();
+        }
+
+        // The state stream ends with an error once lnd's RPC server is gone.
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let watcher = self
+            .subscribe_inner(
+                subscribeState,
+                move |event: Result<lnrpc::SubscribeStateResponse, String>| {
+                    if event.is_err() {
+                        let _ = tx.lock().unwrap_or_else(PoisonError::into_inner).send(());
+                    }
+                },
+                lnrpc::SubscribeStateRequest {},
+                StreamOptions::default(),
+            )
+            .ok();
+
+        let stopped = self.call_inner::<_, lnrpc::StopResponse>(lnrpc::StopRequest {}, stopDaemon);
+        let down = match watcher {
+            Some(id) => {
+                let down = rx
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .is_ok();
+                self.state.subscriptions.remove(id.0);
+                down
+            }
+            // Without a state stream there is nothing to wait for.
+            None => true,
+        };
+
+        stopped.context("Failed to stop lnd")?;
+        if down {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Timed out waiting for lnd to stop"))
+        }
+    }
+
+    /// Returns a guard that shuts down this client when it goes out of scope.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long the guard waits for lnd to stop.
+    pub fn shutdown_guard(&self, timeout: Duration) -> ShutdownGuard {
+        ShutdownGuard::new(self.clone(), timeout)
+    }
+
+    /// Removes a subscription, so its callback is no longer invoked.
+    ///
+    /// lnd's server streams cannot be cancelled through the FFI, so the stream itself
+    /// stays open until lnd ends it and any further events are discarded.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the subscription was still active.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.state.subscriptions.remove(id.0).is_some()
+    }
+
+    /// Sets the dispatcher that runs the callbacks of streams opened from now on.
+    ///
+    /// Without a dispatcher, callbacks run directly on the thread lnd delivered the
+    /// message on, and a slow callback holds up lnd.
+    pub fn set_dispatcher(&self, dispatcher: Option<Dispatcher>) {
+        *self
+            .state
+            .dispatcher
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = dispatcher;
+    }
+
+    /// Returns the number of active subscriptions made through this client.
+    pub fn active_subscriptions(&self) -> usize {
+        self.state.subscriptions.len()
     }
 
     /// Initiates a bidirectional stream with the LND node.
@@ -50,7 +283,7 @@ impl LndClient {
     pub fn bidi_stream<Req, Resp>(
         &self,
         stream_func: unsafe extern "C" fn(CRecvStream) -> usize,
-    ) -> BidiStreamBuilder<Req, Resp>
+    ) -> BidiStreamBuilder<'_, Req, Resp>
     where
         Req: Message + Default + Clone + 'static,
         Resp: Message + Default + 'static,
@@ -70,10 +303,10 @@ impl LndClient {
     pub fn subscribe_events<E, R>(
         &self,
         subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
-    ) -> EventSubscriptionBuilder<E, R>
+    ) -> EventSubscriptionBuilder<'_, E, R>
     where
         E: Message + Default + 'static,
-        R: Message,
+        R: Message + 'static,
     {
         EventSubscriptionBuilder::new(self, subscribe_func)
     }
@@ -88,6 +321,7 @@ impl LndClient {
     ///
     /// A `Result` indicating success or failure.
     pub fn start(&self, args: &str) -> Result<()> {
+        self.ensure_running()?;
         let c_args = CString::new(args).context("Failed to create CString from args")?;
 
         extern "C" fn response_callback(
@@ -134,6 +368,13 @@ impl LndClient {
     ///
     /// A `Result` indicating success or failure.
     pub fn stop_stream(&self, stream_ptr: usize) -> Result<()> {
+        let stream = self.state.bidi_streams.remove(stream_ptr as u64);
+        if let Backend::Replay(_) = self.state.backend {
+            if let Some(stream) = stream {
+                (stream.close)();
+            }
+            return Ok(());
+        }
         let result = unsafe { StopStreamC(stream_ptr) };
         if result == 0 {
             Ok(())
@@ -160,33 +401,91 @@ impl LndClient {
         request: Req,
         lnd_func: unsafe extern "C" fn(*mut c_char, c_int, CCallback) -> (),
     ) -> Result<Resp>
+    where
+        Req: Message,
+        Resp: Message + Default,
+    {
+        self.ensure_running()?;
+        self.call_inner(request, lnd_func)
+    }
+
+    fn call_inner<Req, Resp>(
+        &self,
+        request: Req,
+        lnd_func: unsafe extern "C" fn(*mut c_char, c_int, CCallback) -> (),
+    ) -> Result<Resp>
     where
         Req: Message,
         Resp: Message + Default,
     {
         let encoded = request.encode_to_vec();
+        let result = match &self.state.backend {
+            Backend::Ffi => Self::call_ffi(encoded, lnd_func),
+            Backend::Record(recorder) => {
+                let method = method_name(lnd_func as usize);
+                let id = recorder.next_id();
+                recorder.record(id, EntryKind::Call, &method, &encoded);
+                let result = Self::call_ffi(encoded, lnd_func);
+                match &result {
+                    Ok(bytes) => recorder.record(id, EntryKind::Response, &method, bytes),
+                    Err(e) => {
+                        recorder.record(id, EntryKind::Error, &method, e.to_string().as_bytes())
+                    }
+                }
+                result
+            }
+            Backend::Replay(replay) => replay.call(&method_name(lnd_func as usize), &encoded),
+        };
+
+        result.and_then(|bytes| {
+            Resp::decode(bytes.as_slice())
+                .map_err(|e| anyhow::anyhow!("Failed to decode response: {}", e))
+        })
+    }
+
+    fn call_ffi(
+        encoded: Vec<u8>,
+        lnd_func: unsafe extern "C" fn(*mut c_char, c_int, CCallback) -> (),
+    ) -> Result<Vec<u8>> {
+        struct CallContext {
+            tx: Sender<Result<Vec<u8>>>,
+            method: String,
+        }
+
         let c_args =
             CString::new(encoded).context("Failed to create CString from encoded request")?;
         let (tx, rx) = channel::<Result<Vec<u8>>>();
 
+        // lnd invokes exactly one of the callbacks exactly once, which takes back ownership
+        // of the context. It outlives this function if the call times out.
+        let context_ptr = Box::into_raw(Box::new(CallContext {
+            tx,
+            method: method_name(lnd_func as usize),
+        }));
+
         extern "C" fn response_callback(context: *mut c_void, data: *const c_char, length: c_int) {
-            let tx = unsafe { &*(context as *const Sender<Result<Vec<u8>>>) };
-            let response =
-                unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
-            tx.send(Ok(response.to_vec())).unwrap();
+            let context = unsafe { Box::from_raw(context as *mut CallContext) };
+            guard(&context.method, || {
+                let response =
+                    unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
+                // The receiver is gone if the call already timed out.
+                let _ = context.tx.send(Ok(response.to_vec()));
+            });
         }
 
         extern "C" fn error_callback(context: *mut c_void, err: *const c_char) {
-            let tx = unsafe { &*(context as *const Sender<Result<Vec<u8>>>) };
-            let error = unsafe { CStr::from_ptr(err).to_str().unwrap_or("").to_string() };
-            tx.send(Err(anyhow::anyhow!(error))).unwrap();
+            let context = unsafe { Box::from_raw(context as *mut CallContext) };
+            guard(&context.method, || {
+                let error = unsafe { CStr::from_ptr(err).to_str().unwrap_or("").to_string() };
+                let _ = context.tx.send(Err(anyhow::anyhow!(error)));
+            });
         }
 
         let callback = CCallback {
             onResponse: Some(response_callback),
             onError: Some(error_callback),
-            responseContext: &tx as *const _ as *mut c_void,
-            errorContext: &tx as *const _ as *mut c_void,
+            responseContext: context_ptr as *mut c_void,
+            errorContext: context_ptr as *mut c_void,
         };
 
         unsafe {
@@ -198,10 +497,6 @@ impl LndClient {
 
         rx.recv_timeout(Duration::from_secs(30))
             .context("Timeout waiting for response")?
-            .and_then(|bytes| {
-                Resp::decode(bytes.as_slice())
-                    .map_err(|e| anyhow::anyhow!("Failed to decode response: {}", e))
-            })
     }
 
     pub(crate) fn setup_bidirectional_stream<Req, Resp, F, G>(
@@ -209,6 +504,7 @@ impl LndClient {
         stream_func: unsafe extern "C" fn(CRecvStream) -> usize,
         on_request: F,
         get_response: G,
+        options: StreamOptions,
     ) -> Result<usize>
     where
         Req: Message + Default + Clone + 'static,
@@ -216,69 +512,195 @@ impl LndClient {
         F: Fn(Result<Req, String>) + Send + Sync + 'static,
         G: Fn(Option<Req>) -> Option<Resp> + Send + Sync + 'static,
     {
+        self.ensure_running()?;
+
         struct Context<Req, Resp> {
             on_request: OnRequest<Req>,
             get_response: GetResponse<Req, Resp>,
             send_stream: Mutex<Option<usize>>,
             last_request: Mutex<Option<Req>>,
+            method: String,
+            recording: Option<(Arc<Recorder>, u64)>,
+            options: StreamOptions,
+            closed: AtomicBool,
+            dispatcher: Option<Dispatcher>,
         }
 
+        impl<Req, Resp> Context<Req, Resp>
+        where
+            Req: Message + Default + Clone + 'static,
+            Resp: Message + Default + 'static,
+        {
+            fn handle_message(&'static self, message: Result<&[u8], String>) {
+                if let Some((recorder, id)) = &self.recording {
+                    recorder.record_message(
+                        *id,
+                        &self.method,
+                        message.as_ref().copied().map_err(String::as_str),
+                    );
+                }
+
+                match &self.dispatcher {
+                    Some(dispatcher) => {
+                        let message = message.map(<[u8]>::to_vec);
+                        dispatcher.dispatch(
+                            self as *const _ as usize,
+                            &self.method,
+                            Box::new(move || match message {
+                                Ok(data) => self.run(Ok(&data)),
+                                Err(error) => self.run(Err(error)),
+                            }),
+                        );
+                    }
+                    None => self.run(message),
+                }
+            }
+
+            fn run(&self, message: Result<&[u8], String>) {
+                if self.closed.load(Ordering::Acquire) {
+                    return;
+                }
+                let handled = guard(&self.method, || self.dispatch(message));
+                if handled.is_none() && self.options.panic_policy == PanicPolicy::Close {
+                    self.close();
+                }
+            }
+
+            fn close(&self) {
+                if self.closed.swap(true, Ordering::AcqRel) {
+                    return;
+                }
+                let send_stream = *self
+                    .send_stream
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if let Some(send_stream) = send_stream {
+                    unsafe { StopStreamC(send_stream) };
+                }
+            }
+
+            fn dispatch(&self, message: Result<&[u8], String>) {
+                let request_data = match message {
+                    Ok(data) => data,
+                    Err(error) => return (self.on_request)(Err(error)),
+                };
+
+                match Req::decode(request_data) {
+                    Ok(request) => {
+                        (self.on_request)(Ok(request.clone()));
+                        *self
+                            .last_request
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner) = Some(request.clone());
+
+                        if let Some(response) = (self.get_response)(Some(request)) {
+                            let mut encoded_response = response.encode_to_vec();
+                            if let Some((recorder, id)) = &self.recording {
+                                recorder.record(
+                                    *id,
+                                    EntryKind::Send,
+                                    &self.method,
+                                    &encoded_response,
+                                );
+                            }
+                            let send_stream = *self
+                                .send_stream
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner);
+                            if let Some(send_stream) = send_stream {
+                                // lnd copies the message, and its length is passed explicitly,
+                                // so it may contain NUL bytes.
+                                unsafe {
+                                    SendStreamC(
+                                        send_stream,
+                                        encoded_response.as_mut_ptr() as *mut c_char,
+                                        encoded_response.len() as c_int,
+                                    )
+                                };
+                            }
+                        }
+                    }
+                    Err(e) => (self.on_request)(Err(format!("Failed to decode request: {}", e))),
+                }
+            }
+        }
+
+        let method = method_name(stream_func as usize);
+        let recording = match &self.state.backend {
+            Backend::Record(recorder) => {
+                let id = recorder.next_id();
+                recorder.record(id, EntryKind::BidiOpen, &method, &[]);
+                Some((recorder.clone(), id))
+            }
+            _ => None,
+        };
+
         let context = Box::new(Context {
-            on_request: Arc::new(Mutex::new(on_request)),
-            get_response: Arc::new(Mutex::new(get_response)),
+            on_request: Box::new(on_request),
+            get_response: Box::new(get_response),
             send_stream: Mutex::new(None),
             last_request: Mutex::new(None),
+            method,
+            recording,
+            options,
+            closed: AtomicBool::new(false),
+            dispatcher: self.state.dispatcher(),
         });
+
+        if let Backend::Replay(replay) = &self.state.backend {
+            let stream = replay.open_bidi(&context.method)?;
+            // Responses produced during replay are dropped, as there is no lnd to send them to.
+            let context: &'static Context<Req, Resp> = Box::leak(context);
+            spawn_replay(stream, replay.is_realtime(), move |message| {
+                context.handle_message(message.as_deref().map_err(|e| e.clone()))
+            });
+            let handle = context as *const _ as usize;
+            self.state.bidi_streams.insert(
+                handle as u64,
+                BidiStream {
+                    close: Box::new(move || context.close()),
+                },
+            );
+            return Ok(handle);
+        }
+
         let context_ptr = Box::into_raw(context);
 
-        extern "C" fn request_callback<Req: Message + Default + Clone, Resp: Message + Default>(
+        extern "C" fn request_callback<
+            Req: Message + Default + Clone + 'static,
+            Resp: Message + Default + 'static,
+        >(
             context: *mut c_void,
             data: *const c_char,
             length: c_int,
         ) {
-            let context = unsafe { &*(context as *const Context<Req, Resp>) };
-            let request_data =
-                unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
-
-            match Req::decode(request_data) {
-                Ok(request) => {
-                    context.on_request.lock().unwrap()(Ok(request.clone()));
-                    *context.last_request.lock().unwrap() = Some(request.clone());
-
-                    if let Some(response) = context.get_response.lock().unwrap()(Some(request)) {
-                        let encoded_response = response.encode_to_vec();
-                        if let Some(send_stream) = *context.send_stream.lock().unwrap() {
-                            let c_data =
-                                CString::new(encoded_response).expect("CString::new failed");
-                            unsafe {
-                                SendStreamC(
-                                    send_stream,
-                                    c_data.clone().into_raw(),
-                                    c_data.as_bytes().len() as c_int,
-                                )
-                            };
-                        }
-                    }
-                }
-                Err(e) => context.on_request.lock().unwrap()(Err(format!(
-                    "Failed to decode request: {}",
-                    e
-                ))),
-            }
+            let context: &'static Context<Req, Resp> =
+                unsafe { &*(context as *const Context<Req, Resp>) };
+            guard(&context.method, || {
+                let request_data =
+                    unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
+                context.handle_message(Ok(request_data));
+            });
         }
 
-        extern "C" fn error_callback<Req: Message + Default + Clone, Resp: Message + Default>(
+        extern "C" fn error_callback<
+            Req: Message + Default + Clone + 'static,
+            Resp: Message + Default + 'static,
+        >(
             context: *mut c_void,
             err: *const c_char,
         ) {
-            let context = unsafe { &*(context as *const Context<Req, Resp>) };
-            let error = unsafe {
-                CStr::from_ptr(err)
-                    .to_str()
-                    .unwrap_or("Unknown error")
-                    .to_string()
-            };
-            context.on_request.lock().unwrap()(Err(error));
+            let context: &'static Context<Req, Resp> =
+                unsafe { &*(context as *const Context<Req, Resp>) };
+            guard(&context.method, || {
+                let error = unsafe {
+                    CStr::from_ptr(err)
+                        .to_str()
+                        .unwrap_or("Unknown error")
+                        .to_string()
+                };
+                context.handle_message(Err(error));
+            });
         }
 
         let recv_stream = CRecvStream {
@@ -296,13 +718,18 @@ impl LndClient {
             };
             Err(anyhow::anyhow!("Failed to create send stream"))
         } else {
-            unsafe {
-                (*context_ptr)
-                    .send_stream
-                    .lock()
-                    .unwrap()
-                    .replace(send_stream);
-            }
+            let context: &'static Context<Req, Resp> = unsafe { &*context_ptr };
+            context
+                .send_stream
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .replace(send_stream);
+            self.state.bidi_streams.insert(
+                send_stream as u64,
+                BidiStream {
+                    close: Box::new(move || context.close()),
+                },
+            );
             Ok(send_stream)
         }
     }
@@ -312,60 +739,175 @@ impl LndClient {
         subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
         callback: F,
         request: R,
-    ) -> Result<()>
+    ) -> Result<SubscriptionId>
     where
         E: Message + Default + 'static,
         F: Fn(Result<E, String>) + Send + Sync + 'static,
         R: Message,
     {
-        let id = {
-            let mut id = NEXT_ID.lock().unwrap();
-            *id += 1;
-            *id
+        self.subscribe_with_options(subscribe_func, callback, request, StreamOptions::default())
+    }
+
+    pub(crate) fn subscribe_with_options<E, F, R>(
+        &self,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        callback: F,
+        request: R,
+        options: StreamOptions,
+    ) -> Result<SubscriptionId>
+    where
+        E: Message + Default + 'static,
+        F: Fn(Result<E, String>) + Send + Sync + 'static,
+        R: Message,
+    {
+        self.ensure_running()?;
+        self.subscribe_inner(subscribe_func, callback, request, options)
+    }
+
+    fn subscribe_inner<E, F, R>(
+        &self,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        callback: F,
+        request: R,
+        options: StreamOptions,
+    ) -> Result<SubscriptionId>
+    where
+        E: Message + Default + 'static,
+        F: Fn(Result<E, String>) + Send + Sync + 'static,
+        R: Message,
+    {
+        let handler = move |message: Result<Vec<u8>, String>| match message {
+            Ok(data) => match E::decode(data.as_slice()) {
+                Ok(event) => callback(Ok(event)),
+                Err(e) => callback(Err(format!("Failed to decode event: {}", e))),
+            },
+            Err(e) => callback(Err(e)),
         };
 
-        let callback_wrapper = Box::new(move |data: Vec<u8>| match E::decode(data.as_slice()) {
-            Ok(event) => callback(Ok(event)),
-            Err(e) => callback(Err(format!("Failed to decode event: {}", e))),
-        });
+        let id = self.next_subscription_id();
+        self.subscribe_raw(
+            id,
+            subscribe_func,
+            Box::new(handler),
+            request.encode_to_vec(),
+            options,
+        )?;
+        Ok(id)
+    }
 
-        GLOBAL_CALLBACKS
-            .lock()
-            .unwrap()
-            .insert(id, callback_wrapper);
+    pub(crate) fn next_subscription_id(&self) -> SubscriptionId {
+        SubscriptionId(self.state.subscriptions.next_id())
+    }
 
-        extern "C" fn response_callback(context: *mut c_void, data: *const c_char, length: c_int) {
-            let id = context as usize;
-            let response =
-                unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
-            if let Some(callback) = GLOBAL_CALLBACKS.lock().unwrap().get(&id) {
-                callback(response.to_vec());
+    pub(crate) fn is_subscribed(&self, id: SubscriptionId) -> bool {
+        self.state.subscriptions.get(id.0).is_some()
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakLndClient {
+        WeakLndClient(Arc::downgrade(&self.state))
+    }
+
+    /// Registers `callback` under `id`, which receives the undecoded messages, and opens
+    /// the stream.
+    pub(crate) fn subscribe_raw(
+        &self,
+        id: SubscriptionId,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        callback: CallbackFn,
+        encoded: Vec<u8>,
+        options: StreamOptions,
+    ) -> Result<()> {
+        self.state
+            .subscriptions
+            .insert(id.0, Subscription { callback, options });
+        let opened = self.open_stream(id, subscribe_func, encoded);
+        if opened.is_err() {
+            self.state.subscriptions.remove(id.0);
+        }
+        opened
+    }
+
+    /// Opens another stream for an existing subscription, e.g. after the previous one
+    /// ended.
+    pub(crate) fn reopen_stream(
+        &self,
+        id: SubscriptionId,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        encoded: Vec<u8>,
+    ) -> Result<()> {
+        self.ensure_running()?;
+        if self.state.subscriptions.get(id.0).is_none() {
+            anyhow::bail!("Subscription {:?} is no longer active", id);
+        }
+        self.open_stream(id, subscribe_func, encoded)
+    }
+
+    /// Opens a server stream whose messages go to the subscription `id`.
+    fn open_stream(
+        &self,
+        id: SubscriptionId,
+        subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+        encoded: Vec<u8>,
+    ) -> Result<()> {
+        let method = method_name(subscribe_func as usize);
+        let recording = match &self.state.backend {
+            Backend::Record(recorder) => {
+                let recording_id = recorder.next_id();
+                recorder.record(recording_id, EntryKind::Subscribe, &method, &encoded);
+                Some((recorder.clone(), recording_id))
             }
+            Backend::Ffi | Backend::Replay(_) => None,
+        };
+        let context = StreamContext {
+            state: Arc::downgrade(&self.state),
+            id: id.0,
+            method,
+            dispatcher: self.state.dispatcher(),
+            recording,
+        };
+
+        if let Backend::Replay(replay) = &self.state.backend {
+            let stream = replay.subscribe(&context.method)?;
+            let context: &'static StreamContext = Box::leak(Box::new(context));
+            spawn_replay(stream, replay.is_realtime(), move |message| {
+                context.dispatch(message)
+            });
+            return Ok(());
+        }
+
+        let c_args =
+            CString::new(encoded).context("Failed to create CString from encoded request")?;
+        let context_ptr = Box::into_raw(Box::new(context));
+
+        extern "C" fn response_callback(context: *mut c_void, data: *const c_char, length: c_int) {
+            let context: &'static StreamContext = unsafe { &*(context as *const StreamContext) };
+            guard(&context.method, || {
+                let response =
+                    unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) };
+                context.dispatch(Ok(response.to_vec()));
+            });
         }
 
         extern "C" fn error_callback(context: *mut c_void, err: *const c_char) {
-            let id = context as usize;
-            let error = unsafe {
-                CStr::from_ptr(err)
-                    .to_str()
-                    .unwrap_or("Unknown error")
-                    .to_string()
-            };
-            if let Some(callback) = GLOBAL_CALLBACKS.lock().unwrap().get(&id) {
-                callback(error.into_bytes());
-            }
+            let context: &'static StreamContext = unsafe { &*(context as *const StreamContext) };
+            guard(&context.method, || {
+                let error = unsafe {
+                    CStr::from_ptr(err)
+                        .to_str()
+                        .unwrap_or("Unknown error")
+                        .to_string()
+                };
+                context.dispatch(Err(error));
+            });
         }
 
         let recv_stream = CRecvStream {
             onResponse: Some(response_callback),
             onError: Some(error_callback),
-            responseContext: id as *mut c_void,
-            errorContext: id as *mut c_void,
+            responseContext: context_ptr as *mut c_void,
+            errorContext: context_ptr as *mut c_void,
         };
 
-        let encoded = request.encode_to_vec();
-        let c_args =
-            CString::new(encoded).context("Failed to create CString from encoded request")?;
         unsafe {
             let c_args_len = c_args.as_bytes().len() as c_int;
             let c_args_ptr = c_args.into_raw();
@@ -375,3 +917,33 @@ impl LndClient {
         Ok(())
     }
 }
+
+/// A reference to an `LndClient` that does not keep it alive, for callbacks owned by
+/// the client itself.
+#[derive(Clone)]
+pub(crate) struct WeakLndClient(Weak<ClientState>);
+
+impl WeakLndClient {
+    pub(crate) fn upgrade(&self) -> Option<LndClient> {
+        self.0.upgrade().map(|state| LndClient { state })
+    }
+}
+
+/// Delivers the messages of a recorded stream on a background thread, the way lnd
+/// would deliver them from its own threads.
+fn spawn_replay<F>(stream: RecordedStream, realtime: bool, deliver: F)
+where
+    F: Fn(Result<Vec<u8>, String>) + Send + 'static,
+{
+    thread::spawn(move || {
+        let started = Instant::now();
+        for (at, message) in stream {
+            if realtime {
+                if let Some(wait) = at.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+            deliver(message);
+        }
+    });
+}
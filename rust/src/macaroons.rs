@@ -0,0 +1,406 @@
+use crate::{
+    bakeMacaroon, checkMacaroonPermissions, deleteMacaroonID, listMacaroonIDs, listPermissions,
+    LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The entities lnd grants permissions on, as reported by `listPermissions`.
+pub mod entity {
+    /// On-chain wallet funds and transactions.
+    pub const ONCHAIN: &str = "onchain";
+    /// Channels and payments.
+    pub const OFFCHAIN: &str = "offchain";
+    /// Wallet addresses.
+    pub const ADDRESS: &str = "address";
+    /// Signed messages.
+    pub const MESSAGE: &str = "message";
+    /// Peer connections.
+    pub const PEERS: &str = "peers";
+    /// Node information.
+    pub const INFO: &str = "info";
+    /// Invoices.
+    pub const INVOICES: &str = "invoices";
+    /// The signer sub-server.
+    pub const SIGNER: &str = "signer";
+    /// Macaroons themselves.
+    pub const MACAROON: &str = "macaroon";
+    /// A single gRPC method, named by the action.
+    pub const URI: &str = "uri";
+}
+
+/// The actions lnd grants on an entity.
+pub mod action {
+    /// Reading.
+    pub const READ: &str = "read";
+    /// Changing.
+    pub const WRITE: &str = "write";
+    /// Creating, only used with `entity::MACAROON`.
+    pub const GENERATE: &str = "generate";
+}
+
+/// The custom caveat `payments_with_limit` adds, for an RPC middleware to enforce.
+pub const PAYMENT_LIMIT_CAVEAT: &str = "payment-limit";
+
+/// A permission to perform an action on an entity.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Permission {
+    /// The entity, one of the `entity` constants.
+    pub entity: String,
+    /// The action, one of the `action` constants, or a method URI for `entity::URI`.
+    pub action: String,
+}
+
+impl Permission {
+    fn from_lnd(permission: lnrpc::MacaroonPermission) -> Self {
+        Self {
+            entity: permission.entity,
+            action: permission.action,
+        }
+    }
+
+    fn to_lnd(&self) -> lnrpc::MacaroonPermission {
+        lnrpc::MacaroonPermission {
+            entity: self.entity.clone(),
+            action: self.action.clone(),
+        }
+    }
+}
+
+/// A set of permissions to bake a macaroon with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PermissionSet {
+    permissions: BTreeSet<Permission>,
+}
+
+impl PermissionSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a permission to perform `action` on `entity`.
+    pub fn allow(mut self, entity: &str, action: &str) -> Self {
+        self.permissions.insert(Permission {
+            entity: entity.to_string(),
+            action: action.to_string(),
+        });
+        self
+    }
+
+    /// Adds read and write permissions on `entity`.
+    pub fn read_write(self, entity: &str) -> Self {
+        self.allow(entity, action::READ)
+            .allow(entity, action::WRITE)
+    }
+
+    /// Adds a permission to call a single method, e.g. `/lnrpc.Lightning/GetInfo`.
+    pub fn allow_uri(self, method: &str) -> Self {
+        self.allow(entity::URI, method)
+    }
+
+    /// Read access to everything, like lnd's `readonly.macaroon`.
+    pub fn readonly() -> Self {
+        [
+            entity::ONCHAIN,
+            entity::OFFCHAIN,
+            entity::ADDRESS,
+            entity::MESSAGE,
+            entity::PEERS,
+            entity::INFO,
+            entity::INVOICES,
+            entity::SIGNER,
+            entity::MACAROON,
+        ]
+        .into_iter()
+        .fold(Self::new(), |set, entity| set.allow(entity, action::READ))
+    }
+
+    /// Creating and looking up invoices, like lnd's `invoice.macaroon`.
+    pub fn invoice_only() -> Self {
+        Self::new()
+            .read_write(entity::INVOICES)
+            .read_write(entity::ADDRESS)
+            .allow(entity::ONCHAIN, action::READ)
+    }
+
+    /// Paying invoices and following the payments.
+    pub fn payments() -> Self {
+        Self::new()
+            .read_write(entity::OFFCHAIN)
+            .allow(entity::INFO, action::READ)
+            .allow(entity::INVOICES, action::READ)
+    }
+
+    /// Returns the permissions in the set.
+    pub fn iter(&self) -> impl Iterator<Item = &Permission> {
+        self.permissions.iter()
+    }
+
+    /// Returns `true` if the set holds every permission in `other`.
+    pub fn contains_all(&self, other: &PermissionSet) -> bool {
+        other.permissions.is_subset(&self.permissions)
+    }
+
+    fn to_lnd(&self) -> Vec<lnrpc::MacaroonPermission> {
+        self.permissions.iter().map(Permission::to_lnd).collect()
+    }
+}
+
+impl FromIterator<Permission> for PermissionSet {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        Self {
+            permissions: iter.into_iter().collect(),
+        }
+    }
+}
+
+type MethodPermissions = Arc<HashMap<String, PermissionSet>>;
+
+/// Bakes, checks and revokes macaroons.
+#[derive(Clone)]
+pub struct Macaroons {
+    client: LndClient,
+    /// The permissions each method requires, fetched once from `listPermissions`.
+    methods: Arc<Mutex<Option<MethodPermissions>>>,
+}
+
+impl Macaroons {
+    /// Creates a macaroon manager.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            methods: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Bakes a macaroon with the given permissions.
+    ///
+    /// # Arguments
+    ///
+    /// * `permissions` - What the macaroon allows.
+    /// * `root_key_id` - The root key to bake with. Deleting it revokes the macaroon.
+    ///
+    /// # Returns
+    ///
+    /// The hex encoded macaroon.
+    pub fn bake(&self, permissions: &PermissionSet, root_key_id: u64) -> Result<String> {
+        let response: lnrpc::BakeMacaroonResponse = self.client.call_lnd_method(
+            lnrpc::BakeMacaroonRequest {
+                permissions: permissions.to_lnd(),
+                root_key_id,
+                allow_external_permissions: false,
+            },
+            bakeMacaroon,
+        )?;
+        Ok(response.macaroon)
+    }
+
+    /// Bakes a payments macaroon whose payments an RPC middleware should limit to
+    /// `max_amount_msat`.
+    ///
+    /// lnd has no built-in amount caveat, so the limit is added as the custom caveat
+    /// `lnd-custom payment-limit <max_amount_msat>`. lnd rejects calls made with it
+    /// unless a middleware registered for `PAYMENT_LIMIT_CAVEAT` accepts them.
+    pub fn payments_with_limit(&self, max_amount_msat: u64, root_key_id: u64) -> Result<String> {
+        let macaroon = self.bake(&PermissionSet::payments(), root_key_id)?;
+        add_first_party_caveat(
+            &macaroon,
+            &format!("lnd-custom {} {}", PAYMENT_LIMIT_CAVEAT, max_amount_msat),
+        )
+    }
+
+    /// Returns the root key ids in use.
+    pub fn root_key_ids(&self) -> Result<Vec<u64>> {
+        let response: lnrpc::ListMacaroonIDsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListMacaroonIDsRequest {}, listMacaroonIDs)?;
+        Ok(response.root_key_ids)
+    }
+
+    /// Revokes every macaroon baked with a root key id.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the id was not in use.
+    pub fn revoke(&self, root_key_id: u64) -> Result<bool> {
+        let response: lnrpc::DeleteMacaroonIdResponse = self.client.call_lnd_method(
+            lnrpc::DeleteMacaroonIdRequest { root_key_id },
+            deleteMacaroonID,
+        )?;
+        Ok(response.deleted)
+    }
+
+    /// Bakes a replacement with a fresh root key id, then revokes the old id.
+    ///
+    /// # Returns
+    ///
+    /// The new root key id and the new macaroon.
+    pub fn rotate(
+        &self,
+        permissions: &PermissionSet,
+        old_root_key_id: u64,
+    ) -> Result<(u64, String)> {
+        let new_root_key_id = self
+            .root_key_ids()?
+            .into_iter()
+            .chain([old_root_key_id])
+            .max()
+            .unwrap_or_default()
+            + 1;
+        let macaroon = self.bake(permissions, new_root_key_id)?;
+        self.revoke(old_root_key_id)?;
+        Ok((new_root_key_id, macaroon))
+    }
+
+    /// Returns the permissions a method requires, e.g. for
+    /// `/lnrpc.Lightning/AddInvoice`, or `None` for an unknown method.
+    pub fn required_permissions(&self, method: &str) -> Result<Option<PermissionSet>> {
+        Ok(self.methods()?.get(method).cloned())
+    }
+
+    /// Checks locally whether `permissions` allow calling `method`, without any caveats.
+    pub fn allows(&self, permissions: &PermissionSet, method: &str) -> Result<bool> {
+        if permissions.contains_all(&PermissionSet::new().allow_uri(method)) {
+            return Ok(true);
+        }
+        Ok(self
+            .required_permissions(method)?
+            .is_some_and(|required| permissions.contains_all(&required)))
+    }
+
+    /// Asks lnd whether a macaroon allows calling `method`, caveats included.
+    pub fn check(&self, macaroon: &str, method: &str) -> Result<bool> {
+        let required = self
+            .required_permissions(method)?
+            .with_context(|| format!("Unknown method {}", method))?;
+        let response: lnrpc::CheckMacPermResponse = self.client.call_lnd_method(
+            lnrpc::CheckMacPermRequest {
+                macaroon: hex::decode(macaroon).context("Invalid macaroon")?,
+                permissions: required.to_lnd(),
+                full_method: method.to_string(),
+            },
+            checkMacaroonPermissions,
+        )?;
+        Ok(response.valid)
+    }
+
+    fn methods(&self) -> Result<MethodPermissions> {
+        let mut methods = self.methods.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(methods) = &*methods {
+            return Ok(methods.clone());
+        }
+        let response: lnrpc::ListPermissionsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListPermissionsRequest {}, listPermissions)?;
+        let listed = Arc::new(
+            response
+                .method_permissions
+                .into_iter()
+                .map(|(method, list)| {
+                    let set = list
+                        .permissions
+                        .into_iter()
+                        .map(Permission::from_lnd)
+                        .collect();
+                    (method, set)
+                })
+                .collect::<HashMap<_, _>>(),
+        );
+        *methods = Some(listed.clone());
+        Ok(listed)
+    }
+}
+
+/// Field types of the macaroon v2 binary format.
+const FIELD_EOS: u8 = 0;
+const FIELD_IDENTIFIER: u8 = 2;
+const FIELD_SIGNATURE: u8 = 6;
+
+/// Adds a first-party caveat to a hex encoded v2 macaroon, as the macaroon's holder can
+/// always do to restrict it further.
+pub fn add_first_party_caveat(macaroon: &str, caveat: &str) -> Result<String> {
+    let bytes = hex::decode(macaroon).context("Invalid macaroon")?;
+    if bytes.first() != Some(&2) {
+        return Err(anyhow::anyhow!("Only version 2 macaroons are supported"));
+    }
+
+    // Skip the header packets, then every caveat section, up to the empty section
+    // that ends the caveat list.
+    let mut position = skip_section(&bytes, 1)?;
+    while *bytes.get(position).context("Truncated macaroon")? != FIELD_EOS {
+        position = skip_section(&bytes, position)?;
+    }
+    let caveats_end = position;
+    position += 1;
+
+    let (field, signature, _) = read_field(&bytes, position)?;
+    if field != FIELD_SIGNATURE {
+        return Err(anyhow::anyhow!("Invalid macaroon signature"));
+    }
+    let key = PKey::hmac(signature).context("Invalid macaroon signature")?;
+    let mut signer =
+        Signer::new(openssl::hash::MessageDigest::sha256(), &key).context("HMAC failed")?;
+    signer.update(caveat.as_bytes()).context("HMAC failed")?;
+    let signature = signer.sign_to_vec().context("HMAC failed")?;
+
+    let mut result = bytes[..caveats_end].to_vec();
+    write_field(&mut result, FIELD_IDENTIFIER, caveat.as_bytes());
+    result.push(FIELD_EOS);
+    result.push(FIELD_EOS);
+    write_field(&mut result, FIELD_SIGNATURE, &signature);
+    Ok(hex::encode(result))
+}
+
+/// Skips the packets of a section, returning the position after its EOS.
+fn skip_section(bytes: &[u8], mut position: usize) -> Result<usize> {
+    loop {
+        let (field, _, next) = read_field(bytes, position)?;
+        position = next;
+        if field == FIELD_EOS {
+            return Ok(position);
+        }
+    }
+}
+
+/// Reads the field at `position`, returning its type, data and the following position.
+fn read_field(bytes: &[u8], position: usize) -> Result<(u8, &[u8], usize)> {
+    let field = *bytes.get(position).context("Truncated macaroon")?;
+    if field == FIELD_EOS {
+        return Ok((field, &[], position + 1));
+    }
+    let mut length = 0usize;
+    let mut shift = 0;
+    let mut next = position + 1;
+    loop {
+        let byte = *bytes.get(next).context("Truncated macaroon")?;
+        next += 1;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 28 {
+            return Err(anyhow::anyhow!("Invalid macaroon field length"));
+        }
+    }
+    let data = bytes
+        .get(next..next + length)
+        .context("Truncated macaroon")?;
+    Ok((field, data, next + length))
+}
+
+fn write_field(out: &mut Vec<u8>, field: u8, data: &[u8]) {
+    out.push(field);
+    let mut length = data.len();
+    while length >= 0x80 {
+        out.push((length as u8 & 0x7f) | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+    out.extend_from_slice(data);
+}
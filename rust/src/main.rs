@@ -1,10 +1,11 @@
 use anyhow::{anyhow, Result};
 use embedded_lnd::{
-    addInvoice, channelAcceptor, connectPeer, getInfo, invoicesSubscribeSingleInvoice, stopDaemon,
-    subscribePeerEvents, LndClient,
+    addInvoice, channelAcceptor, connectPeer, getInfo, invoicesSubscribeSingleInvoice,
+    subscribePeerEvents, LndClient, NeutrinoConfig, NeutrinoManager,
 };
 use lnd_grpc_rust::{invoicesrpc, lnrpc};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[allow(clippy::needless_update)]
 fn main() -> Result<()> {
@@ -38,6 +39,16 @@ fn main() -> Result<()> {
 
     println!("Getinfo response {:?}", info);
 
+    // Keep neutrino connected to the local peer, reconnecting it when it drops or stalls
+    let neutrino = NeutrinoManager::new(
+        (*client).clone(),
+        NeutrinoConfig {
+            peers: vec!["localhost:19444".to_string()],
+            ..Default::default()
+        },
+    );
+    neutrino.start(|event| println!("Neutrino: {:?}", event));
+
     let invoice = lnrpc::Invoice {
         memo: "test invoice".to_string(),
         value: 1000,
@@ -75,7 +86,7 @@ fn main() -> Result<()> {
         .subscribe()?;
 
     // Setup channel acceptor
-    let acceptor = client
+    client
         .bidi_stream::<lnrpc::ChannelAcceptRequest, lnrpc::ChannelAcceptResponse>(channelAcceptor)
         .on_request(|request_result| {
             match request_result {
@@ -124,10 +135,8 @@ fn main() -> Result<()> {
         }
     }
 
-    client.stop_stream(acceptor)?;
-
-    let _stop_response: lnrpc::StopResponse =
-        client.call_lnd_method(lnrpc::StopRequest {}, stopDaemon)?;
+    // Closes the channel acceptor and the subscriptions, then stops lnd.
+    client.shutdown(Duration::from_secs(30))?;
 
     Ok(())
 }
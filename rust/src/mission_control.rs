@@ -0,0 +1,407 @@
+use crate::pagination::unix_seconds;
+use crate::{
+    routerGetMissionControlConfig, routerQueryMissionControl, routerResetMissionControl,
+    routerSetMissionControlConfig, routerXImportMissionControl, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::routerrpc;
+use lnd_grpc_rust::routerrpc::mission_control_config::{EstimatorConfig, ProbabilityModel};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The first line of an exported mission control file.
+const FILE_HEADER: &str = "lnd-mission-control";
+
+/// The version of the exported file format.
+pub const MISSION_CONTROL_FILE_VERSION: u32 = 1;
+
+/// The payment results mission control has for one channel direction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PairRecord {
+    /// The sending node, as hex.
+    pub from: String,
+    /// The receiving node, as hex.
+    pub to: String,
+    /// When the last failure happened, in unix seconds, or 0.
+    pub fail_time: i64,
+    /// The smallest amount that failed, in millisatoshis.
+    pub fail_amt_msat: i64,
+    /// When the last success happened, in unix seconds, or 0.
+    pub success_time: i64,
+    /// The largest amount that succeeded, in millisatoshis.
+    pub success_amt_msat: i64,
+}
+
+impl PairRecord {
+    /// Returns when the last result happened, in unix seconds.
+    pub fn last_update(&self) -> i64 {
+        self.fail_time.max(self.success_time)
+    }
+
+    fn from_history(pair: routerrpc::PairHistory) -> Self {
+        let history = pair.history.unwrap_or_default();
+        Self {
+            from: hex::encode(pair.node_from),
+            to: hex::encode(pair.node_to),
+            fail_time: history.fail_time,
+            fail_amt_msat: history.fail_amt_msat,
+            success_time: history.success_time,
+            success_amt_msat: history.success_amt_msat,
+        }
+    }
+
+    fn to_history(&self) -> Result<routerrpc::PairHistory> {
+        Ok(routerrpc::PairHistory {
+            node_from: hex::decode(&self.from).context("Invalid node pubkey")?,
+            node_to: hex::decode(&self.to).context("Invalid node pubkey")?,
+            history: Some(routerrpc::PairData {
+                fail_time: self.fail_time,
+                fail_amt_sat: self.fail_amt_msat / 1000,
+                fail_amt_msat: self.fail_amt_msat,
+                success_time: self.success_time,
+                success_amt_sat: self.success_amt_msat / 1000,
+                success_amt_msat: self.success_amt_msat,
+            }),
+        })
+    }
+}
+
+/// Writes pair records in the portable file format: a `lnd-mission-control <version>`
+/// header, then one pair per line with the nodes as hex and the times and amounts as
+/// numbers, separated by spaces.
+pub fn encode_pairs(pairs: &[PairRecord]) -> String {
+    let mut out = format!("{} {}\n", FILE_HEADER, MISSION_CONTROL_FILE_VERSION);
+    for pair in pairs {
+        out.push_str(&format!(
+            "{} {} {} {} {} {}\n",
+            pair.from,
+            pair.to,
+            pair.fail_time,
+            pair.fail_amt_msat,
+            pair.success_time,
+            pair.success_amt_msat
+        ));
+    }
+    out
+}
+
+/// Reads pair records written by `encode_pairs`.
+pub fn decode_pairs(text: &str) -> Result<Vec<PairRecord>> {
+    let mut lines = text.lines();
+    let version = lines
+        .next()
+        .and_then(|header| header.strip_prefix(FILE_HEADER))
+        .and_then(|version| version.trim().parse::<u32>().ok())
+        .context("Not a mission control file")?;
+    if version != MISSION_CONTROL_FILE_VERSION {
+        anyhow::bail!("Unsupported mission control file version {}", version);
+    }
+    lines
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                [from, to, numbers @ ..] if numbers.len() == 4 => numbers
+                    .iter()
+                    .map(|number| number.parse::<i64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .map(|numbers| PairRecord {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        fail_time: numbers[0],
+                        fail_amt_msat: numbers[1],
+                        success_time: numbers[2],
+                        success_amt_msat: numbers[3],
+                    }),
+                _ => None,
+            };
+            parsed.with_context(|| format!("Invalid mission control entry on line {}", number + 2))
+        })
+        .collect()
+}
+
+/// How `MissionControl::import` treats existing results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Keeps the results lnd already has, only importing pairs with newer results.
+    /// Otherwise mission control is reset first.
+    pub merge: bool,
+    /// Skips pairs whose last result is older than this.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for ImportOptions {
+    /// Merges and keeps entries of any age.
+    fn default() -> Self {
+        Self {
+            merge: true,
+            max_age: None,
+        }
+    }
+}
+
+/// The result of `MissionControl::import`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// The pairs handed to lnd.
+    pub imported: usize,
+    /// The pairs skipped because they were too old or lnd had newer results.
+    pub skipped: usize,
+}
+
+/// The parameters of the apriori probability estimator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AprioriEstimator {
+    /// How long it takes for a failure to be half forgotten.
+    pub half_life: Duration,
+    /// The probability of a channel without results, from 0 to 1.
+    pub hop_probability: f64,
+    /// How much past results count against the a priori probability, from 0 to 1.
+    pub weight: f64,
+    /// The fraction of the capacity above which the probability drops, from 0.75 to 1.
+    pub capacity_fraction: f64,
+}
+
+/// The parameters of the bimodal probability estimator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BimodalEstimator {
+    /// How much the results of a node's other channels count, from 0 to 1.
+    pub node_weight: f64,
+    /// How fast the liquidity distribution drops off from the channel edges.
+    pub scale_msat: u64,
+    /// How long it takes for results to be forgotten.
+    pub decay_time: Duration,
+}
+
+/// The probability estimator used in pathfinding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Estimator {
+    /// Estimates from a fixed a priori probability and past results.
+    Apriori(AprioriEstimator),
+    /// Assumes liquidity sits mostly at either end of a channel.
+    Bimodal(BimodalEstimator),
+}
+
+/// The mission control configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MissionControlSettings {
+    /// The probability estimator.
+    pub estimator: Estimator,
+    /// How many payment results are kept.
+    pub maximum_payment_results: u32,
+    /// How long a success keeps a failure from being forgotten.
+    pub minimum_failure_relax_interval: Duration,
+}
+
+impl MissionControlSettings {
+    fn validate(&self) -> Result<()> {
+        let unit = 0.0..=1.0;
+        match &self.estimator {
+            Estimator::Apriori(apriori) => {
+                if !unit.contains(&apriori.hop_probability) || !unit.contains(&apriori.weight) {
+                    anyhow::bail!("Apriori hop probability and weight must be between 0 and 1");
+                }
+                if !(0.75..=1.0).contains(&apriori.capacity_fraction) {
+                    anyhow::bail!("Apriori capacity fraction must be between 0.75 and 1");
+                }
+            }
+            Estimator::Bimodal(bimodal) => {
+                if !unit.contains(&bimodal.node_weight) {
+                    anyhow::bail!("Bimodal node weight must be between 0 and 1");
+                }
+                if bimodal.scale_msat == 0 || bimodal.decay_time.is_zero() {
+                    anyhow::bail!("Bimodal scale and decay time must be positive");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn from_config(config: routerrpc::MissionControlConfig) -> Result<Self> {
+        let estimator = match config.estimator_config {
+            Some(EstimatorConfig::Apriori(apriori)) => Estimator::Apriori(AprioriEstimator {
+                half_life: Duration::from_secs(apriori.half_life_seconds),
+                hop_probability: apriori.hop_probability,
+                weight: apriori.weight,
+                capacity_fraction: apriori.capacity_fraction,
+            }),
+            Some(EstimatorConfig::Bimodal(bimodal)) => Estimator::Bimodal(BimodalEstimator {
+                node_weight: bimodal.node_weight,
+                scale_msat: bimodal.scale_msat,
+                decay_time: Duration::from_secs(bimodal.decay_time),
+            }),
+            None => anyhow::bail!("lnd returned no estimator config"),
+        };
+        Ok(Self {
+            estimator,
+            maximum_payment_results: config.maximum_payment_results,
+            minimum_failure_relax_interval: Duration::from_secs(
+                config.minimum_failure_relax_interval,
+            ),
+        })
+    }
+
+    fn to_config(self) -> routerrpc::MissionControlConfig {
+        let (model, estimator_config) = match self.estimator {
+            Estimator::Apriori(apriori) => (
+                ProbabilityModel::Apriori,
+                EstimatorConfig::Apriori(routerrpc::AprioriParameters {
+                    half_life_seconds: apriori.half_life.as_secs(),
+                    hop_probability: apriori.hop_probability,
+                    weight: apriori.weight,
+                    capacity_fraction: apriori.capacity_fraction,
+                }),
+            ),
+            Estimator::Bimodal(bimodal) => (
+                ProbabilityModel::Bimodal,
+                EstimatorConfig::Bimodal(routerrpc::BimodalParameters {
+                    node_weight: bimodal.node_weight,
+                    scale_msat: bimodal.scale_msat,
+                    decay_time: bimodal.decay_time.as_secs(),
+                }),
+            ),
+        };
+        routerrpc::MissionControlConfig {
+            maximum_payment_results: self.maximum_payment_results,
+            minimum_failure_relax_interval: self.minimum_failure_relax_interval.as_secs(),
+            model: model as i32,
+            estimator_config: Some(estimator_config),
+            // The other fields are deprecated in favor of the estimator config.
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads, saves and restores lnd's mission control, the payment results pathfinding
+/// learns from, and configures its probability estimator.
+#[derive(Clone)]
+pub struct MissionControl {
+    client: LndClient,
+}
+
+impl MissionControl {
+    /// Creates a mission control handle.
+    pub fn new(client: LndClient) -> Self {
+        Self { client }
+    }
+
+    /// Returns the results of every channel direction mission control knows about.
+    pub fn pairs(&self) -> Result<Vec<PairRecord>> {
+        let response: routerrpc::QueryMissionControlResponse = self.client.call_lnd_method(
+            routerrpc::QueryMissionControlRequest {},
+            routerQueryMissionControl,
+        )?;
+        Ok(response
+            .pairs
+            .into_iter()
+            .map(PairRecord::from_history)
+            .collect())
+    }
+
+    /// Exports mission control in the portable file format of `encode_pairs`.
+    pub fn export(&self) -> Result<String> {
+        Ok(encode_pairs(&self.pairs()?))
+    }
+
+    /// Exports mission control to a file, replacing it atomically.
+    pub fn export_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, self.export()?).context("Failed to write mission control")?;
+        fs::rename(&temp, path).context("Failed to replace mission control file")
+    }
+
+    /// Imports an export of `export`.
+    pub fn import(&self, text: &str, options: &ImportOptions) -> Result<ImportSummary> {
+        let pairs = decode_pairs(text)?;
+        let oldest = options
+            .max_age
+            .map(|max_age| unix_seconds(SystemTime::now()).saturating_sub(max_age.as_secs()));
+        let existing: HashMap<(String, String), i64> = if options.merge {
+            self.pairs()?
+                .into_iter()
+                .map(|pair| {
+                    let last_update = pair.last_update();
+                    ((pair.from, pair.to), last_update)
+                })
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        let mut summary = ImportSummary::default();
+        let mut histories = Vec::new();
+        for pair in &pairs {
+            let too_old = oldest.is_some_and(|oldest| pair.last_update() < oldest as i64);
+            let outdated = existing
+                .get(&(pair.from.clone(), pair.to.clone()))
+                .is_some_and(|&last_update| last_update >= pair.last_update());
+            if too_old || outdated {
+                summary.skipped += 1;
+            } else {
+                histories.push(pair.to_history()?);
+            }
+        }
+        summary.imported = histories.len();
+
+        if !options.merge {
+            self.reset()?;
+        }
+        if !histories.is_empty() {
+            let _: routerrpc::XImportMissionControlResponse = self.client.call_lnd_method(
+                routerrpc::XImportMissionControlRequest {
+                    pairs: histories,
+                    // Newer results must be able to replace older ones in a merge, and
+                    // after a reset there is nothing to protect.
+                    force: true,
+                },
+                routerXImportMissionControl,
+            )?;
+        }
+        Ok(summary)
+    }
+
+    /// Imports a file written by `export_to_file`.
+    pub fn import_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: &ImportOptions,
+    ) -> Result<ImportSummary> {
+        let text = fs::read_to_string(path).context("Failed to read mission control file")?;
+        self.import(&text, options)
+    }
+
+    /// Forgets all payment results.
+    pub fn reset(&self) -> Result<()> {
+        let _: routerrpc::ResetMissionControlResponse = self.client.call_lnd_method(
+            routerrpc::ResetMissionControlRequest {},
+            routerResetMissionControl,
+        )?;
+        Ok(())
+    }
+
+    /// Returns the current configuration.
+    pub fn config(&self) -> Result<MissionControlSettings> {
+        let response: routerrpc::GetMissionControlConfigResponse = self.client.call_lnd_method(
+            routerrpc::GetMissionControlConfigRequest {},
+            routerGetMissionControlConfig,
+        )?;
+        MissionControlSettings::from_config(response.config.context("lnd returned no config")?)
+    }
+
+    /// Replaces the configuration, after checking the parameters are in range.
+    pub fn set_config(&self, settings: &MissionControlSettings) -> Result<()> {
+        settings.validate()?;
+        let _: routerrpc::SetMissionControlConfigResponse = self.client.call_lnd_method(
+            routerrpc::SetMissionControlConfigRequest {
+                config: Some(settings.to_config()),
+            },
+            routerSetMissionControlConfig,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use crate::lnd_client::WeakLndClient;
+use crate::{
+    getInfo, neutrinoKitAddPeer, neutrinoKitDisconnectPeer, neutrinoKitIsBanned, neutrinoKitStatus,
+    LndClient,
+};
+use anyhow::Result;
+use lnd_grpc_rust::{lnrpc, neutrinorpc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type EventHandler = Box<dyn Fn(NeutrinoEvent) + Send + Sync>;
+
+/// How a `NeutrinoManager` keeps the light client connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeutrinoConfig {
+    /// The peers to rotate through, as `host:port`.
+    pub peers: Vec<String>,
+    /// How many peers should be connected at any time.
+    pub min_peers: usize,
+    /// How long the block height may stand still while not synced before the connected
+    /// peers are considered stalled and replaced.
+    pub stall_timeout: Duration,
+    /// How often `start` checks on the peers.
+    pub poll_interval: Duration,
+}
+
+impl Default for NeutrinoConfig {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            min_peers: 1,
+            stall_timeout: Duration::from_secs(120),
+            poll_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How far the light client is synced.
+///
+/// neutrino does not report the heights of its peers, so `synced` is the only signal
+/// that the chain tip was reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// Whether neutrino is running.
+    pub active: bool,
+    /// Whether neutrino considers itself caught up with its peers.
+    pub synced: bool,
+    /// The height of the best block header neutrino knows.
+    pub block_height: i32,
+    /// The hash of that block.
+    pub block_hash: String,
+    /// The height lnd's wallet has processed, which trails `block_height` while the
+    /// compact filters are fetched and scanned.
+    pub wallet_height: u32,
+    /// Whether lnd's wallet is synced to the chain.
+    pub wallet_synced: bool,
+    /// The connected peers.
+    pub peers: Vec<String>,
+}
+
+/// Something a `NeutrinoManager` noticed or did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NeutrinoEvent {
+    /// The latest sync progress, reported on every check.
+    Progress(SyncProgress),
+    /// The light client finished syncing.
+    Synced,
+    /// The block height did not move for `stall_timeout` while not synced.
+    Stalled {
+        /// The height it is stuck at.
+        block_height: i32,
+    },
+    /// A configured peer is banned by neutrino and is skipped.
+    Banned(String),
+    /// A peer was connected.
+    PeerAdded(String),
+    /// A peer was disconnected.
+    PeerDisconnected(String),
+    /// A check failed.
+    Error(String),
+}
+
+struct Progress {
+    block_height: i32,
+    since: Instant,
+    synced: bool,
+    /// The index in the configured peer list to try next.
+    next_peer: usize,
+}
+
+/// Keeps neutrino connected to working peers and reports its sync progress.
+///
+/// Each check connects configured peers until `min_peers` are connected, skipping
+/// banned ones, and replaces the connected peers when syncing stalls. Peers are tried
+/// in turn, so a peer that is down does not block the others.
+#[derive(Clone)]
+pub struct NeutrinoManager {
+    client: LndClient,
+    config: NeutrinoConfig,
+    progress: Arc<Mutex<Progress>>,
+    running: Arc<AtomicBool>,
+    /// Counts the calls to `start`, so a thread left over from before a `stop` exits
+    /// instead of running next to the new one.
+    generation: Arc<AtomicU64>,
+}
+
+impl NeutrinoManager {
+    /// Creates a manager for the given peers.
+    pub fn new(client: LndClient, config: NeutrinoConfig) -> Self {
+        Self {
+            client,
+            config,
+            progress: Arc::new(Mutex::new(Progress {
+                block_height: -1,
+                since: Instant::now(),
+                synced: false,
+                next_peer: 0,
+            })),
+            running: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the current sync progress.
+    pub fn status(&self) -> Result<SyncProgress> {
+        let status: neutrinorpc::StatusResponse = self
+            .client
+            .call_lnd_method(neutrinorpc::StatusRequest {}, neutrinoKitStatus)?;
+        let info: lnrpc::GetInfoResponse = self
+            .client
+            .call_lnd_method(lnrpc::GetInfoRequest {}, getInfo)?;
+        Ok(SyncProgress {
+            active: status.active,
+            synced: status.synced,
+            block_height: status.block_height,
+            block_hash: status.block_hash,
+            wallet_height: info.block_height,
+            wallet_synced: info.synced_to_chain,
+            peers: status.peers,
+        })
+    }
+
+    /// Connects a peer.
+    pub fn add_peer(&self, address: &str) -> Result<()> {
+        let _: neutrinorpc::AddPeerResponse = self.client.call_lnd_method(
+            neutrinorpc::AddPeerRequest {
+                peer_addrs: address.to_string(),
+            },
+            neutrinoKitAddPeer,
+        )?;
+        Ok(())
+    }
+
+    /// Disconnects a peer.
+    pub fn disconnect_peer(&self, address: &str) -> Result<()> {
+        let _: neutrinorpc::DisconnectPeerResponse = self.client.call_lnd_method(
+            neutrinorpc::DisconnectPeerRequest {
+                peer_addrs: address.to_string(),
+            },
+            neutrinoKitDisconnectPeer,
+        )?;
+        Ok(())
+    }
+
+    /// Returns whether neutrino banned a peer.
+    pub fn is_banned(&self, address: &str) -> Result<bool> {
+        let response: neutrinorpc::IsBannedResponse = self.client.call_lnd_method(
+            neutrinorpc::IsBannedRequest {
+                peer_addrs: address.to_string(),
+            },
+            neutrinoKitIsBanned,
+        )?;
+        Ok(response.banned)
+    }
+
+    /// Checks on the peers once, fixing what it can.
+    ///
+    /// # Returns
+    ///
+    /// What was noticed and done, starting with the sync progress.
+    pub fn check(&self) -> Result<Vec<NeutrinoEvent>> {
+        let status = self.status()?;
+        let mut events = vec![NeutrinoEvent::Progress(status.clone())];
+        let mut connected = status.peers.clone();
+
+        let stalled = {
+            let mut progress = self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+            if status.synced && !progress.synced {
+                events.push(NeutrinoEvent::Synced);
+            }
+            progress.synced = status.synced;
+            if status.block_height != progress.block_height || status.synced {
+                progress.block_height = status.block_height;
+                progress.since = Instant::now();
+                false
+            } else {
+                progress.since.elapsed() >= self.config.stall_timeout
+            }
+        };
+        if stalled {
+            events.push(NeutrinoEvent::Stalled {
+                block_height: status.block_height,
+            });
+            // A peer that fails to disconnect stays connected and counts towards
+            // `min_peers`.
+            let mut still_connected = Vec::new();
+            for peer in connected.drain(..) {
+                match self.disconnect_peer(&peer) {
+                    Ok(()) => events.push(NeutrinoEvent::PeerDisconnected(peer)),
+                    Err(e) => {
+                        events.push(NeutrinoEvent::Error(format!(
+                            "Failed to disconnect peer {}: {}",
+                            peer, e
+                        )));
+                        still_connected.push(peer);
+                    }
+                }
+            }
+            connected = still_connected;
+            // Give the next peers the full timeout.
+            self.progress
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .since = Instant::now();
+        }
+
+        // Try each configured peer at most once per check, starting where the last
+        // check stopped.
+        let count = self.config.peers.len();
+        for _ in 0..count {
+            if connected.len() >= self.config.min_peers {
+                break;
+            }
+            let peer = {
+                let mut progress = self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+                let peer = self.config.peers[progress.next_peer % count].clone();
+                progress.next_peer = (progress.next_peer + 1) % count;
+                peer
+            };
+            if connected.contains(&peer) {
+                continue;
+            }
+            match self.is_banned(&peer) {
+                Ok(true) => {
+                    events.push(NeutrinoEvent::Banned(peer));
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    events.push(NeutrinoEvent::Error(format!(
+                        "Failed to check whether peer {} is banned: {}",
+                        peer, e
+                    )));
+                    continue;
+                }
+            }
+            match self.add_peer(&peer) {
+                Ok(()) => {
+                    connected.push(peer.clone());
+                    events.push(NeutrinoEvent::PeerAdded(peer));
+                }
+                Err(e) => events.push(NeutrinoEvent::Error(format!(
+                    "Failed to add peer {}: {}",
+                    peer, e
+                ))),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Runs `check` every `poll_interval` on a background thread until `stop` is called
+    /// or the client shuts down.
+    pub fn start<F>(&self, on_event: F)
+    where
+        F: Fn(NeutrinoEvent) + Send + Sync + 'static,
+    {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let on_event: EventHandler = Box::new(on_event);
+        let manager = Watcher {
+            client: self.client.downgrade(),
+            config: self.config.clone(),
+            progress: self.progress.clone(),
+            running: self.running.clone(),
+            generation: self.generation.clone(),
+            started: self.generation.fetch_add(1, Ordering::AcqRel) + 1,
+        };
+        thread::spawn(move || manager.run(on_event));
+    }
+
+    /// Stops the checks started by `start`.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+/// The background side of `NeutrinoManager::start`, which must not keep the client
+/// alive.
+struct Watcher {
+    client: WeakLndClient,
+    config: NeutrinoConfig,
+    progress: Arc<Mutex<Progress>>,
+    running: Arc<AtomicBool>,
+    generation: Arc<AtomicU64>,
+    /// The generation this thread belongs to.
+    started: u64,
+}
+
+impl Watcher {
+    fn is_current(&self) -> bool {
+        self.generation.load(Ordering::Acquire) == self.started
+    }
+
+    fn run(self, on_event: EventHandler) {
+        while self.running.load(Ordering::Acquire) && self.is_current() {
+            let Some(client) = self.client.upgrade() else {
+                break;
+            };
+            if !client.is_running() {
+                break;
+            }
+            let manager = NeutrinoManager {
+                client,
+                config: self.config.clone(),
+                progress: self.progress.clone(),
+                running: self.running.clone(),
+                generation: self.generation.clone(),
+            };
+            match manager.check() {
+                Ok(events) => events.into_iter().for_each(&on_event),
+                Err(e) => on_event(NeutrinoEvent::Error(e.to_string())),
+            }
+            drop(manager);
+            thread::sleep(self.config.poll_interval);
+        }
+        if self.is_current() {
+            self.running.store(false, Ordering::Release);
+        }
+    }
+}
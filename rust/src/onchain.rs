@@ -0,0 +1,451 @@
+use crate::chain_notifier::txid_to_bytes;
+use crate::channels::txid_to_string;
+use crate::{
+    getTransactions, listUnspent, newAddress, sendCoins, sendMany, walletBalance,
+    walletKitEstimateFee, walletKitFinalizePsbt, walletKitFundPsbt, walletKitLabelTransaction,
+    walletKitLeaseOutput, walletKitPublishTransaction, walletKitReleaseOutput,
+    walletKitRequiredReserve, walletKitSendOutputs, FeePreference, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::walletrpc::fund_psbt_request::Template;
+use lnd_grpc_rust::{lnrpc, signrpc, walletrpc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The wallet's balance, in satoshis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalletBalance {
+    /// Confirmed funds.
+    pub confirmed: i64,
+    /// Funds waiting for a confirmation.
+    pub unconfirmed: i64,
+    /// Funds in leased outputs, which cannot be spent until the lease ends.
+    pub locked: i64,
+    /// Funds that must stay in the wallet to bump the fees of anchor channels.
+    pub reserved: i64,
+}
+
+impl WalletBalance {
+    /// Returns the confirmed funds that can be sent without touching the reserve.
+    pub fn spendable(&self) -> i64 {
+        (self.confirmed - self.reserved).max(0)
+    }
+
+    /// Returns all funds, confirmed or not.
+    pub fn total(&self) -> i64 {
+        self.confirmed + self.unconfirmed
+    }
+}
+
+/// An output of a transaction sent by `OnchainWallet::send`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendOutput {
+    /// The address to pay.
+    pub address: String,
+    /// The amount to pay, in satoshis.
+    pub amount_sat: i64,
+}
+
+/// The parameters of an on-chain send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendRequest {
+    /// Who to pay.
+    pub outputs: Vec<SendOutput>,
+    /// The fee rate to pay.
+    pub fee: FeePreference,
+    /// The label to store with the transaction, if not empty.
+    pub label: String,
+    /// The UTXOs to spend from, as `txid:output_index`, or empty to let lnd choose.
+    pub inputs: Vec<String>,
+    /// How many confirmations the spent UTXOs need, or lnd's default when zero.
+    pub min_confs: i32,
+}
+
+impl SendRequest {
+    /// Creates a request paying a single address.
+    pub fn new(address: &str, amount_sat: i64, fee: FeePreference) -> Self {
+        Self {
+            outputs: vec![SendOutput {
+                address: address.to_string(),
+                amount_sat,
+            }],
+            fee,
+            label: String::new(),
+            inputs: Vec::new(),
+            min_confs: 0,
+        }
+    }
+}
+
+/// Sends and receives on-chain funds with lnd's wallet.
+#[derive(Clone)]
+pub struct OnchainWallet {
+    client: LndClient,
+    /// The id of the leases this wallet takes, so it only ever releases its own.
+    lease_id: [u8; 32],
+}
+
+impl OnchainWallet {
+    /// Creates a wallet API.
+    pub fn new(client: LndClient) -> Result<Self> {
+        let mut lease_id = [0u8; 32];
+        openssl::rand::rand_bytes(&mut lease_id).context("Failed to generate a lease id")?;
+        Ok(Self { client, lease_id })
+    }
+
+    /// Returns the balance, including what must be kept for anchor channels.
+    pub fn balance(&self) -> Result<WalletBalance> {
+        let balance: lnrpc::WalletBalanceResponse = self
+            .client
+            .call_lnd_method(lnrpc::WalletBalanceRequest::default(), walletBalance)?;
+        let reserve: walletrpc::RequiredReserveResponse = self.client.call_lnd_method(
+            walletrpc::RequiredReserveRequest::default(),
+            walletKitRequiredReserve,
+        )?;
+        Ok(WalletBalance {
+            confirmed: balance.confirmed_balance,
+            unconfirmed: balance.unconfirmed_balance,
+            locked: balance.locked_balance,
+            reserved: reserve.required_reserve,
+        })
+    }
+
+    /// Returns a new address to receive funds.
+    pub fn new_address(&self, address_type: lnrpc::AddressType) -> Result<String> {
+        let response: lnrpc::NewAddressResponse = self.client.call_lnd_method(
+            lnrpc::NewAddressRequest {
+                r#type: address_type as i32,
+                ..Default::default()
+            },
+            newAddress,
+        )?;
+        Ok(response.address)
+    }
+
+    /// Returns the UTXOs with at least `min_confs` confirmations, unconfirmed ones
+    /// included when `min_confs` is zero.
+    pub fn utxos(&self, min_confs: i32) -> Result<Vec<lnrpc::Utxo>> {
+        let response: lnrpc::ListUnspentResponse = self.client.call_lnd_method(
+            lnrpc::ListUnspentRequest {
+                min_confs,
+                max_confs: i32::MAX,
+                ..Default::default()
+            },
+            listUnspent,
+        )?;
+        Ok(response.utxos)
+    }
+
+    /// Returns the wallet's transactions, with their labels.
+    pub fn transactions(&self) -> Result<Vec<lnrpc::Transaction>> {
+        let response: lnrpc::TransactionDetails = self.client.call_lnd_method(
+            lnrpc::GetTransactionsRequest {
+                end_height: -1,
+                ..Default::default()
+            },
+            getTransactions,
+        )?;
+        Ok(response.transactions)
+    }
+
+    /// Labels a wallet transaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `txid` - The transaction, as shown in block explorers.
+    /// * `label` - The label to store.
+    /// * `overwrite` - Whether to replace an existing label instead of failing.
+    pub fn label_transaction(&self, txid: &str, label: &str, overwrite: bool) -> Result<()> {
+        let _: walletrpc::LabelTransactionResponse = self.client.call_lnd_method(
+            walletrpc::LabelTransactionRequest {
+                txid: txid_to_bytes(txid)?,
+                label: label.to_string(),
+                overwrite,
+            },
+            walletKitLabelTransaction,
+        )?;
+        Ok(())
+    }
+
+    /// Leases a UTXO so that lnd does not spend it until `release` is called or the
+    /// lease expires.
+    pub fn lease(&self, outpoint: &str, duration: Duration) -> Result<()> {
+        let _: walletrpc::LeaseOutputResponse = self.client.call_lnd_method(
+            walletrpc::LeaseOutputRequest {
+                id: self.lease_id.to_vec(),
+                outpoint: Some(parse_outpoint(outpoint)?),
+                expiration_seconds: duration.as_secs().max(1),
+            },
+            walletKitLeaseOutput,
+        )?;
+        Ok(())
+    }
+
+    /// Releases a UTXO leased by `lease`.
+    pub fn release(&self, outpoint: &str) -> Result<()> {
+        let _: walletrpc::ReleaseOutputResponse = self.client.call_lnd_method(
+            walletrpc::ReleaseOutputRequest {
+                id: self.lease_id.to_vec(),
+                outpoint: Some(parse_outpoint(outpoint)?),
+            },
+            walletKitReleaseOutput,
+        )?;
+        Ok(())
+    }
+
+    /// Sends funds, applying the label to the transaction.
+    ///
+    /// When `inputs` are given, lnd spends only those. `sendMany` cannot be
+    /// restricted, so a send with inputs and several outputs is funded with
+    /// `walletKitFundPsbt`, which leases the inputs, then signed and published. The
+    /// leases are released if that fails.
+    ///
+    /// # Returns
+    ///
+    /// The txid of the sent transaction.
+    pub fn send(&self, request: &SendRequest) -> Result<String> {
+        if request.outputs.is_empty() {
+            return Err(anyhow::anyhow!("Nothing to send"));
+        }
+        if !request.inputs.is_empty() && request.outputs.len() > 1 {
+            return self.send_funded(request);
+        }
+        self.send_coins(request)
+    }
+
+    /// Sends to raw output scripts with `walletKitSendOutputs`, for outputs that have
+    /// no address. lnd chooses the coins spent, as the call takes no inputs.
+    ///
+    /// # Returns
+    ///
+    /// The serialized transaction.
+    pub fn send_outputs(
+        &self,
+        outputs: Vec<signrpc::TxOut>,
+        fee: FeePreference,
+        label: &str,
+    ) -> Result<Vec<u8>> {
+        let sat_per_kw = match fee {
+            // 1 vbyte is 4 weight units, so 250 vbytes make a kiloweight.
+            FeePreference::SatPerVbyte(rate) => rate as i64 * 250,
+            FeePreference::TargetConf(blocks) => {
+                let estimate: walletrpc::EstimateFeeResponse = self.client.call_lnd_method(
+                    walletrpc::EstimateFeeRequest {
+                        conf_target: blocks as i32,
+                    },
+                    walletKitEstimateFee,
+                )?;
+                estimate.sat_per_kw
+            }
+        };
+        let response: walletrpc::SendOutputsResponse = self.client.call_lnd_method(
+            walletrpc::SendOutputsRequest {
+                sat_per_kw,
+                outputs,
+                label: label.to_string(),
+                ..Default::default()
+            },
+            walletKitSendOutputs,
+        )?;
+        Ok(response.raw_tx)
+    }
+
+    fn send_coins(&self, request: &SendRequest) -> Result<String> {
+        let (target_conf, sat_per_vbyte) = match request.fee {
+            FeePreference::SatPerVbyte(rate) => (0, rate),
+            FeePreference::TargetConf(blocks) => (blocks as i32, 0),
+        };
+        if let [output] = request.outputs.as_slice() {
+            let response: lnrpc::SendCoinsResponse = self.client.call_lnd_method(
+                lnrpc::SendCoinsRequest {
+                    addr: output.address.clone(),
+                    amount: output.amount_sat,
+                    target_conf,
+                    sat_per_vbyte,
+                    label: request.label.clone(),
+                    min_confs: request.min_confs,
+                    outpoints: request
+                        .inputs
+                        .iter()
+                        .map(|input| parse_outpoint(input))
+                        .collect::<Result<_>>()?,
+                    ..Default::default()
+                },
+                sendCoins,
+            )?;
+            return Ok(response.txid);
+        }
+        let mut addr_to_amount = HashMap::new();
+        for output in &request.outputs {
+            *addr_to_amount.entry(output.address.clone()).or_insert(0) += output.amount_sat;
+        }
+        let response: lnrpc::SendManyResponse = self.client.call_lnd_method(
+            lnrpc::SendManyRequest {
+                addr_to_amount,
+                target_conf,
+                sat_per_vbyte,
+                label: request.label.clone(),
+                min_confs: request.min_confs,
+                ..Default::default()
+            },
+            sendMany,
+        )?;
+        Ok(response.txid)
+    }
+
+    fn send_funded(&self, request: &SendRequest) -> Result<String> {
+        let mut outputs = HashMap::new();
+        for output in &request.outputs {
+            *outputs.entry(output.address.clone()).or_insert(0) += output.amount_sat.max(0) as u64;
+        }
+        let funded: walletrpc::FundPsbtResponse = self.client.call_lnd_method(
+            walletrpc::FundPsbtRequest {
+                template: Some(Template::Raw(walletrpc::TxTemplate {
+                    inputs: request
+                        .inputs
+                        .iter()
+                        .map(|input| parse_outpoint(input))
+                        .collect::<Result<_>>()?,
+                    outputs,
+                })),
+                fees: Some(request.fee.to_fees()),
+                min_confs: request.min_confs,
+                ..Default::default()
+            },
+            walletKitFundPsbt,
+        )?;
+        let published = self.publish_funded(funded.funded_psbt, &request.label);
+        if published.is_err() {
+            for lease in funded.locked_utxos {
+                let _: Result<walletrpc::ReleaseOutputResponse> = self.client.call_lnd_method(
+                    walletrpc::ReleaseOutputRequest {
+                        id: lease.id,
+                        outpoint: lease.outpoint,
+                    },
+                    walletKitReleaseOutput,
+                );
+            }
+        }
+        published
+    }
+
+    fn publish_funded(&self, funded_psbt: Vec<u8>, label: &str) -> Result<String> {
+        let finalized: walletrpc::FinalizePsbtResponse = self.client.call_lnd_method(
+            walletrpc::FinalizePsbtRequest {
+                funded_psbt,
+                account: String::new(),
+            },
+            walletKitFinalizePsbt,
+        )?;
+        let txid = raw_txid(&finalized.raw_final_tx)?;
+        let response: walletrpc::PublishResponse = self.client.call_lnd_method(
+            walletrpc::Transaction {
+                tx_hex: finalized.raw_final_tx,
+                label: label.to_string(),
+            },
+            walletKitPublishTransaction,
+        )?;
+        if !response.publish_error.is_empty() {
+            anyhow::bail!("Failed to publish transaction: {}", response.publish_error);
+        }
+        Ok(txid)
+    }
+}
+
+/// Returns the txid of a serialized transaction, which hashes it without the witness
+/// data.
+pub(crate) fn raw_txid(raw_tx: &[u8]) -> Result<String> {
+    let mut reader = TxReader { raw_tx, pos: 0 };
+    reader.take(4)?;
+    let segwit = raw_tx.get(4..6) == Some(&[0, 1][..]);
+    if segwit {
+        reader.take(2)?;
+    }
+    let body_start = reader.pos;
+    let inputs = reader.compact_size()?;
+    for _ in 0..inputs {
+        reader.take(36)?;
+        let script_len = reader.compact_size()?;
+        reader.take(script_len)?;
+        reader.take(4)?;
+    }
+    for _ in 0..reader.compact_size()? {
+        reader.take(8)?;
+        let script_len = reader.compact_size()?;
+        reader.take(script_len)?;
+    }
+    let body_end = reader.pos;
+    if segwit {
+        for _ in 0..inputs {
+            for _ in 0..reader.compact_size()? {
+                let item_len = reader.compact_size()?;
+                reader.take(item_len)?;
+            }
+        }
+    }
+    let lock_time = reader.take(4)?;
+    if reader.pos != raw_tx.len() {
+        anyhow::bail!("Trailing bytes after transaction");
+    }
+
+    let mut stripped = raw_tx[..4].to_vec();
+    stripped.extend_from_slice(&raw_tx[body_start..body_end]);
+    stripped.extend_from_slice(lock_time);
+    Ok(txid_to_string(&openssl::sha::sha256(
+        &openssl::sha::sha256(&stripped),
+    )))
+}
+
+/// Reads the fields of a serialized transaction.
+struct TxReader<'a> {
+    raw_tx: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TxReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .raw_tx
+            .get(self.pos..self.pos.saturating_add(len))
+            .context("Truncated transaction")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn compact_size(&mut self) -> Result<usize> {
+        let size = match self.take(1)?[0] {
+            0xfd => u16::from_le_bytes(self.take(2)?.try_into()?) as u64,
+            0xfe => u32::from_le_bytes(self.take(4)?.try_into()?) as u64,
+            0xff => u64::from_le_bytes(self.take(8)?.try_into()?),
+            size => size as u64,
+        };
+        usize::try_from(size).context("Invalid transaction size")
+    }
+}
+
+/// Parses a `txid:output_index` outpoint.
+pub(crate) fn parse_outpoint(outpoint: &str) -> Result<lnrpc::OutPoint> {
+    let (txid, output_index) = outpoint
+        .split_once(':')
+        .with_context(|| format!("Invalid outpoint {}", outpoint))?;
+    Ok(lnrpc::OutPoint {
+        txid_bytes: txid_to_bytes(txid)?,
+        txid_str: txid.to_string(),
+        output_index: output_index
+            .parse()
+            .with_context(|| format!("Invalid outpoint {}", outpoint))?,
+    })
+}
+
+/// Formats an outpoint as `txid:output_index`.
+pub(crate) fn format_outpoint(outpoint: &lnrpc::OutPoint) -> String {
+    if outpoint.txid_str.is_empty() {
+        format!(
+            "{}:{}",
+            txid_to_string(&outpoint.txid_bytes),
+            outpoint.output_index
+        )
+    } else {
+        format!("{}:{}", outpoint.txid_str, outpoint.output_index)
+    }
+}
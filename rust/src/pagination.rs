@@ -0,0 +1,421 @@
+use crate::{closedChannels, getInfo, getTransactions, listInvoices, listPayments, LndClient};
+use anyhow::Result;
+use lnd_grpc_rust::lnrpc;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The default number of items fetched per call.
+const DEFAULT_PAGE_SIZE: u64 = 1000;
+
+/// The items of the current page, and whether more pages follow.
+pub(crate) struct Pages<T> {
+    items: VecDeque<T>,
+    done: bool,
+}
+
+impl<T> Pages<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            items: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the next item, calling `fetch` for pages until one has items or
+    /// `fetch` reports the last page. Iteration ends after the first error.
+    pub(crate) fn next(
+        &mut self,
+        mut fetch: impl FnMut() -> Result<(Vec<T>, bool)>,
+    ) -> Option<Result<T>> {
+        while self.items.is_empty() && !self.done {
+            match fetch() {
+                Ok((items, last)) => {
+                    self.done = last;
+                    self.items.extend(items);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.items.pop_front().map(Ok)
+    }
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Moves an index based cursor past a page.
+///
+/// # Returns
+///
+/// Whether the page was the last one.
+fn advance(
+    index_offset: &mut u64,
+    reversed: bool,
+    (first_index_offset, last_index_offset): (u64, u64),
+    count: usize,
+    page_size: u64,
+) -> bool {
+    if (count as u64) < page_size {
+        return true;
+    }
+    if reversed {
+        // Going backwards, the next page ends before the first index of this one.
+        let done =
+            first_index_offset <= 1 || (*index_offset != 0 && first_index_offset >= *index_offset);
+        *index_offset = first_index_offset;
+        done
+    } else {
+        let done = last_index_offset <= *index_offset;
+        *index_offset = last_index_offset;
+        done
+    }
+}
+
+/// Iterates over invoices with `listInvoices`, oldest first unless reversed.
+pub struct InvoiceList {
+    client: LndClient,
+    request: lnrpc::ListInvoiceRequest,
+    pages: Pages<lnrpc::Invoice>,
+}
+
+impl InvoiceList {
+    /// Creates an iterator over all invoices.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            request: lnrpc::ListInvoiceRequest {
+                num_max_invoices: DEFAULT_PAGE_SIZE,
+                ..Default::default()
+            },
+            pages: Pages::new(),
+        }
+    }
+
+    /// Sets how many invoices each call fetches.
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.request.num_max_invoices = page_size.max(1);
+        self
+    }
+
+    /// Iterates newest first.
+    pub fn reversed(mut self) -> Self {
+        self.request.reversed = true;
+        self
+    }
+
+    /// Only returns invoices that are still open or accepted.
+    pub fn pending_only(mut self) -> Self {
+        self.request.pending_only = true;
+        self
+    }
+
+    /// Only returns invoices created between `start` and `end`.
+    pub fn created_between(mut self, start: SystemTime, end: SystemTime) -> Self {
+        self.request.creation_date_start = unix_seconds(start);
+        self.request.creation_date_end = unix_seconds(end);
+        self
+    }
+}
+
+impl Iterator for InvoiceList {
+    type Item = Result<lnrpc::Invoice>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Self {
+            client,
+            request,
+            pages,
+        } = self;
+        pages.next(|| {
+            let response: lnrpc::ListInvoiceResponse =
+                client.call_lnd_method(request.clone(), listInvoices)?;
+            let last = advance(
+                &mut request.index_offset,
+                request.reversed,
+                (response.first_index_offset, response.last_index_offset),
+                response.invoices.len(),
+                request.num_max_invoices,
+            );
+            Ok((response.invoices, last))
+        })
+    }
+}
+
+/// Iterates over payments with `listPayments`, oldest first unless reversed.
+pub struct PaymentList {
+    client: LndClient,
+    request: lnrpc::ListPaymentsRequest,
+    pages: Pages<lnrpc::Payment>,
+}
+
+impl PaymentList {
+    /// Creates an iterator over all succeeded payments.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            request: lnrpc::ListPaymentsRequest {
+                max_payments: DEFAULT_PAGE_SIZE,
+                ..Default::default()
+            },
+            pages: Pages::new(),
+        }
+    }
+
+    /// Sets how many payments each call fetches.
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.request.max_payments = page_size.max(1);
+        self
+    }
+
+    /// Iterates newest first.
+    pub fn reversed(mut self) -> Self {
+        self.request.reversed = true;
+        self
+    }
+
+    /// Also returns payments that are in flight or failed.
+    pub fn include_incomplete(mut self) -> Self {
+        self.request.include_incomplete = true;
+        self
+    }
+
+    /// Only returns payments created between `start` and `end`.
+    pub fn created_between(mut self, start: SystemTime, end: SystemTime) -> Self {
+        self.request.creation_date_start = unix_seconds(start);
+        self.request.creation_date_end = unix_seconds(end);
+        self
+    }
+}
+
+impl Iterator for PaymentList {
+    type Item = Result<lnrpc::Payment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Self {
+            client,
+            request,
+            pages,
+        } = self;
+        pages.next(|| {
+            let response: lnrpc::ListPaymentsResponse =
+                client.call_lnd_method(request.clone(), listPayments)?;
+            let last = advance(
+                &mut request.index_offset,
+                request.reversed,
+                (response.first_index_offset, response.last_index_offset),
+                response.payments.len(),
+                request.max_payments,
+            );
+            Ok((response.payments, last))
+        })
+    }
+}
+
+/// Iterates over wallet transactions with `getTransactions`, a window of blocks per
+/// call, oldest first unless reversed. Unconfirmed transactions come after the
+/// confirmed ones.
+pub struct TransactionList {
+    client: LndClient,
+    account: String,
+    blocks_per_page: u32,
+    reversed: bool,
+    start_height: u32,
+    end_height: Option<u32>,
+    created: Option<(u64, u64)>,
+    /// The next window to fetch, once the chain tip is known.
+    cursor: Option<(u32, u32)>,
+    pages: Pages<lnrpc::Transaction>,
+}
+
+impl TransactionList {
+    /// Creates an iterator over all transactions.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            account: String::new(),
+            blocks_per_page: 10_000,
+            reversed: false,
+            start_height: 0,
+            end_height: None,
+            created: None,
+            cursor: None,
+            pages: Pages::new(),
+        }
+    }
+
+    /// Sets how many blocks each call covers.
+    pub fn page_size(mut self, blocks: u32) -> Self {
+        self.blocks_per_page = blocks.max(1);
+        self
+    }
+
+    /// Iterates newest first.
+    pub fn reversed(mut self) -> Self {
+        self.reversed = true;
+        self
+    }
+
+    /// Only returns transactions of one wallet account.
+    pub fn account(mut self, account: &str) -> Self {
+        self.account = account.to_string();
+        self
+    }
+
+    /// Only returns transactions confirmed from `start_height` to `end_height`, which
+    /// leaves out unconfirmed ones.
+    pub fn blocks(mut self, start_height: u32, end_height: u32) -> Self {
+        self.start_height = start_height;
+        self.end_height = Some(end_height);
+        self
+    }
+
+    /// Only returns transactions first seen between `start` and `end`.
+    pub fn created_between(mut self, start: SystemTime, end: SystemTime) -> Self {
+        self.created = Some((unix_seconds(start), unix_seconds(end)));
+        self
+    }
+
+    fn fetch_page(&mut self) -> Result<(Vec<lnrpc::Transaction>, bool)> {
+        let (low, high) = match self.cursor {
+            Some(cursor) => cursor,
+            None => {
+                let tip = match self.end_height {
+                    Some(end_height) => end_height,
+                    None => {
+                        let info: lnrpc::GetInfoResponse = self
+                            .client
+                            .call_lnd_method(lnrpc::GetInfoRequest {}, getInfo)?;
+                        info.block_height
+                    }
+                };
+                (self.start_height, tip.max(self.start_height))
+            }
+        };
+        let span = self.blocks_per_page - 1;
+        let (from, to) = if self.reversed {
+            (high.saturating_sub(span).max(low), high)
+        } else {
+            (low, low.saturating_add(span).min(high))
+        };
+        let last = if self.reversed {
+            from <= low
+        } else {
+            to >= high
+        };
+        // The window at the tip also takes the unconfirmed transactions, unless the
+        // heights were given.
+        let at_tip = to == high && self.end_height.is_none();
+
+        // lnd reads an end height of 0 as the unconfirmed tip, and no wallet
+        // transaction confirms in the genesis block, so a window ending there is not
+        // fetched.
+        let fetched = if to == 0 && !at_tip {
+            Vec::new()
+        } else {
+            let response: lnrpc::TransactionDetails = self.client.call_lnd_method(
+                lnrpc::GetTransactionsRequest {
+                    start_height: from as i32,
+                    end_height: if at_tip { -1 } else { to as i32 },
+                    account: self.account.clone(),
+                },
+                getTransactions,
+            )?;
+            response.transactions
+        };
+        self.cursor = Some(if self.reversed {
+            (low, from.saturating_sub(1))
+        } else {
+            (to + 1, high)
+        });
+
+        let mut transactions: Vec<lnrpc::Transaction> = fetched
+            .into_iter()
+            .filter(|tx| {
+                self.created.is_none_or(|(start, end)| {
+                    (start..=end).contains(&(tx.time_stamp.max(0) as u64))
+                })
+            })
+            .collect();
+        // Unconfirmed transactions have no height and go last.
+        transactions.sort_by_key(|tx| (tx.block_height <= 0, tx.block_height, tx.time_stamp));
+        if self.reversed {
+            transactions.reverse();
+        }
+        Ok((transactions, last))
+    }
+}
+
+impl Iterator for TransactionList {
+    type Item = Result<lnrpc::Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut pages = std::mem::replace(&mut self.pages, Pages::new());
+        let item = pages.next(|| self.fetch_page());
+        self.pages = pages;
+        item
+    }
+}
+
+/// Iterates over closed channels with `closedChannels`.
+///
+/// lnd returns all closed channels in one call, so they are fetched on the first
+/// `next` and the page size does not apply.
+pub struct ClosedChannelList {
+    client: LndClient,
+    request: lnrpc::ClosedChannelsRequest,
+    reversed: bool,
+    pages: Pages<lnrpc::ChannelCloseSummary>,
+}
+
+impl ClosedChannelList {
+    /// Creates an iterator over all closed channels, in the order they were closed.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            request: lnrpc::ClosedChannelsRequest::default(),
+            reversed: false,
+            pages: Pages::new(),
+        }
+    }
+
+    /// Iterates the most recently closed first.
+    pub fn reversed(mut self) -> Self {
+        self.reversed = true;
+        self
+    }
+
+    /// Only returns channels closed in one of the given ways. Without a filter, all
+    /// are returned.
+    pub fn only(mut self, filter: lnrpc::ClosedChannelsRequest) -> Self {
+        self.request = filter;
+        self
+    }
+}
+
+impl Iterator for ClosedChannelList {
+    type Item = Result<lnrpc::ChannelCloseSummary>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Self {
+            client,
+            request,
+            reversed,
+            pages,
+        } = self;
+        pages.next(|| {
+            let response: lnrpc::ClosedChannelsResponse =
+                client.call_lnd_method(request.clone(), closedChannels)?;
+            let mut channels = response.channels;
+            if *reversed {
+                channels.reverse();
+            }
+            Ok((channels, true))
+        })
+    }
+}
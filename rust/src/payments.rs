@@ -0,0 +1,507 @@
+use crate::{
+    decodePayReq, routerSendPaymentV2, routerTrackPaymentV2, LndClient, PaymentList, SubscriptionId,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc::payment::PaymentStatus as LndPaymentStatus;
+use lnd_grpc_rust::lnrpc::PaymentFailureReason;
+use lnd_grpc_rust::{lnrpc, routerrpc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many payments are requested per `listPayments` call when resuming.
+const PAGE_SIZE: u64 = 100;
+
+/// Limits for a single payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentLimits {
+    /// The amount to pay, for invoices that do not specify one.
+    pub amount_sat: Option<i64>,
+    /// The highest routing fee to pay, in satoshis.
+    pub max_fee_sat: i64,
+    /// The highest routing fee to pay, in parts per million of the amount.
+    pub max_fee_ppm: Option<u64>,
+    /// How long lnd may keep looking for a route.
+    pub timeout: Duration,
+}
+
+impl Default for PaymentLimits {
+    fn default() -> Self {
+        Self {
+            amount_sat: None,
+            max_fee_sat: 1_000,
+            max_fee_ppm: Some(10_000),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Upper bounds applied to the limits of every payment made through a `PaymentManager`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentPolicy {
+    /// The highest routing fee any payment may pay, in satoshis.
+    pub max_fee_sat: i64,
+    /// The highest routing fee any payment may pay, in parts per million of the amount.
+    pub max_fee_ppm: Option<u64>,
+    /// The longest time any payment may spend looking for a route.
+    pub max_timeout: Duration,
+}
+
+impl Default for PaymentPolicy {
+    fn default() -> Self {
+        Self {
+            max_fee_sat: i64::MAX,
+            max_fee_ppm: None,
+            max_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Why a payment failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureReason {
+    /// lnd ran out of time before a route succeeded.
+    Timeout,
+    /// No route to the destination was found, or all of them failed.
+    NoRoute,
+    /// A non-recoverable error occurred in lnd.
+    Error,
+    /// The destination rejected the payment hash, amount or CLTV delta.
+    IncorrectPaymentDetails,
+    /// The local channels do not have enough balance.
+    InsufficientBalance,
+    /// The payment was canceled.
+    Canceled,
+    /// lnd refused to start the payment.
+    Rejected(String),
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::Timeout => write!(f, "payment timed out"),
+            FailureReason::NoRoute => write!(f, "no route found"),
+            FailureReason::Error => write!(f, "non-recoverable error"),
+            FailureReason::IncorrectPaymentDetails => write!(f, "incorrect payment details"),
+            FailureReason::InsufficientBalance => write!(f, "insufficient balance"),
+            FailureReason::Canceled => write!(f, "payment canceled"),
+            FailureReason::Rejected(error) => write!(f, "payment rejected: {}", error),
+        }
+    }
+}
+
+impl From<PaymentFailureReason> for FailureReason {
+    fn from(reason: PaymentFailureReason) -> Self {
+        match reason {
+            PaymentFailureReason::FailureReasonTimeout => FailureReason::Timeout,
+            PaymentFailureReason::FailureReasonNoRoute => FailureReason::NoRoute,
+            PaymentFailureReason::FailureReasonIncorrectPaymentDetails => {
+                FailureReason::IncorrectPaymentDetails
+            }
+            PaymentFailureReason::FailureReasonInsufficientBalance => {
+                FailureReason::InsufficientBalance
+            }
+            PaymentFailureReason::FailureReasonCanceled => FailureReason::Canceled,
+            PaymentFailureReason::FailureReasonNone | PaymentFailureReason::FailureReasonError => {
+                FailureReason::Error
+            }
+        }
+    }
+}
+
+/// The state of a payment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentStatus {
+    /// lnd is still trying to complete the payment.
+    InFlight,
+    /// The payment completed.
+    Succeeded {
+        /// The hex encoded preimage, proof that the invoice was paid.
+        preimage: String,
+        /// The routing fee paid, in millisatoshis.
+        fee_msat: i64,
+    },
+    /// The payment failed and no funds are locked up anymore.
+    Failed(FailureReason),
+}
+
+impl PaymentStatus {
+    /// Returns `true` once the payment succeeded or failed.
+    pub fn is_final(&self) -> bool {
+        !matches!(self, PaymentStatus::InFlight)
+    }
+}
+
+impl From<&lnrpc::Payment> for PaymentStatus {
+    fn from(payment: &lnrpc::Payment) -> Self {
+        match payment.status() {
+            LndPaymentStatus::Succeeded => PaymentStatus::Succeeded {
+                preimage: payment.payment_preimage.clone(),
+                fee_msat: payment.fee_msat,
+            },
+            LndPaymentStatus::Failed => PaymentStatus::Failed(payment.failure_reason().into()),
+            LndPaymentStatus::Unknown
+            | LndPaymentStatus::InFlight
+            | LndPaymentStatus::Initiated => PaymentStatus::InFlight,
+        }
+    }
+}
+
+/// A payment followed by a `PaymentManager`.
+struct Tracked {
+    payment_hash: String,
+    status: Mutex<PaymentStatus>,
+    changed: Condvar,
+    /// Whether lnd reported on the payment at all.
+    updated: AtomicBool,
+    subscription: Mutex<Option<SubscriptionId>>,
+}
+
+impl Tracked {
+    fn new(payment_hash: &str) -> Arc<Self> {
+        Arc::new(Self {
+            payment_hash: payment_hash.to_string(),
+            status: Mutex::new(PaymentStatus::InFlight),
+            changed: Condvar::new(),
+            updated: AtomicBool::new(false),
+            subscription: Mutex::new(None),
+        })
+    }
+
+    fn status(&self) -> PaymentStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set(&self, status: PaymentStatus) {
+        *self.status.lock().unwrap_or_else(PoisonError::into_inner) = status;
+        self.changed.notify_all();
+    }
+
+    fn apply(&self, client: &LndClient, payment: &lnrpc::Payment) {
+        self.updated.store(true, Ordering::Release);
+        self.set(payment.into());
+        self.release(client);
+    }
+
+    /// Drops the stream of a payment that reached its final state.
+    fn release(&self, client: &LndClient) {
+        if self.status().is_final() {
+            let subscription = self
+                .subscription
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            if let Some(id) = subscription {
+                client.unsubscribe(id);
+            }
+        }
+    }
+
+    fn handle(self: &Arc<Self>) -> PaymentHandle {
+        PaymentHandle {
+            tracked: self.clone(),
+        }
+    }
+}
+
+/// A handle to a payment made or resumed through a `PaymentManager`.
+#[derive(Clone)]
+pub struct PaymentHandle {
+    tracked: Arc<Tracked>,
+}
+
+impl PaymentHandle {
+    /// Returns the hex encoded payment hash.
+    pub fn payment_hash(&self) -> &str {
+        &self.tracked.payment_hash
+    }
+
+    /// Returns the latest known state of the payment.
+    pub fn status(&self) -> PaymentStatus {
+        self.tracked.status()
+    }
+
+    /// Waits until the payment succeeded or failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait.
+    ///
+    /// # Returns
+    ///
+    /// The final state, or an error if the payment is still in flight after `timeout`.
+    pub fn wait(&self, timeout: Duration) -> Result<PaymentStatus> {
+        let status = self
+            .tracked
+            .status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (status, _) = self
+            .tracked
+            .changed
+            .wait_timeout_while(status, timeout, |status| !status.is_final())
+            .unwrap_or_else(PoisonError::into_inner);
+        if status.is_final() {
+            Ok(status.clone())
+        } else {
+            Err(anyhow::anyhow!(
+                "Payment {} is still in flight",
+                self.tracked.payment_hash
+            ))
+        }
+    }
+}
+
+/// Pays invoices and keeps track of the resulting payments.
+///
+/// Payments are keyed by payment hash, so paying the same invoice twice returns the
+/// handle of the first payment unless it failed. lnd keeps payments across restarts,
+/// and `resume` picks up the ones that were still in flight.
+#[derive(Clone)]
+pub struct PaymentManager {
+    client: LndClient,
+    policy: PaymentPolicy,
+    payments: Arc<Mutex<HashMap<String, Arc<Tracked>>>>,
+}
+
+impl PaymentManager {
+    /// Creates a payment manager with the default policy.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            policy: PaymentPolicy::default(),
+            payments: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sets the bounds applied to the limits of every payment. Limits above them are
+    /// lowered to the policy.
+    pub fn with_policy(mut self, policy: PaymentPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns the payment with the given hex encoded hash, if it is known.
+    pub fn get(&self, payment_hash: &str) -> Option<PaymentHandle> {
+        self.payments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(payment_hash)
+            .map(Tracked::handle)
+    }
+
+    /// Pays a BOLT11 invoice.
+    ///
+    /// # Arguments
+    ///
+    /// * `invoice` - The BOLT11 payment request.
+    /// * `limits` - Fee and timeout limits, bounded by the manager's policy.
+    ///
+    /// # Returns
+    ///
+    /// A handle to follow the payment. If the invoice is already being paid or was paid,
+    /// the existing payment is returned instead of starting another one.
+    pub fn pay(&self, invoice: &str, limits: PaymentLimits) -> Result<PaymentHandle> {
+        let pay_req: lnrpc::PayReq = self
+            .client
+            .call_lnd_method(
+                lnrpc::PayReqString {
+                    pay_req: invoice.to_string(),
+                },
+                decodePayReq,
+            )
+            .context("Failed to decode invoice")?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        if pay_req.expiry > 0 && pay_req.timestamp + pay_req.expiry < now {
+            anyhow::bail!("Invoice {} has expired", pay_req.payment_hash);
+        }
+
+        let amount_msat = match (pay_req.num_msat, limits.amount_sat) {
+            (0, Some(amount_sat)) => amount_sat
+                .checked_mul(1000)
+                .context("Payment amount is too large")?,
+            (0, None) => anyhow::bail!("Invoice has no amount and no amount was given"),
+            (num_msat, _) => num_msat,
+        };
+
+        let tracked = {
+            let mut payments = self.payments.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(tracked) = payments.get(&pay_req.payment_hash) {
+                if !matches!(tracked.status(), PaymentStatus::Failed(_)) {
+                    return Ok(tracked.handle());
+                }
+            }
+            let tracked = Tracked::new(&pay_req.payment_hash);
+            payments.insert(pay_req.payment_hash.clone(), tracked.clone());
+            tracked
+        };
+
+        let request = routerrpc::SendPaymentRequest {
+            payment_request: invoice.to_string(),
+            amt: if pay_req.num_msat == 0 {
+                amount_msat / 1000
+            } else {
+                0
+            },
+            fee_limit_msat: self.fee_limit_msat(&limits, amount_msat),
+            timeout_seconds: limits.timeout.min(self.policy.max_timeout).as_secs().max(1) as i32,
+            ..Default::default()
+        };
+
+        let client = self.client.downgrade();
+        let stream = tracked.clone();
+        let result = self
+            .client
+            .subscribe_events::<lnrpc::Payment, routerrpc::SendPaymentRequest>(routerSendPaymentV2)
+            .on_event(move |event| {
+                if let Some(client) = client.upgrade() {
+                    on_send_event(&client, &stream, event);
+                }
+            })
+            .with_request(request)
+            .subscribe();
+        self.attach(&tracked, result)?;
+
+        Ok(tracked.handle())
+    }
+
+    /// Resumes tracking of all payments lnd still has in flight, e.g. after a restart.
+    ///
+    /// # Returns
+    ///
+    /// Handles to the resumed payments.
+    pub fn resume(&self) -> Result<Vec<PaymentHandle>> {
+        let mut resumed = Vec::new();
+        let payments = PaymentList::new(self.client.clone())
+            .page_size(PAGE_SIZE)
+            .include_incomplete();
+        for payment in payments {
+            let payment = payment?;
+            if PaymentStatus::from(&payment).is_final() {
+                continue;
+            }
+            let tracked = {
+                let mut payments = self.payments.lock().unwrap_or_else(PoisonError::into_inner);
+                if payments.contains_key(&payment.payment_hash) {
+                    continue;
+                }
+                let tracked = Tracked::new(&payment.payment_hash);
+                payments.insert(payment.payment_hash.clone(), tracked.clone());
+                tracked
+            };
+            self.attach(&tracked, track(&self.client, &tracked))?;
+            resumed.push(tracked.handle());
+        }
+        Ok(resumed)
+    }
+
+    /// Returns the fee limit in millisatoshis, so that ppm limits on small payments are
+    /// not rounded down to zero satoshis.
+    fn fee_limit_msat(&self, limits: &PaymentLimits, amount_msat: i64) -> i64 {
+        let ppm_limit = |ppm: Option<u64>| {
+            ppm.map_or(i64::MAX, |ppm| {
+                (amount_msat as i128 * ppm as i128 / 1_000_000).min(i64::MAX as i128) as i64
+            })
+        };
+        limits
+            .max_fee_sat
+            .min(self.policy.max_fee_sat)
+            .saturating_mul(1000)
+            .min(ppm_limit(limits.max_fee_ppm))
+            .min(ppm_limit(self.policy.max_fee_ppm))
+            .max(0)
+    }
+
+    /// Records the stream of a payment, or marks the payment failed if it could not be
+    /// opened.
+    fn attach(&self, tracked: &Tracked, result: Result<SubscriptionId>) -> Result<()> {
+        match result {
+            Ok(id) => {
+                *tracked
+                    .subscription
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(id);
+                // The payment may have completed before the id was known.
+                tracked.release(&self.client);
+                Ok(())
+            }
+            Err(error) => {
+                tracked.set(PaymentStatus::Failed(FailureReason::Rejected(
+                    error.to_string(),
+                )));
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Follows an existing payment with `routerTrackPaymentV2`.
+fn track(client: &LndClient, tracked: &Arc<Tracked>) -> Result<SubscriptionId> {
+    let request = routerrpc::TrackPaymentRequest {
+        payment_hash: hex::decode(&tracked.payment_hash).context("Invalid payment hash")?,
+        no_inflight_updates: false,
+    };
+    let stream = tracked.clone();
+    let stream_client = client.downgrade();
+    let id = client
+        .subscribe_events::<lnrpc::Payment, routerrpc::TrackPaymentRequest>(routerTrackPaymentV2)
+        .on_event(move |event| {
+            // A broken tracking stream leaves the payment in flight until it is resumed.
+            if let (Ok(payment), Some(client)) = (event, stream_client.upgrade()) {
+                stream.apply(&client, &payment);
+            }
+        })
+        .with_request(request)
+        .subscribe()?;
+    let previous = tracked
+        .subscription
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace(id);
+    if let Some(previous) = previous {
+        client.unsubscribe(previous);
+    }
+    Ok(id)
+}
+
+/// Handles an update from the `routerSendPaymentV2` stream of a payment.
+fn on_send_event(
+    client: &LndClient,
+    tracked: &Arc<Tracked>,
+    event: Result<lnrpc::Payment, String>,
+) {
+    let error = match event {
+        Ok(payment) => return tracked.apply(client, &payment),
+        // lnd ends the stream after the final update.
+        Err(_) if tracked.status().is_final() => return,
+        Err(error) => error,
+    };
+
+    if !tracked.updated.load(Ordering::Acquire) && !is_already_known(&error) {
+        tracked.set(PaymentStatus::Failed(FailureReason::Rejected(error)));
+        return;
+    }
+    // The payment was started before, e.g. by an earlier run of the app, or the stream
+    // broke while it was in flight.
+    if let Err(error) = track(client, tracked) {
+        tracked.set(PaymentStatus::Failed(FailureReason::Rejected(
+            error.to_string(),
+        )));
+    }
+}
+
+/// Returns `true` for the errors lnd reports when a payment to the same hash exists.
+fn is_already_known(error: &str) -> bool {
+    ["already paid", "in transition", "already exists"]
+        .iter()
+        .any(|known| error.contains(known))
+}
@@ -0,0 +1,283 @@
+use crate::pagination::unix_seconds;
+use crate::rebalancer::failed_pair;
+use crate::{
+    getInfo, queryRoutes, routerEstimateRouteFee, routerQueryProbability, routerSendToRouteV2,
+    routerXImportMissionControl, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc::failure::FailureCode;
+use lnd_grpc_rust::lnrpc::htlc_attempt::HtlcStatus;
+use lnd_grpc_rust::{lnrpc, routerrpc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+type Cache = HashMap<(String, i64), (Instant, FeeEstimate)>;
+
+/// How a probe payment ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    /// The destination rejected the unknown payment hash, so the route can carry the
+    /// amount.
+    Reached,
+    /// A node before the destination failed the probe.
+    Failed {
+        /// Why it failed.
+        code: FailureCode,
+        /// The position on the route of the node that failed it, 0 being us.
+        source_index: u32,
+    },
+}
+
+/// What it costs to pay a destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeEstimate {
+    /// The destination node.
+    pub dest: String,
+    /// The amount, in satoshis.
+    pub amount_sat: i64,
+    /// The lowest of the estimated fees, in millisatoshis, or 0 if no route was found.
+    pub min_fee_msat: i64,
+    /// The highest of the estimated fees, in millisatoshis, or 0 if no route was found.
+    pub max_fee_msat: i64,
+    /// The time lock the payment needs, in blocks, or 0 if no route was found.
+    pub time_lock_delay: i64,
+    /// The success probability of the queried route according to mission control,
+    /// from 0 to 1. It is 0 if no route was found.
+    pub probability: f64,
+    /// The probe payment, if one was sent.
+    pub probe: Option<ProbeOutcome>,
+    /// When the estimate was made.
+    pub estimated_at: SystemTime,
+}
+
+/// Estimates routing fees and success probabilities, optionally confirming them with
+/// probe payments that cannot settle.
+///
+/// Estimates are cached per destination and amount. Probe failures are imported into
+/// mission control, so later payments avoid the failing channels.
+#[derive(Clone)]
+pub struct Prober {
+    client: LndClient,
+    probe: bool,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+    own_pubkey: Arc<Mutex<Option<String>>>,
+}
+
+impl Prober {
+    /// Creates a prober that sends probes and caches estimates for 10 minutes.
+    pub fn new(client: LndClient) -> Self {
+        Self {
+            client,
+            probe: true,
+            cache_ttl: Duration::from_secs(10 * 60),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            own_pubkey: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets whether probe payments are sent. Without them, estimates only use the
+    /// graph and mission control.
+    pub fn with_probing(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Sets how long estimates are cached.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Drops all cached estimates.
+    pub fn clear_cache(&self) {
+        self.cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Estimates the fee range and success probability of paying `amount_sat` to
+    /// `dest`, returning a cached estimate if there is a recent one.
+    ///
+    /// The range covers lnd's `routerEstimateRouteFee` estimate and the route found
+    /// by `queryRoutes`. If probing is on, a payment with a random hash is sent along
+    /// that route.
+    pub fn estimate(&self, dest: &str, amount_sat: i64) -> Result<FeeEstimate> {
+        let key = (dest.to_string(), amount_sat);
+        if let Some((at, estimate)) = self
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            if at.elapsed() < self.cache_ttl {
+                return Ok(estimate.clone());
+            }
+        }
+
+        let dest_bytes = hex::decode(dest).context("Invalid destination pubkey")?;
+        let fee: Result<routerrpc::RouteFeeResponse> = self.client.call_lnd_method(
+            routerrpc::RouteFeeRequest {
+                dest: dest_bytes,
+                amt_sat: amount_sat,
+                ..Default::default()
+            },
+            routerEstimateRouteFee,
+        );
+        let (fee, found) = match fee {
+            Ok(fee) => (fee, true),
+            // lnd reports a missing route as an error.
+            Err(e) if e.to_string().contains("unable to find a path") => {
+                (routerrpc::RouteFeeResponse::default(), false)
+            }
+            Err(e) => return Err(e),
+        };
+        let mut estimate = FeeEstimate {
+            dest: dest.to_string(),
+            amount_sat,
+            min_fee_msat: fee.routing_fee_msat,
+            max_fee_msat: fee.routing_fee_msat,
+            time_lock_delay: fee.time_lock_delay,
+            probability: 0.0,
+            probe: None,
+            estimated_at: SystemTime::now(),
+        };
+
+        let route = if found {
+            self.query_route(dest, amount_sat)?
+        } else {
+            None
+        };
+        if let Some(route) = route {
+            estimate.min_fee_msat = estimate.min_fee_msat.min(route.total_fees_msat);
+            estimate.max_fee_msat = estimate.max_fee_msat.max(route.total_fees_msat);
+            let own_pubkey = self.own_pubkey()?;
+            estimate.probability = self.route_probability(&route, &own_pubkey)?;
+            if self.probe {
+                let outcome = self.send_probe(&route)?;
+                if let ProbeOutcome::Failed { source_index, .. } = &outcome {
+                    self.import_failure(&route, *source_index, &own_pubkey)?;
+                }
+                estimate.probe = Some(outcome);
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.retain(|_, (at, _)| at.elapsed() < self.cache_ttl);
+        cache.insert(key, (Instant::now(), estimate.clone()));
+        Ok(estimate)
+    }
+
+    fn own_pubkey(&self) -> Result<String> {
+        let mut own_pubkey = self
+            .own_pubkey
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(pubkey) = own_pubkey.as_ref() {
+            return Ok(pubkey.clone());
+        }
+        let info: lnrpc::GetInfoResponse = self
+            .client
+            .call_lnd_method(lnrpc::GetInfoRequest {}, getInfo)?;
+        *own_pubkey = Some(info.identity_pubkey.clone());
+        Ok(info.identity_pubkey)
+    }
+
+    fn query_route(&self, dest: &str, amount_sat: i64) -> Result<Option<lnrpc::Route>> {
+        let response: Result<lnrpc::QueryRoutesResponse> = self.client.call_lnd_method(
+            lnrpc::QueryRoutesRequest {
+                pub_key: dest.to_string(),
+                amt: amount_sat,
+                use_mission_control: true,
+                ..Default::default()
+            },
+            queryRoutes,
+        );
+        match response {
+            Ok(response) => Ok(response.routes.into_iter().next()),
+            // lnd reports a missing route as an error.
+            Err(e) if e.to_string().contains("unable to find a path") => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Multiplies the mission control probabilities of each channel on the route.
+    fn route_probability(&self, route: &lnrpc::Route, own_pubkey: &str) -> Result<f64> {
+        let mut probability = 1.0;
+        let mut from = own_pubkey;
+        for hop in &route.hops {
+            let response: routerrpc::QueryProbabilityResponse = self.client.call_lnd_method(
+                routerrpc::QueryProbabilityRequest {
+                    from_node: hex::decode(from).context("Invalid pubkey in route")?,
+                    to_node: hex::decode(&hop.pub_key).context("Invalid pubkey in route")?,
+                    amt_msat: hop.amt_to_forward_msat + hop.fee_msat,
+                },
+                routerQueryProbability,
+            )?;
+            probability *= response.probability;
+            from = &hop.pub_key;
+        }
+        Ok(probability)
+    }
+
+    fn send_probe(&self, route: &lnrpc::Route) -> Result<ProbeOutcome> {
+        let mut payment_hash = vec![0; 32];
+        openssl::rand::rand_bytes(&mut payment_hash).context("Failed to generate a hash")?;
+        let attempt: lnrpc::HtlcAttempt = self.client.call_lnd_method(
+            routerrpc::SendToRouteRequest {
+                payment_hash,
+                route: Some(route.clone()),
+                skip_temp_err: true,
+            },
+            routerSendToRouteV2,
+        )?;
+        if attempt.status() == HtlcStatus::Succeeded {
+            // Nobody knows the preimage of a random hash.
+            anyhow::bail!("Probe payment unexpectedly succeeded");
+        }
+        let failure = attempt.failure.unwrap_or_default();
+        let reached = failure.failure_source_index as usize == route.hops.len()
+            && failure.code() == FailureCode::IncorrectOrUnknownPaymentDetails;
+        Ok(if reached {
+            ProbeOutcome::Reached
+        } else {
+            ProbeOutcome::Failed {
+                code: failure.code(),
+                source_index: failure.failure_source_index,
+            }
+        })
+    }
+
+    fn import_failure(
+        &self,
+        route: &lnrpc::Route,
+        source_index: u32,
+        own_pubkey: &str,
+    ) -> Result<()> {
+        // A failure at the destination says nothing about a channel.
+        let Some(hop) = route.hops.get(source_index as usize) else {
+            return Ok(());
+        };
+        let pair = failed_pair(route, source_index, own_pubkey)?;
+        let amt_msat = hop.amt_to_forward_msat + hop.fee_msat;
+        let _: routerrpc::XImportMissionControlResponse = self.client.call_lnd_method(
+            routerrpc::XImportMissionControlRequest {
+                pairs: vec![routerrpc::PairHistory {
+                    node_from: pair.from,
+                    node_to: pair.to,
+                    history: Some(routerrpc::PairData {
+                        fail_time: unix_seconds(SystemTime::now()) as i64,
+                        fail_amt_sat: amt_msat / 1000,
+                        fail_amt_msat: amt_msat,
+                        ..Default::default()
+                    }),
+                }],
+                force: false,
+            },
+            routerXImportMissionControl,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+use crate::channels::{ChannelHandle, ChannelManager};
+use crate::{
+    batchOpenChannel, fundingStateStep, walletKitFundPsbt, walletKitReleaseOutput,
+    walletKitSignPsbt, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc::funding_shim::Shim;
+use lnd_grpc_rust::lnrpc::funding_transition_msg::Trigger;
+use lnd_grpc_rust::walletrpc::fund_psbt_request::{Fees, Template};
+use lnd_grpc_rust::{lnrpc, walletrpc};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// How the fee of a transaction built by lnd's wallet is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePreference {
+    /// A fixed fee rate in sat/vbyte.
+    SatPerVbyte(u64),
+    /// The fee rate estimated to confirm within the given number of blocks.
+    TargetConf(u32),
+}
+
+impl FeePreference {
+    pub(crate) fn to_fees(self) -> Fees {
+        match self {
+            FeePreference::SatPerVbyte(rate) => Fees::SatPerVbyte(rate),
+            FeePreference::TargetConf(blocks) => Fees::TargetConf(blocks),
+        }
+    }
+}
+
+/// An output the funding transaction has to pay for one of the channels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FundingOutput {
+    /// The P2WSH address of the channel's funding output.
+    pub address: String,
+    /// The exact amount to send to `address`, in satoshis.
+    pub amount_sat: i64,
+}
+
+struct PendingOpen {
+    pending_chan_id: Vec<u8>,
+    channel: ChannelHandle,
+}
+
+/// Opens channels funded by a PSBT instead of lnd's wallet.
+///
+/// Each open is started with a PSBT funding shim and suspended by lnd until the funding
+/// transaction is provided through `fundingStateStep`. Several channels can be funded by
+/// a single transaction with `start_batch`.
+#[derive(Clone)]
+pub struct PsbtChannelFunding {
+    channels: ChannelManager,
+}
+
+impl PsbtChannelFunding {
+    /// Creates a PSBT funding flow whose channels are followed by `channels`.
+    pub fn new(channels: ChannelManager) -> Self {
+        Self { channels }
+    }
+
+    /// Starts opening a channel funded by a PSBT.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The channel to open. Any `funding_shim` is replaced.
+    /// * `timeout` - How long to wait for the peer to accept the channel.
+    pub fn start(
+        &self,
+        request: lnrpc::OpenChannelRequest,
+        timeout: Duration,
+    ) -> Result<PsbtFunding> {
+        self.start_batch(vec![request], timeout)
+    }
+
+    /// Starts opening several channels funded by the same PSBT.
+    ///
+    /// The channels are negotiated one after the other, each adding its output to the
+    /// PSBT of the previous one. Only the last channel publishes the transaction, once
+    /// every channel was finalized.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The channels to open. Any `funding_shim` is replaced.
+    /// * `timeout` - How long to wait for each peer to accept its channel.
+    pub fn start_batch(
+        &self,
+        requests: Vec<lnrpc::OpenChannelRequest>,
+        timeout: Duration,
+    ) -> Result<PsbtFunding> {
+        if requests.is_empty() {
+            return Err(anyhow::anyhow!("No channels to open"));
+        }
+
+        // Dropping `funding` on an error cancels the channels started so far.
+        let mut funding = PsbtFunding {
+            client: self.channels.client().clone(),
+            opens: Vec::new(),
+            outputs: Vec::new(),
+            template: Vec::new(),
+            leases: Vec::new(),
+            verified: false,
+            done: false,
+        };
+        let count = requests.len();
+        for (index, mut request) in requests.into_iter().enumerate() {
+            let mut pending_chan_id = vec![0u8; 32];
+            openssl::rand::rand_bytes(&mut pending_chan_id)
+                .context("Failed to generate pending channel id")?;
+            request.funding_shim = Some(lnrpc::FundingShim {
+                shim: Some(Shim::PsbtShim(lnrpc::PsbtShim {
+                    pending_chan_id: pending_chan_id.clone(),
+                    base_psbt: funding.template.clone(),
+                    no_publish: index + 1 < count,
+                })),
+            });
+
+            let (sender, receiver) = mpsc::channel();
+            let sender = Mutex::new(sender);
+            let channel = self.channels.open_with(
+                request,
+                Some(Box::new(move |ready| {
+                    let _ = sender
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .send(ready);
+                })),
+            )?;
+            funding.opens.push(PendingOpen {
+                pending_chan_id,
+                channel,
+            });
+
+            let ready = match receiver.recv_timeout(timeout) {
+                Ok(ready) => ready.map_err(|e| anyhow::anyhow!(e))?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(anyhow::anyhow!(
+                        "Timed out waiting for the peer to accept the channel"
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow::anyhow!("Channel open ended before PSBT funding"))
+                }
+            };
+            funding.outputs.push(FundingOutput {
+                address: ready.funding_address,
+                amount_sat: ready.funding_amount,
+            });
+            funding.template = ready.psbt;
+        }
+        Ok(funding)
+    }
+
+    /// Opens several channels in one transaction funded by lnd's wallet, through
+    /// `batchOpenChannel`.
+    ///
+    /// # Returns
+    ///
+    /// Handles to the opened channels, already `Pending`.
+    pub fn batch_open(
+        &self,
+        request: lnrpc::BatchOpenChannelRequest,
+    ) -> Result<Vec<ChannelHandle>> {
+        let response: lnrpc::BatchOpenChannelResponse = self
+            .channels
+            .client()
+            .call_lnd_method(request, batchOpenChannel)?;
+        response
+            .pending_channels
+            .iter()
+            .map(|pending| self.channels.track_pending(pending))
+            .collect()
+    }
+}
+
+/// Channel opens waiting for their funding transaction.
+///
+/// Either `finish` or `cancel` it. If it is dropped without either, or if finishing
+/// fails, the opens are canceled and any UTXOs leased by `fund_with_wallet` are released.
+pub struct PsbtFunding {
+    client: LndClient,
+    opens: Vec<PendingOpen>,
+    outputs: Vec<FundingOutput>,
+    template: Vec<u8>,
+    leases: Vec<walletrpc::UtxoLease>,
+    verified: bool,
+    done: bool,
+}
+
+impl PsbtFunding {
+    /// Returns the PSBT that contains the funding outputs of all channels, to be funded.
+    pub fn template(&self) -> &[u8] {
+        &self.template
+    }
+
+    /// Returns the funding outputs, in the order the channels were started.
+    pub fn outputs(&self) -> &[FundingOutput] {
+        &self.outputs
+    }
+
+    /// Returns handles to the channels, in the order they were started.
+    pub fn channels(&self) -> Vec<ChannelHandle> {
+        self.opens.iter().map(|open| open.channel.clone()).collect()
+    }
+
+    /// Adds inputs and change from lnd's wallet to the template with `walletKitFundPsbt`
+    /// and verifies the result.
+    ///
+    /// # Returns
+    ///
+    /// The funded, unsigned PSBT.
+    pub fn fund_with_wallet(&mut self, fee: FeePreference) -> Result<Vec<u8>> {
+        let response: walletrpc::FundPsbtResponse = self.client.call_lnd_method(
+            walletrpc::FundPsbtRequest {
+                template: Some(Template::Psbt(self.template.clone())),
+                fees: Some(fee.to_fees()),
+                ..Default::default()
+            },
+            walletKitFundPsbt,
+        )?;
+        self.leases.extend(response.locked_utxos);
+        self.verify(&response.funded_psbt)?;
+        Ok(response.funded_psbt)
+    }
+
+    /// Signs the inputs of `funded_psbt` that belong to lnd's wallet with
+    /// `walletKitSignPsbt`.
+    pub fn sign_with_wallet(&self, funded_psbt: &[u8]) -> Result<Vec<u8>> {
+        let response: walletrpc::SignPsbtResponse = self.client.call_lnd_method(
+            walletrpc::SignPsbtRequest {
+                funded_psbt: funded_psbt.to_vec(),
+            },
+            walletKitSignPsbt,
+        )?;
+        Ok(response.signed_psbt)
+    }
+
+    /// Checks with every channel that `funded_psbt` pays its funding output.
+    ///
+    /// Funding should be verified before signing. `finish` verifies if this was not done.
+    pub fn verify(&mut self, funded_psbt: &[u8]) -> Result<()> {
+        for open in &self.opens {
+            self.step(Trigger::PsbtVerify(lnrpc::FundingPsbtVerify {
+                funded_psbt: funded_psbt.to_vec(),
+                pending_chan_id: open.pending_chan_id.clone(),
+                skip_finalize: false,
+            }))
+            .context("Failed to verify funding PSBT")?;
+        }
+        self.verified = true;
+        Ok(())
+    }
+
+    /// Hands the signed funding transaction to every channel, which publishes it.
+    ///
+    /// # Returns
+    ///
+    /// Handles to the channels, or an error after canceling them.
+    pub fn finish(mut self, signed_psbt: Vec<u8>) -> Result<Vec<ChannelHandle>> {
+        match self.try_finish(&signed_psbt) {
+            Ok(()) => {
+                self.done = true;
+                Ok(self.channels())
+            }
+            Err(e) => {
+                let _ = self.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// Funds, verifies and signs with lnd's wallet, then finishes.
+    pub fn fund_and_finish(mut self, fee: FeePreference) -> Result<Vec<ChannelHandle>> {
+        let signed = self
+            .fund_with_wallet(fee)
+            .and_then(|funded| self.sign_with_wallet(&funded));
+        match signed {
+            Ok(signed) => self.finish(signed),
+            Err(e) => {
+                let _ = self.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// Cancels the opens and releases any UTXOs leased by `fund_with_wallet`.
+    pub fn cancel(mut self) -> Result<()> {
+        self.abort()
+    }
+
+    fn try_finish(&mut self, signed_psbt: &[u8]) -> Result<()> {
+        if !self.verified {
+            self.verify(signed_psbt)?;
+        }
+        // Channels started with `no_publish` come first, so the transaction is only
+        // published once all of them accepted it.
+        for open in &self.opens {
+            self.step(Trigger::PsbtFinalize(lnrpc::FundingPsbtFinalize {
+                signed_psbt: signed_psbt.to_vec(),
+                pending_chan_id: open.pending_chan_id.clone(),
+                ..Default::default()
+            }))
+            .context("Failed to finalize funding PSBT")?;
+        }
+        Ok(())
+    }
+
+    /// Cancels everything, returning the first error but attempting every step.
+    fn abort(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+
+        let mut result = Ok(());
+        for open in &self.opens {
+            let canceled = self.step(Trigger::ShimCancel(lnrpc::FundingShimCancel {
+                pending_chan_id: open.pending_chan_id.clone(),
+            }));
+            if result.is_ok() {
+                result = canceled.context("Failed to cancel channel funding");
+            }
+        }
+        for lease in self.leases.drain(..) {
+            let released: Result<walletrpc::ReleaseOutputResponse> = self.client.call_lnd_method(
+                walletrpc::ReleaseOutputRequest {
+                    id: lease.id,
+                    outpoint: lease.outpoint,
+                },
+                walletKitReleaseOutput,
+            );
+            if result.is_ok() {
+                result = released
+                    .map(|_| ())
+                    .context("Failed to release funding input");
+            }
+        }
+        result
+    }
+
+    fn step(&self, trigger: Trigger) -> Result<()> {
+        let _: lnrpc::FundingStateStepResp = self.client.call_lnd_method(
+            lnrpc::FundingTransitionMsg {
+                trigger: Some(trigger),
+            },
+            fundingStateStep,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for PsbtFunding {
+    fn drop(&mut self) {
+        let _ = self.abort();
+    }
+}
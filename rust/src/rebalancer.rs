@@ -0,0 +1,319 @@
+use crate::{
+    addInvoice, getInfo, listChannels, queryRoutes, routerBuildRoute, routerSendToRouteV2,
+    LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::lnrpc::failure::FailureCode;
+use lnd_grpc_rust::lnrpc::fee_limit::Limit;
+use lnd_grpc_rust::lnrpc::htlc_attempt::HtlcStatus;
+use lnd_grpc_rust::{lnrpc, routerrpc};
+
+/// The CLTV delta of the final hop, which is our own node.
+const FINAL_CLTV_DELTA: i32 = 40;
+
+/// How a `Rebalancer` finds routes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteSource {
+    /// Asks `queryRoutes` for a route, excluding the node pairs that failed earlier
+    /// attempts.
+    Query,
+    /// Builds each route with `routerBuildRoute` through the given nodes, after the
+    /// outgoing channel's peer and before our own node. The candidates are tried in
+    /// order.
+    Hops(Vec<Vec<String>>),
+}
+
+/// A circular payment moving funds from one of our channels to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebalanceRequest {
+    /// The channel to move funds out of.
+    pub outgoing_chan_id: u64,
+    /// The peer of the channel to move funds into. lnd picks which of our channels
+    /// with the peer the payment arrives on.
+    pub last_hop_pubkey: String,
+    /// The amount to move, in satoshis.
+    pub amount_sat: i64,
+    /// The most to pay in routing fees, in millisatoshis.
+    pub max_fee_msat: i64,
+    /// How many routes to try.
+    pub max_attempts: usize,
+    /// Where the routes come from.
+    pub routes: RouteSource,
+}
+
+/// How an attempt ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    /// The funds were moved.
+    Succeeded,
+    /// A node on the route failed the payment.
+    Failed {
+        /// Why it failed.
+        code: FailureCode,
+        /// The position on the route of the node that failed it, 0 being us.
+        source_index: u32,
+    },
+    /// The route was not tried because its fees were above the cap.
+    TooExpensive,
+}
+
+/// A route tried by a `Rebalancer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebalanceAttempt {
+    /// The attempt number, starting at 1.
+    pub number: usize,
+    /// The channels of the route.
+    pub chan_ids: Vec<u64>,
+    /// The routing fees of the route, in millisatoshis.
+    pub fee_msat: i64,
+    /// How it ended.
+    pub outcome: AttemptOutcome,
+}
+
+/// The result of `Rebalancer::rebalance`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebalanceReport {
+    /// Whether the funds were moved.
+    pub succeeded: bool,
+    /// The fees paid, in millisatoshis, if it succeeded.
+    pub fee_msat: Option<i64>,
+    /// Every attempt, in order.
+    pub attempts: Vec<RebalanceAttempt>,
+}
+
+/// Moves funds between our own channels by paying ourselves.
+#[derive(Clone)]
+pub struct Rebalancer {
+    client: LndClient,
+}
+
+impl Rebalancer {
+    /// Creates a rebalancer.
+    pub fn new(client: LndClient) -> Self {
+        Self { client }
+    }
+
+    /// Pays a self-invoice out of `outgoing_chan_id` and back in from
+    /// `last_hop_pubkey`, trying routes until one succeeds or `max_attempts` are used.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - What to move and how.
+    /// * `on_attempt` - Called after each attempt.
+    ///
+    /// # Returns
+    ///
+    /// Every attempt made. Running out of routes is not an error.
+    pub fn rebalance<F>(&self, request: &RebalanceRequest, on_attempt: F) -> Result<RebalanceReport>
+    where
+        F: Fn(&RebalanceAttempt),
+    {
+        let info: lnrpc::GetInfoResponse = self
+            .client
+            .call_lnd_method(lnrpc::GetInfoRequest {}, getInfo)?;
+        let own_pubkey = info.identity_pubkey;
+        let amt_msat = request.amount_sat * 1000;
+        let invoice: lnrpc::AddInvoiceResponse = self.client.call_lnd_method(
+            lnrpc::Invoice {
+                memo: format!("Rebalance from {}", request.outgoing_chan_id),
+                value_msat: amt_msat,
+                ..Default::default()
+            },
+            addInvoice,
+        )?;
+
+        let mut report = RebalanceReport {
+            succeeded: false,
+            fee_msat: None,
+            attempts: Vec::new(),
+        };
+        let mut ignored_pairs = Vec::new();
+        for number in 1..=request.max_attempts {
+            let route = match &request.routes {
+                RouteSource::Query => {
+                    match self.query_route(request, &own_pubkey, &ignored_pairs)? {
+                        Some(route) => route,
+                        None => break,
+                    }
+                }
+                RouteSource::Hops(candidates) => match candidates.get(number - 1) {
+                    Some(hops) => self.build_route(request, hops, &own_pubkey, &invoice)?,
+                    None => break,
+                },
+            };
+            let mut attempt = RebalanceAttempt {
+                number,
+                chan_ids: route.hops.iter().map(|hop| hop.chan_id).collect(),
+                fee_msat: route.total_fees_msat,
+                outcome: AttemptOutcome::TooExpensive,
+            };
+            if route.total_fees_msat <= request.max_fee_msat {
+                let result = self.send(&invoice, route.clone(), amt_msat)?;
+                attempt.outcome = result;
+            }
+            on_attempt(&attempt);
+
+            match &attempt.outcome {
+                AttemptOutcome::Succeeded => {
+                    report.succeeded = true;
+                    report.fee_msat = Some(attempt.fee_msat);
+                    report.attempts.push(attempt);
+                    return Ok(report);
+                }
+                AttemptOutcome::Failed { source_index, .. } => {
+                    // A failure at our own node as the receiver will not go away.
+                    if *source_index as usize >= route.hops.len() {
+                        report.attempts.push(attempt);
+                        return Ok(report);
+                    }
+                    ignored_pairs.push(failed_pair(&route, *source_index, &own_pubkey)?);
+                }
+                // A cheaper route has to avoid one of the channels of this one; the
+                // most expensive hop is the best guess.
+                AttemptOutcome::TooExpensive => {
+                    if let Some(index) = most_expensive_hop(&route) {
+                        ignored_pairs.push(failed_pair(&route, index, &own_pubkey)?);
+                    }
+                }
+            }
+            report.attempts.push(attempt);
+        }
+        Ok(report)
+    }
+
+    fn query_route(
+        &self,
+        request: &RebalanceRequest,
+        own_pubkey: &str,
+        ignored_pairs: &[lnrpc::NodePair],
+    ) -> Result<Option<lnrpc::Route>> {
+        let response: Result<lnrpc::QueryRoutesResponse> = self.client.call_lnd_method(
+            lnrpc::QueryRoutesRequest {
+                pub_key: own_pubkey.to_string(),
+                amt: request.amount_sat,
+                final_cltv_delta: FINAL_CLTV_DELTA,
+                fee_limit: Some(lnrpc::FeeLimit {
+                    limit: Some(Limit::FixedMsat(request.max_fee_msat)),
+                }),
+                use_mission_control: true,
+                ignored_pairs: ignored_pairs.to_vec(),
+                outgoing_chan_id: request.outgoing_chan_id,
+                last_hop_pubkey: hex::decode(&request.last_hop_pubkey)
+                    .context("Invalid last hop pubkey")?,
+                ..Default::default()
+            },
+            queryRoutes,
+        );
+        match response {
+            Ok(response) => Ok(response.routes.into_iter().next()),
+            // lnd reports a missing route as an error.
+            Err(e) if e.to_string().contains("unable to find a path") => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn build_route(
+        &self,
+        request: &RebalanceRequest,
+        hops: &[String],
+        own_pubkey: &str,
+        invoice: &lnrpc::AddInvoiceResponse,
+    ) -> Result<lnrpc::Route> {
+        let outgoing_peer = self.outgoing_peer(request.outgoing_chan_id)?;
+        let hop_pubkeys = std::iter::once(outgoing_peer.as_str())
+            .chain(hops.iter().map(String::as_str))
+            .chain([request.last_hop_pubkey.as_str(), own_pubkey])
+            .map(|pubkey| hex::decode(pubkey).with_context(|| format!("Invalid pubkey {}", pubkey)))
+            .collect::<Result<Vec<_>>>()?;
+        let response: routerrpc::BuildRouteResponse = self.client.call_lnd_method(
+            routerrpc::BuildRouteRequest {
+                amt_msat: request.amount_sat * 1000,
+                final_cltv_delta: FINAL_CLTV_DELTA,
+                outgoing_chan_id: request.outgoing_chan_id,
+                hop_pubkeys,
+                payment_addr: invoice.payment_addr.clone(),
+            },
+            routerBuildRoute,
+        )?;
+        response.route.context("lnd built no route")
+    }
+
+    /// Returns the peer of one of our channels, which is the first hop of routes out
+    /// of it.
+    fn outgoing_peer(&self, chan_id: u64) -> Result<String> {
+        let response: lnrpc::ListChannelsResponse = self
+            .client
+            .call_lnd_method(lnrpc::ListChannelsRequest::default(), listChannels)?;
+        response
+            .channels
+            .into_iter()
+            .find(|channel| channel.chan_id == chan_id)
+            .map(|channel| channel.remote_pubkey)
+            .with_context(|| format!("Channel {} is not open", chan_id))
+    }
+
+    fn send(
+        &self,
+        invoice: &lnrpc::AddInvoiceResponse,
+        mut route: lnrpc::Route,
+        amt_msat: i64,
+    ) -> Result<AttemptOutcome> {
+        // The invoice requires its payment address, which queried routes lack.
+        if let Some(last) = route.hops.last_mut() {
+            last.mpp_record = Some(lnrpc::MppRecord {
+                payment_addr: invoice.payment_addr.clone(),
+                total_amt_msat: amt_msat,
+            });
+        }
+        let attempt: lnrpc::HtlcAttempt = self.client.call_lnd_method(
+            routerrpc::SendToRouteRequest {
+                payment_hash: invoice.r_hash.clone(),
+                route: Some(route),
+                skip_temp_err: true,
+            },
+            routerSendToRouteV2,
+        )?;
+        Ok(match attempt.status() {
+            HtlcStatus::Succeeded => AttemptOutcome::Succeeded,
+            _ => {
+                let failure = attempt.failure.unwrap_or_default();
+                AttemptOutcome::Failed {
+                    code: failure.code(),
+                    source_index: failure.failure_source_index,
+                }
+            }
+        })
+    }
+}
+
+/// Returns the pair of nodes around the channel leaving the node at `index`, 0 being
+/// us.
+pub(crate) fn failed_pair(
+    route: &lnrpc::Route,
+    index: u32,
+    own_pubkey: &str,
+) -> Result<lnrpc::NodePair> {
+    let index = index as usize;
+    let from = match index {
+        0 => own_pubkey,
+        _ => &route.hops[index - 1].pub_key,
+    };
+    Ok(lnrpc::NodePair {
+        from: hex::decode(from).context("Invalid pubkey in route")?,
+        to: hex::decode(&route.hops[index].pub_key).context("Invalid pubkey in route")?,
+    })
+}
+
+/// Returns the index of the node charging the highest fee, for use with `failed_pair`.
+/// We charge nothing for our own outgoing channel, which is fixed anyway.
+fn most_expensive_hop(route: &lnrpc::Route) -> Option<u32> {
+    // The fee of hop j is charged by its node, at index j + 1, for forwarding over
+    // hop j + 1.
+    route
+        .hops
+        .iter()
+        .enumerate()
+        .take(route.hops.len().saturating_sub(1))
+        .max_by_key(|(_, hop)| hop.fee_msat)
+        .map(|(index, _)| index as u32 + 1)
+}
@@ -0,0 +1,480 @@
+use anyhow::{Context, Result};
+use lnd_grpc_rust::prost::Message;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+include!(concat!(env!("OUT_DIR"), "/lnd_methods.rs"));
+
+const MAGIC: &[u8; 8] = b"ELNDREC\0";
+const VERSION: u32 = 1;
+
+/// Returns the lnd method name for an exported FFI function.
+///
+/// Functions that are not part of `liblnd.h` (such as test mocks) are named after
+/// their address, which is only stable within a single process.
+pub(crate) fn method_name(func: usize) -> String {
+    LND_METHODS
+        .iter()
+        .find(|(_, addr)| *addr == func)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("0x{:x}", func))
+}
+
+/// The kind of traffic a `SessionEntry` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A unary call was made. The payload is the encoded request.
+    Call,
+    /// A unary call succeeded. The payload is the encoded response.
+    Response,
+    /// A call or stream failed. The payload is the UTF-8 error message.
+    Error,
+    /// A server stream was opened. The payload is the encoded request.
+    Subscribe,
+    /// A bidirectional stream was opened.
+    BidiOpen,
+    /// A message was received on a stream. The payload is the encoded message.
+    Event,
+    /// A message was sent on a bidirectional stream. The payload is the encoded message.
+    Send,
+}
+
+impl EntryKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            EntryKind::Call => 0,
+            EntryKind::Response => 1,
+            EntryKind::Error => 2,
+            EntryKind::Subscribe => 3,
+            EntryKind::BidiOpen => 4,
+            EntryKind::Event => 5,
+            EntryKind::Send => 6,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => EntryKind::Call,
+            1 => EntryKind::Response,
+            2 => EntryKind::Error,
+            3 => EntryKind::Subscribe,
+            4 => EntryKind::BidiOpen,
+            5 => EntryKind::Event,
+            6 => EntryKind::Send,
+            other => anyhow::bail!("Unknown session entry kind: {}", other),
+        })
+    }
+}
+
+/// A single recorded piece of FFI traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionEntry {
+    /// Identifies the call or stream this entry belongs to.
+    pub id: u64,
+    pub kind: EntryKind,
+    /// The lnd method name, e.g. `getInfo`.
+    pub method: String,
+    /// Time since the start of the recording.
+    pub at: Duration,
+    pub payload: Vec<u8>,
+}
+
+impl SessionEntry {
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&[self.kind.to_byte()])?;
+        w.write_all(&self.id.to_le_bytes())?;
+        w.write_all(&(self.at.as_micros() as u64).to_le_bytes())?;
+        w.write_all(&(self.method.len() as u32).to_le_bytes())?;
+        w.write_all(self.method.as_bytes())?;
+        w.write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        w.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Option<Self>> {
+        let mut kind = [0u8; 1];
+        if r.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        let kind = EntryKind::from_byte(kind[0])?;
+        let id = read_u64(r)?;
+        let at = Duration::from_micros(read_u64(r)?);
+        let method = String::from_utf8(read_bytes(r)?).context("Invalid method name")?;
+        let payload = read_bytes(r)?;
+        Ok(Some(SessionEntry {
+            id,
+            kind,
+            method,
+            at,
+            payload,
+        }))
+    }
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf).context("Truncated session entry")?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).context("Truncated session entry")?;
+    let len = u32::from_le_bytes(len) as usize;
+    // The length comes from the file, so only what is actually there is allocated.
+    let mut buf = Vec::new();
+    r.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        anyhow::bail!("Truncated session entry");
+    }
+    Ok(buf)
+}
+
+fn write_header<W: Write>(w: &mut W) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// An ordered list of recorded FFI traffic.
+///
+/// Sessions are usually written by a `Recorder` and loaded from disk, but they can also
+/// be scripted by hand to drive a `Replay` in tests.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    entries: Vec<SessionEntry>,
+    next_id: u64,
+}
+
+impl Session {
+    /// Creates an empty session.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a session file written by a `Recorder`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref()).context("Failed to open session file")?;
+        Self::read_from(BufReader::new(file))
+    }
+
+    /// Reads a session from any reader.
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("Failed to read session header")?;
+        if &magic != MAGIC {
+            anyhow::bail!("Not a session file");
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            anyhow::bail!("Unsupported session version: {}", version);
+        }
+
+        let mut session = Session::new();
+        while let Some(entry) = SessionEntry::read_from(&mut reader)? {
+            session.push(entry);
+        }
+        Ok(session)
+    }
+
+    /// Writes the session in the same format a `Recorder` uses.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        write_header(&mut writer)?;
+        for entry in &self.entries {
+            entry.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Returns all entries in recording order.
+    pub fn entries(&self) -> &[SessionEntry] {
+        &self.entries
+    }
+
+    /// Appends an entry to the session.
+    pub fn push(&mut self, entry: SessionEntry) {
+        self.next_id = self.next_id.max(entry.id.saturating_add(1));
+        self.entries.push(entry);
+    }
+
+    /// Scripts a successful unary call to `method`.
+    pub fn add_response<M: Message>(&mut self, method: &str, response: &M) -> &mut Self {
+        let id = self.script(method, EntryKind::Call, Vec::new());
+        self.script_with_id(id, method, EntryKind::Response, response.encode_to_vec());
+        self
+    }
+
+    /// Scripts a successful unary call to `method` made with `request`, which a strict
+    /// `Replay` checks.
+    pub fn add_call<Q: Message, M: Message>(
+        &mut self,
+        method: &str,
+        request: &Q,
+        response: &M,
+    ) -> &mut Self {
+        let id = self.script(method, EntryKind::Call, request.encode_to_vec());
+        self.script_with_id(id, method, EntryKind::Response, response.encode_to_vec());
+        self
+    }
+
+    /// Scripts a failed unary call to `method`.
+    pub fn add_error(&mut self, method: &str, error: &str) -> &mut Self {
+        let id = self.script(method, EntryKind::Call, Vec::new());
+        self.script_with_id(id, method, EntryKind::Error, error.as_bytes().to_vec());
+        self
+    }
+
+    /// Scripts a server stream on `method` that delivers `events` and then, if given,
+    /// fails with `error`.
+    pub fn add_stream<M: Message>(
+        &mut self,
+        method: &str,
+        events: &[M],
+        error: Option<&str>,
+    ) -> &mut Self {
+        let id = self.script(method, EntryKind::Subscribe, Vec::new());
+        for event in events {
+            self.script_with_id(id, method, EntryKind::Event, event.encode_to_vec());
+        }
+        if let Some(error) = error {
+            self.script_with_id(id, method, EntryKind::Error, error.as_bytes().to_vec());
+        }
+        self
+    }
+
+    /// Scripts a bidirectional stream on `method` on which lnd sends `messages`.
+    pub fn add_bidi_stream<M: Message>(&mut self, method: &str, messages: &[M]) -> &mut Self {
+        let id = self.script(method, EntryKind::BidiOpen, Vec::new());
+        for message in messages {
+            self.script_with_id(id, method, EntryKind::Event, message.encode_to_vec());
+        }
+        self
+    }
+
+    fn script(&mut self, method: &str, kind: EntryKind, payload: Vec<u8>) -> u64 {
+        let id = self.next_id;
+        self.script_with_id(id, method, kind, payload);
+        id
+    }
+
+    fn script_with_id(&mut self, id: u64, method: &str, kind: EntryKind, payload: Vec<u8>) {
+        self.push(SessionEntry {
+            id,
+            kind,
+            method: method.to_string(),
+            at: Duration::ZERO,
+            payload,
+        });
+    }
+}
+
+/// Writes every call, stream event and error passing through an `LndClient` to a
+/// session file, so it can later be served back with `Replay`.
+pub struct Recorder {
+    sink: Mutex<Box<dyn Write + Send>>,
+    started: Instant,
+    next_id: AtomicU64,
+}
+
+impl Recorder {
+    /// Creates a recorder writing to a new session file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path.as_ref()).context("Failed to create session file")?;
+        Self::new(BufWriter::new(file))
+    }
+
+    /// Creates a recorder writing to any writer.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Result<Self> {
+        write_header(&mut writer)?;
+        writer.flush()?;
+        Ok(Self {
+            sink: Mutex::new(Box::new(writer)),
+            started: Instant::now(),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Appends an entry and flushes it, so a session survives the app being killed.
+    pub(crate) fn record(&self, id: u64, kind: EntryKind, method: &str, payload: &[u8]) {
+        let entry = SessionEntry {
+            id,
+            kind,
+            method: method.to_string(),
+            at: self.started.elapsed(),
+            payload: payload.to_vec(),
+        };
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        // Recording is best effort and must never break the call being recorded.
+        let _ = entry.write_to(&mut *sink).and_then(|_| Ok(sink.flush()?));
+    }
+
+    /// Records a message or error received on a stream.
+    pub(crate) fn record_message(&self, id: u64, method: &str, message: Result<&[u8], &str>) {
+        match message {
+            Ok(data) => self.record(id, EntryKind::Event, method, data),
+            Err(error) => self.record(id, EntryKind::Error, method, error.as_bytes()),
+        }
+    }
+}
+
+/// A recorded stream: its incoming messages, the optional error that ended it, and
+/// when each of them happened.
+pub(crate) type RecordedStream = Vec<(Duration, Result<Vec<u8>, String>)>;
+
+struct RecordedCall {
+    request: Vec<u8>,
+    result: Result<Vec<u8>, String>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    calls: HashMap<String, VecDeque<RecordedCall>>,
+    streams: HashMap<String, VecDeque<RecordedStream>>,
+    bidi_streams: HashMap<String, VecDeque<RecordedStream>>,
+}
+
+/// Serves a recorded `Session` back to an `LndClient` instead of calling lnd.
+///
+/// Calls and streams are matched by method name in recording order.
+pub struct Replay {
+    state: Mutex<ReplayState>,
+    realtime: bool,
+    strict: bool,
+}
+
+impl Replay {
+    /// Creates a replay backend for `session`.
+    pub fn new(session: Session) -> Self {
+        let mut state = ReplayState::default();
+        let mut calls: HashMap<u64, RecordedCall> = HashMap::new();
+        let mut streams: HashMap<u64, (Duration, RecordedStream)> = HashMap::new();
+        let mut call_order = Vec::new();
+        let mut stream_order = Vec::new();
+
+        for entry in session.entries {
+            match entry.kind {
+                EntryKind::Call => {
+                    call_order.push((entry.id, entry.method));
+                    calls.insert(
+                        entry.id,
+                        RecordedCall {
+                            request: entry.payload,
+                            result: Err("Call was recorded without a response".to_string()),
+                        },
+                    );
+                }
+                EntryKind::Subscribe | EntryKind::BidiOpen => {
+                    stream_order.push((entry.id, entry.method, entry.kind));
+                    streams.insert(entry.id, (entry.at, Vec::new()));
+                }
+                EntryKind::Response | EntryKind::Error | EntryKind::Event => {
+                    let result = if entry.kind == EntryKind::Error {
+                        Err(String::from_utf8_lossy(&entry.payload).into_owned())
+                    } else {
+                        Ok(entry.payload)
+                    };
+                    if let Some(call) = calls.get_mut(&entry.id) {
+                        call.result = result;
+                    } else if let Some((opened, stream)) = streams.get_mut(&entry.id) {
+                        stream.push((entry.at.saturating_sub(*opened), result));
+                    }
+                }
+                // Outgoing messages are produced by the code under test during replay.
+                EntryKind::Send => {}
+            }
+        }
+
+        for (id, method) in call_order {
+            if let Some(call) = calls.remove(&id) {
+                state.calls.entry(method).or_default().push_back(call);
+            }
+        }
+        for (id, method, kind) in stream_order {
+            if let Some((_, stream)) = streams.remove(&id) {
+                let target = if kind == EntryKind::Subscribe {
+                    &mut state.streams
+                } else {
+                    &mut state.bidi_streams
+                };
+                target.entry(method).or_default().push_back(stream);
+            }
+        }
+
+        Self {
+            state: Mutex::new(state),
+            realtime: false,
+            strict: false,
+        }
+    }
+
+    /// Loads a session file and creates a replay backend for it.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(Session::load(path)?))
+    }
+
+    /// Delivers stream messages with their recorded delays instead of immediately.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Fails calls whose request differs from the recorded one.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub(crate) fn is_realtime(&self) -> bool {
+        self.realtime
+    }
+
+    pub(crate) fn call(&self, method: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let call = self
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .calls
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| anyhow::anyhow!("No recorded call to {} left", method))?;
+
+        if self.strict && call.request != request {
+            anyhow::bail!("Request to {} differs from the recorded one", method);
+        }
+        call.result.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    pub(crate) fn subscribe(&self, method: &str) -> Result<RecordedStream> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .streams
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| anyhow::anyhow!("No recorded subscription to {} left", method))
+    }
+
+    pub(crate) fn open_bidi(&self, method: &str) -> Result<RecordedStream> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bidi_streams
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| anyhow::anyhow!("No recorded stream on {} left", method))
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Number of independently locked shards. Streams only contend with each other when
+/// their ids land on the same shard, and then only for the duration of a map lookup.
+const SHARDS: usize = 16;
+
+/// Identifies a subscription made through an `LndClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriptionId(pub(crate) u64);
+
+/// A sharded map of live streams, owned by a single client.
+///
+/// Lookups clone the `Arc` out of the shard and release the lock immediately, so the
+/// caller can run user callbacks without holding any lock.
+pub(crate) struct Registry<T> {
+    shards: Vec<Mutex<HashMap<u64, Arc<T>>>>,
+    next_id: AtomicU64,
+}
+
+impl<T> Registry<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn shard(&self, id: u64) -> &Mutex<HashMap<u64, Arc<T>>> {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    /// Reserves an id without inserting anything yet.
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn insert(&self, id: u64, value: T) {
+        self.shard(id)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, Arc::new(value));
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<Arc<T>> {
+        self.shard(id)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .cloned()
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> Option<Arc<T>> {
+        self.shard(id)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id)
+    }
+
+    /// Removes and returns every entry.
+    pub(crate) fn drain(&self) -> Vec<Arc<T>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .drain()
+                    .map(|(_, value)| value)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+}
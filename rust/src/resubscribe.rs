@@ -0,0 +1,211 @@
+use crate::callback_panic::guard;
+use crate::event_subscription::EventCallback;
+use crate::lnd_client::{StreamOptions, WeakLndClient};
+use crate::recording::method_name;
+use crate::{CRecvStream, LndClient, SubscriptionId};
+use anyhow::Result;
+use lnd_grpc_rust::prost::Message;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::os::raw::{c_char, c_int};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+pub(crate) type RewriteHook<R> = Box<dyn Fn(&mut R) + Send + Sync>;
+pub(crate) type LifecycleHook = Box<dyn Fn(StreamLifecycle) + Send + Sync>;
+
+/// How a subscription is reopened after its stream ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResubscribePolicy {
+    /// The delay before the first attempt.
+    pub initial_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// How much the delay grows with every failed attempt.
+    pub multiplier: f64,
+    /// The fraction of each delay that is randomized, between 0 and 1, so that many
+    /// subscriptions do not all retry at the same moment.
+    pub jitter: f64,
+    /// How many attempts are made before giving up, or `None` to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ResubscribePolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ResubscribePolicy {
+    /// Returns the delay before the given attempt, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_unit();
+        Duration::from_secs_f64((base * (1.0 - jitter)).max(0.0))
+    }
+}
+
+/// A change in the connection of a resubscribing stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamLifecycle {
+    /// The stream was opened.
+    Connected,
+    /// The stream ended with the given error.
+    Disconnected(String),
+    /// The stream is reopened after `delay`.
+    Retrying {
+        /// The number of this attempt, counting from 1.
+        attempt: u32,
+        /// How long until the attempt is made.
+        delay: Duration,
+    },
+}
+
+/// Reopens a subscription under the same id whenever its stream ends.
+struct Resubscriber<E, R> {
+    client: WeakLndClient,
+    id: SubscriptionId,
+    subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+    method: String,
+    /// Held while the stream is (re)opened, which also keeps lifecycle events in order.
+    request: Mutex<R>,
+    callback: EventCallback<E>,
+    policy: ResubscribePolicy,
+    rewrite: Option<RewriteHook<R>>,
+    lifecycle: Option<LifecycleHook>,
+}
+
+impl<E, R> Resubscriber<E, R>
+where
+    E: Message + Default + 'static,
+    R: Message + 'static,
+{
+    fn handle(self: &Arc<Self>, message: Result<Vec<u8>, String>) {
+        match message {
+            Ok(data) => match E::decode(data.as_slice()) {
+                Ok(event) => (self.callback)(Ok(event)),
+                Err(e) => (self.callback)(Err(format!("Failed to decode event: {}", e))),
+            },
+            Err(error) => {
+                // Waiting out the backoff must not hold up the thread lnd delivered on.
+                let resubscriber = self.clone();
+                thread::spawn(move || resubscriber.retry(error));
+            }
+        }
+    }
+
+    fn retry(&self, error: String) {
+        let mut request = self.request.lock().unwrap_or_else(PoisonError::into_inner);
+        self.emit(StreamLifecycle::Disconnected(error.clone()));
+
+        let mut last_error = error;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if self.policy.max_attempts.is_some_and(|max| attempt > max) {
+                if let Some(client) = self.client.upgrade() {
+                    client.unsubscribe(self.id);
+                }
+                (self.callback)(Err(last_error));
+                return;
+            }
+
+            let delay = self.policy.delay(attempt);
+            self.emit(StreamLifecycle::Retrying { attempt, delay });
+            thread::sleep(delay);
+
+            // Stop quietly once the client is gone, shut down or the caller unsubscribed.
+            let Some(client) = self.client.upgrade() else {
+                return;
+            };
+            if !client.is_running() || !client.is_subscribed(self.id) {
+                return;
+            }
+
+            if let Some(rewrite) = &self.rewrite {
+                guard(&self.method, || rewrite(&mut request));
+            }
+            match client.reopen_stream(self.id, self.subscribe_func, request.encode_to_vec()) {
+                Ok(()) => {
+                    self.emit(StreamLifecycle::Connected);
+                    return;
+                }
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+    }
+
+    fn emit(&self, event: StreamLifecycle) {
+        if let Some(lifecycle) = &self.lifecycle {
+            guard(&self.method, || lifecycle(event));
+        }
+    }
+}
+
+/// Subscribes like `LndClient::subscribe_with_options`, but reopens the stream with
+/// `policy` whenever it ends.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn subscribe<E, R>(
+    client: &LndClient,
+    subscribe_func: unsafe extern "C" fn(*mut c_char, c_int, CRecvStream) -> (),
+    callback: EventCallback<E>,
+    request: R,
+    options: StreamOptions,
+    policy: ResubscribePolicy,
+    rewrite: Option<RewriteHook<R>>,
+    lifecycle: Option<LifecycleHook>,
+) -> Result<SubscriptionId>
+where
+    E: Message + Default + 'static,
+    R: Message + 'static,
+{
+    client.ensure_running()?;
+    let id = client.next_subscription_id();
+    let resubscriber = Arc::new(Resubscriber {
+        client: client.downgrade(),
+        id,
+        subscribe_func,
+        method: method_name(subscribe_func as usize),
+        request: Mutex::new(request),
+        callback,
+        policy,
+        rewrite,
+        lifecycle,
+    });
+
+    let request = resubscriber
+        .request
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let handler = resubscriber.clone();
+    client.subscribe_raw(
+        id,
+        subscribe_func,
+        Box::new(move |message| handler.handle(message)),
+        request.encode_to_vec(),
+        options,
+    )?;
+    resubscriber.emit(StreamLifecycle::Connected);
+    Ok(id)
+}
+
+/// Returns a random number in `[0, 1)`. Good enough for jitter, not for secrets.
+fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as u64,
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
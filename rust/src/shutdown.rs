@@ -0,0 +1,28 @@
+use crate::LndClient;
+use std::time::Duration;
+
+/// Shuts down an `LndClient` when it goes out of scope.
+///
+/// Created with `LndClient::shutdown_guard`. Errors during shutdown are ignored; call
+/// `LndClient::shutdown` directly to handle them.
+pub struct ShutdownGuard {
+    client: LndClient,
+    timeout: Duration,
+}
+
+impl ShutdownGuard {
+    pub(crate) fn new(client: LndClient, timeout: Duration) -> Self {
+        Self { client, timeout }
+    }
+
+    /// Returns the guarded client.
+    pub fn client(&self) -> &LndClient {
+        &self.client
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        let _ = self.client.shutdown(self.timeout);
+    }
+}
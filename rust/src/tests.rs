@@ -1,10 +1,25 @@
 // tests.rs
 
-use crate::{CCallback, CRecvStream, LndClient};
-use lnd_grpc_rust::lnrpc;
+use crate::{
+    action, add_first_party_caveat, decode_pairs, encode_pairs, entity, fees_by_channel, to_csv,
+    to_json, volume_by_bucket, AttemptOutcome, BackupManager, BackupSink, BlockEvent, CCallback,
+    CRecvStream, CallbackBackupSink, CallbackPanic, ChainNotifier, ChannelManager, ChannelState,
+    ClientError, Dispatcher, EntryKind, EventBus, EventFilter, EventSource, FailedForwardCounter,
+    FailedForwards, FailureReason, FeeBumpEvent, FeeBumpPolicy, FeeBumper, FeePolicyConfig,
+    FeePolicyEngine, FeePreference, FileBackupSink, ForwardingHistory, ImportOptions, IndexStore,
+    InvoiceEvent, InvoiceFeed, InvoiceIndices, InvoiceList, InvoiceManager, InvoiceSpec,
+    InvoiceState, LndClient, Macaroons, MemoryIndexStore, MissionControl, NeutrinoConfig,
+    NeutrinoEvent, NeutrinoManager, NodeEvent, OnchainWallet, PairRecord, PanicPolicy,
+    PaymentLimits, PaymentManager, PaymentStatus, PermissionSet, PolicyRule, ProbeOutcome, Prober,
+    PsbtChannelFunding, RebalanceRequest, Rebalancer, Recorder, Replay, ResubscribePolicy,
+    RouteSource, SendRequest, Session, SessionEntry, StreamLifecycle, TowerIssue, TowerUri,
+    TransactionList, WalletBalance, WatchState, WatchtowerClient,
+};
 use lnd_grpc_rust::prost::Message;
+use lnd_grpc_rust::{chainrpc, invoicesrpc, lnrpc, neutrinorpc, routerrpc, walletrpc, wtclientrpc};
 use std::ffi::CString;
 use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, LazyLock, Mutex};
 
@@ -154,6 +169,26 @@ unsafe extern "C" fn mock_connect_peer(
     }
 }
 
+unsafe extern "C" fn mock_wallet_balance(
+    _data: *mut std::os::raw::c_char,
+    _length: std::os::raw::c_int,
+    callback: CCallback,
+) {
+    let encoded = lnrpc::WalletBalanceResponse {
+        total_balance: 21_000,
+        confirmed_balance: 21_000,
+        ..Default::default()
+    }
+    .encode_to_vec();
+    if let Some(on_response) = callback.onResponse {
+        on_response(
+            callback.responseContext,
+            encoded.as_ptr() as *const std::os::raw::c_char,
+            encoded.len() as std::os::raw::c_int,
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -270,4 +305,2367 @@ mod tests {
         assert!(result.is_ok(), "Expected Ok, got Err: {:?}", result.err());
         assert_eq!(result.unwrap(), expected_response);
     }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("embedded-lnd-session-{}.bin", std::process::id()));
+
+        let recorder = Recorder::create(&path).unwrap();
+        let client = LndClient::with_recorder(recorder);
+        let recorded: lnrpc::WalletBalanceResponse = client
+            .call_lnd_method(lnrpc::WalletBalanceRequest::default(), mock_wallet_balance)
+            .unwrap();
+        drop(client);
+
+        let session = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(session.entries().len(), 2);
+
+        let client = LndClient::with_replay(Replay::new(session).strict(true));
+        let replayed: lnrpc::WalletBalanceResponse = client
+            .call_lnd_method(lnrpc::WalletBalanceRequest::default(), mock_wallet_balance)
+            .unwrap();
+        assert_eq!(replayed, recorded);
+
+        let exhausted: anyhow::Result<lnrpc::WalletBalanceResponse> =
+            client.call_lnd_method(lnrpc::WalletBalanceRequest::default(), mock_wallet_balance);
+        assert!(exhausted.is_err());
+
+        // A length past the end of the file fails instead of allocating it.
+        let mut bytes = Vec::new();
+        let mut session = Session::new();
+        session.add_response("getInfo", &lnrpc::GetInfoResponse::default());
+        session.write_to(&mut bytes).unwrap();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Session::read_from(bytes.as_slice()).is_err());
+
+        session.push(SessionEntry {
+            id: u64::MAX,
+            kind: EntryKind::Call,
+            method: "getInfo".to_string(),
+            at: std::time::Duration::ZERO,
+            payload: Vec::new(),
+        });
+    }
+
+    #[test]
+    fn test_replay_subscription() {
+        let event = lnrpc::PeerEvent {
+            pub_key: "02546bfe3778d7f8aea43224337d082bcc4521150569c94c9052413ae5b6599c2d"
+                .to_string(),
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session.add_stream(
+            "subscribePeerEvents",
+            std::slice::from_ref(&event),
+            Some("EOF"),
+        );
+        let client = LndClient::with_replay(Replay::new(session));
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(move |event_result| sender.lock().unwrap().send(event_result).unwrap())
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .subscribe()
+            .unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), Ok(event));
+        assert_eq!(
+            receiver.recv_timeout(timeout).unwrap(),
+            Err("EOF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_independent_client_subscriptions() {
+        let replay_client = |pub_key: &str| {
+            let event = lnrpc::PeerEvent {
+                pub_key: pub_key.to_string(),
+                ..Default::default()
+            };
+            let mut session = Session::new();
+            session.add_stream("subscribePeerEvents", &[event], None);
+            LndClient::with_replay(Replay::new(session))
+        };
+        let slow_client = replay_client("slow");
+        let fast_client = replay_client("fast");
+
+        // The slow handler blocks until the fast client has seen its event.
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let release_receiver = Mutex::new(release_receiver);
+        let (done_sender, done_receiver) = mpsc::channel();
+        let done_sender = Mutex::new(done_sender);
+        let slow_id = slow_client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(move |_| {
+                let _ = release_receiver.lock().unwrap().recv();
+                done_sender.lock().unwrap().send(()).unwrap();
+            })
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .subscribe()
+            .unwrap();
+
+        let (fast_sender, fast_receiver) = mpsc::channel();
+        let fast_sender = Mutex::new(fast_sender);
+        fast_client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(move |event| fast_sender.lock().unwrap().send(event).unwrap())
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .subscribe()
+            .unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        let fast_event = fast_receiver.recv_timeout(timeout).unwrap().unwrap();
+        assert_eq!(fast_event.pub_key, "fast");
+        release_sender.send(()).unwrap();
+        done_receiver.recv_timeout(timeout).unwrap();
+
+        assert_eq!(slow_client.active_subscriptions(), 1);
+        assert_eq!(fast_client.active_subscriptions(), 1);
+        assert!(slow_client.unsubscribe(slow_id));
+        assert!(!slow_client.unsubscribe(slow_id));
+        assert_eq!(slow_client.active_subscriptions(), 0);
+        assert_eq!(fast_client.active_subscriptions(), 1);
+    }
+
+    #[test]
+    fn test_callback_panic_policy() {
+        let (panic_sender, panic_receiver) = mpsc::channel::<CallbackPanic>();
+        let panic_sender = Mutex::new(panic_sender);
+        crate::set_error_hook(move |panic| {
+            if panic.message.starts_with("test_callback_panic_policy") {
+                let _ = panic_sender.lock().unwrap().send(panic.clone());
+            }
+        });
+
+        let events: Vec<lnrpc::PeerEvent> = ["first", "second"]
+            .iter()
+            .map(|pub_key| lnrpc::PeerEvent {
+                pub_key: pub_key.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let timeout = std::time::Duration::from_secs(5);
+
+        for policy in [PanicPolicy::Resume, PanicPolicy::Close] {
+            let mut session = Session::new();
+            session.add_stream("subscribePeerEvents", &events, None);
+            let client = LndClient::with_replay(Replay::new(session));
+
+            let (sender, receiver) = mpsc::channel();
+            let sender = Mutex::new(sender);
+            client
+                .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                    crate::subscribePeerEvents,
+                )
+                .on_event(move |event| {
+                    let event = event.unwrap();
+                    if event.pub_key == "first" {
+                        panic!("test_callback_panic_policy");
+                    }
+                    sender.lock().unwrap().send(event).unwrap();
+                })
+                .on_panic(policy)
+                .with_request(lnrpc::PeerEventSubscription::default())
+                .subscribe()
+                .unwrap();
+
+            let panic = panic_receiver.recv_timeout(timeout).unwrap();
+            assert_eq!(panic.method, "subscribePeerEvents");
+
+            match policy {
+                PanicPolicy::Resume => {
+                    assert_eq!(receiver.recv_timeout(timeout).unwrap().pub_key, "second");
+                    assert_eq!(client.active_subscriptions(), 1);
+                }
+                PanicPolicy::Close => {
+                    assert!(receiver.recv_timeout(timeout).is_err());
+                    assert_eq!(client.active_subscriptions(), 0);
+                }
+            }
+        }
+
+        crate::clear_error_hook();
+    }
+
+    #[test]
+    fn test_dispatcher_preserves_order() {
+        let events: Vec<lnrpc::PeerEvent> = (0..50)
+            .map(|i| lnrpc::PeerEvent {
+                pub_key: i.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let mut session = Session::new();
+        session.add_stream("subscribePeerEvents", &events, None);
+        let client = LndClient::with_replay(Replay::new(session));
+        let dispatcher = Dispatcher::thread_pool(4);
+        client.set_dispatcher(Some(dispatcher.clone()));
+
+        // Hold up the handler until every event has been queued, which shows lnd's
+        // thread is not blocked by it.
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let release_receiver = Mutex::new(release_receiver);
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(move |event| {
+                let event = event.unwrap();
+                if event.pub_key == "0" {
+                    release_receiver.lock().unwrap().recv().unwrap();
+                }
+                sender.lock().unwrap().send(event.pub_key).unwrap();
+            })
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .subscribe()
+            .unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while dispatcher.metrics().queued < 49 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Events were not queued"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let metrics = dispatcher.metrics();
+        assert_eq!(
+            metrics.stream_depths,
+            vec![("subscribePeerEvents".to_string(), 49)]
+        );
+        release_sender.send(()).unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        for i in 0..50 {
+            assert_eq!(receiver.recv_timeout(timeout).unwrap(), i.to_string());
+        }
+        assert!(dispatcher.metrics().peak_stream_depth >= 49);
+    }
+
+    fn shutdown_session() -> Session {
+        let mut session = Session::new();
+        session
+            .add_stream::<lnrpc::PeerEvent>("subscribePeerEvents", &[], None)
+            .add_bidi_stream::<lnrpc::ChannelAcceptRequest>("channelAcceptor", &[])
+            .add_stream(
+                "subscribeState",
+                &[lnrpc::SubscribeStateResponse { state: 4 }],
+                Some("lnd is shutting down"),
+            )
+            .add_response("stopDaemon", &lnrpc::StopResponse {});
+        session
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let client = LndClient::with_replay(Replay::new(shutdown_session()));
+        client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(|_| {})
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .subscribe()
+            .unwrap();
+        client
+            .bidi_stream::<lnrpc::ChannelAcceptRequest, lnrpc::ChannelAcceptResponse>(
+                crate::channelAcceptor,
+            )
+            .on_request(|_| {})
+            .get_response(|_| None)
+            .build()
+            .unwrap();
+        assert_eq!(client.active_subscriptions(), 1);
+
+        client.shutdown(std::time::Duration::from_secs(5)).unwrap();
+        assert!(!client.is_running());
+        assert_eq!(client.active_subscriptions(), 0);
+
+        let result: anyhow::Result<lnrpc::GetInfoResponse> =
+            client.call_lnd_method(lnrpc::GetInfoRequest {}, crate::getInfo);
+        assert_eq!(
+            result.unwrap_err().downcast_ref::<ClientError>(),
+            Some(&ClientError::NotRunning)
+        );
+        assert!(client.shutdown(std::time::Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_guard() {
+        let client = LndClient::with_replay(Replay::new(shutdown_session()));
+        {
+            let _guard = client.shutdown_guard(std::time::Duration::from_secs(5));
+            assert!(client.is_running());
+        }
+        assert!(!client.is_running());
+    }
+
+    #[test]
+    fn test_payment_manager_pay_is_idempotent() {
+        let payment_hash = "ab".repeat(32);
+        let pay_req = lnrpc::PayReq {
+            payment_hash: payment_hash.clone(),
+            num_satoshis: 100,
+            num_msat: 100_000,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            expiry: 3600,
+            ..Default::default()
+        };
+        let update = |status: lnrpc::payment::PaymentStatus| lnrpc::Payment {
+            payment_hash: payment_hash.clone(),
+            payment_preimage: "cd".repeat(32),
+            fee_msat: 1_000,
+            status: status as i32,
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response("decodePayReq", &pay_req)
+            .add_response("decodePayReq", &pay_req)
+            .add_stream(
+                "routerSendPaymentV2",
+                &[
+                    update(lnrpc::payment::PaymentStatus::InFlight),
+                    update(lnrpc::payment::PaymentStatus::Succeeded),
+                ],
+                Some("EOF"),
+            );
+        let manager = PaymentManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let timeout = std::time::Duration::from_secs(5);
+        let handle = manager.pay("lnbcrt1u1", PaymentLimits::default()).unwrap();
+        let expected = PaymentStatus::Succeeded {
+            preimage: "cd".repeat(32),
+            fee_msat: 1_000,
+        };
+        assert_eq!(handle.wait(timeout).unwrap(), expected);
+
+        // No second payment is sent, as there is no recorded stream left for one.
+        let again = manager.pay("lnbcrt1u1", PaymentLimits::default()).unwrap();
+        assert_eq!(again.payment_hash(), payment_hash);
+        assert_eq!(again.status(), expected);
+    }
+
+    #[test]
+    fn test_payment_manager_resumes_in_flight_payments() {
+        let payment = |hash: &str, status: lnrpc::payment::PaymentStatus| lnrpc::Payment {
+            payment_hash: hash.to_string(),
+            status: status as i32,
+            ..Default::default()
+        };
+        let in_flight = "01".repeat(32);
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listPayments",
+                &lnrpc::ListPaymentsResponse {
+                    payments: vec![
+                        payment(&"02".repeat(32), lnrpc::payment::PaymentStatus::Succeeded),
+                        payment(&in_flight, lnrpc::payment::PaymentStatus::InFlight),
+                    ],
+                    first_index_offset: 1,
+                    last_index_offset: 2,
+                    ..Default::default()
+                },
+            )
+            .add_stream(
+                "routerTrackPaymentV2",
+                &[lnrpc::Payment {
+                    failure_reason: lnrpc::PaymentFailureReason::FailureReasonNoRoute as i32,
+                    ..payment(&in_flight, lnrpc::payment::PaymentStatus::Failed)
+                }],
+                None,
+            );
+        let manager = PaymentManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let resumed = manager.resume().unwrap();
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].payment_hash(), in_flight);
+        assert_eq!(
+            resumed[0].wait(std::time::Duration::from_secs(5)).unwrap(),
+            PaymentStatus::Failed(FailureReason::NoRoute)
+        );
+        assert!(manager.get(&in_flight).is_some());
+    }
+
+    fn invoice_update(state: lnrpc::invoice::InvoiceState, expiry_height: i32) -> lnrpc::Invoice {
+        lnrpc::Invoice {
+            state: state as i32,
+            htlcs: vec![lnrpc::InvoiceHtlc {
+                expiry_height,
+                state: lnrpc::InvoiceHtlcState::Accepted as i32,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hold_invoice_settle() {
+        let mut session = Session::new();
+        session
+            .add_response(
+                "invoicesAddHoldInvoice",
+                &invoicesrpc::AddHoldInvoiceResp {
+                    payment_request: "lnbcrt1u1".to_string(),
+                    ..Default::default()
+                },
+            )
+            .add_stream(
+                "invoicesSubscribeSingleInvoice",
+                &[
+                    invoice_update(lnrpc::invoice::InvoiceState::Open, 0),
+                    invoice_update(lnrpc::invoice::InvoiceState::Accepted, 500),
+                ],
+                None,
+            )
+            .add_response("invoicesSettleInvoice", &invoicesrpc::SettleInvoiceResp {});
+        let manager = InvoiceManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let invoice = manager
+            .create_hold_invoice(&InvoiceSpec::default(), None)
+            .unwrap();
+        let preimage = invoice.preimage().unwrap().clone();
+        assert_eq!(&preimage.payment_hash(), invoice.payment_hash());
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            invoice.wait_for(InvoiceState::Accepted, timeout).unwrap(),
+            InvoiceState::Accepted
+        );
+        manager.settle(&invoice).unwrap();
+        assert_eq!(invoice.state(), InvoiceState::Settled);
+        assert!(!InvoiceState::Settled.can_transition_to(InvoiceState::Accepted));
+    }
+
+    #[test]
+    fn test_hold_invoice_auto_cancel() {
+        let mut session = Session::new();
+        session
+            .add_response(
+                "invoicesAddHoldInvoice",
+                &invoicesrpc::AddHoldInvoiceResp::default(),
+            )
+            .add_stream(
+                "chainNotifierRegisterBlockEpochNtfn",
+                &[
+                    chainrpc::BlockEpoch {
+                        height: 100,
+                        ..Default::default()
+                    },
+                    chainrpc::BlockEpoch {
+                        height: 141,
+                        ..Default::default()
+                    },
+                ],
+                None,
+            )
+            .add_stream(
+                "invoicesSubscribeSingleInvoice",
+                &[invoice_update(lnrpc::invoice::InvoiceState::Accepted, 150)],
+                None,
+            )
+            .add_response("invoicesCancelInvoice", &invoicesrpc::CancelInvoiceResp {});
+        let manager =
+            InvoiceManager::new(LndClient::with_replay(Replay::new(session))).auto_cancel(10);
+
+        let invoice = manager
+            .create_hold_invoice(&InvoiceSpec::default(), None)
+            .unwrap();
+        assert_eq!(
+            invoice
+                .wait_for(InvoiceState::Canceled, std::time::Duration::from_secs(5))
+                .unwrap(),
+            InvoiceState::Canceled
+        );
+    }
+
+    #[test]
+    fn test_track_starts_from_looked_up_state() {
+        let mut session = Session::new();
+        session
+            .add_response(
+                "invoicesLookupInvoiceV2",
+                &lnrpc::Invoice {
+                    r_preimage: vec![1; 32],
+                    state: lnrpc::invoice::InvoiceState::Settled as i32,
+                    ..Default::default()
+                },
+            )
+            .add_stream::<lnrpc::Invoice>("invoicesSubscribeSingleInvoice", &[], None);
+        let manager = InvoiceManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let invoice = manager.track([7; 32]).unwrap();
+        assert_eq!(invoice.state(), InvoiceState::Settled);
+    }
+
+    #[test]
+    fn test_invoice_feed_backfills_exactly_once() {
+        let invoice = |add_index: u64, settle_index: u64| lnrpc::Invoice {
+            add_index,
+            settle_index,
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listInvoices",
+                &lnrpc::ListInvoiceResponse {
+                    invoices: vec![invoice(2, 0), invoice(3, 1)],
+                    first_index_offset: 2,
+                    last_index_offset: 3,
+                },
+            )
+            .add_response("listInvoices", &lnrpc::ListInvoiceResponse::default())
+            // lnd replays what the backfill already covered.
+            .add_stream(
+                "subscribeInvoices",
+                &[invoice(3, 1), invoice(4, 0), invoice(2, 2)],
+                None,
+            );
+        let store = Arc::new(MemoryIndexStore::new(InvoiceIndices {
+            add_index: 1,
+            settle_index: 0,
+        }));
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let feed = InvoiceFeed::new(
+            LndClient::with_replay(Replay::new(session)),
+            store.clone(),
+            move |event| sender.lock().unwrap().send(event.unwrap()).unwrap(),
+        );
+        feed.subscribe().unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        // Additions and settlements are each in order, but may interleave depending on
+        // whether lnd's replay arrives during the backfill.
+        let events: Vec<InvoiceEvent> = (0..5)
+            .map(|_| receiver.recv_timeout(timeout).unwrap())
+            .collect();
+        let (added, settled): (Vec<_>, Vec<_>) = events
+            .into_iter()
+            .partition(|event| matches!(event, InvoiceEvent::Added(_)));
+        assert_eq!(
+            added,
+            [
+                InvoiceEvent::Added(invoice(2, 0)),
+                InvoiceEvent::Added(invoice(3, 1)),
+                InvoiceEvent::Added(invoice(4, 0)),
+            ]
+        );
+        assert_eq!(
+            settled,
+            [
+                InvoiceEvent::Settled(invoice(3, 1)),
+                InvoiceEvent::Settled(invoice(2, 2)),
+            ]
+        );
+        assert!(receiver
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+        assert_eq!(
+            store.load().unwrap(),
+            InvoiceIndices {
+                add_index: 4,
+                settle_index: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_invoice_feed_reports_settlements_in_settle_order() {
+        let invoice = |add_index: u64, settle_index: u64| lnrpc::Invoice {
+            add_index,
+            settle_index,
+            ..Default::default()
+        };
+        // The older invoice was settled after the newer one.
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listInvoices",
+                &lnrpc::ListInvoiceResponse {
+                    invoices: vec![invoice(1, 2), invoice(2, 1)],
+                    first_index_offset: 1,
+                    last_index_offset: 2,
+                },
+            )
+            .add_response("listInvoices", &lnrpc::ListInvoiceResponse::default())
+            .add_stream("subscribeInvoices", &[invoice(2, 1), invoice(1, 2)], None);
+        let store = Arc::new(MemoryIndexStore::new(InvoiceIndices::default()));
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let feed = InvoiceFeed::new(
+            LndClient::with_replay(Replay::new(session)),
+            store.clone(),
+            move |event| sender.lock().unwrap().send(event.unwrap()).unwrap(),
+        );
+        feed.subscribe().unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        let expected = [
+            InvoiceEvent::Added(invoice(1, 2)),
+            InvoiceEvent::Added(invoice(2, 1)),
+            InvoiceEvent::Settled(invoice(2, 1)),
+            InvoiceEvent::Settled(invoice(1, 2)),
+        ];
+        for event in expected {
+            assert_eq!(receiver.recv_timeout(timeout).unwrap(), event);
+        }
+        assert!(receiver
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+        assert_eq!(
+            store.load().unwrap(),
+            InvoiceIndices {
+                add_index: 2,
+                settle_index: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_resubscribe_after_stream_error() {
+        let event = |pub_key: &str| lnrpc::PeerEvent {
+            pub_key: pub_key.to_string(),
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_stream("subscribePeerEvents", &[event("first")], Some("EOF"))
+            .add_stream("subscribePeerEvents", &[event("second")], None);
+        let client = LndClient::with_replay(Replay::new(session));
+
+        let (events, event_receiver) = mpsc::channel();
+        let events = Mutex::new(events);
+        let (lifecycle, lifecycle_receiver) = mpsc::channel();
+        let lifecycle = Mutex::new(lifecycle);
+        let rewrites = Arc::new(AtomicUsize::new(0));
+        let rewrite_count = rewrites.clone();
+        let delay = std::time::Duration::from_millis(10);
+        let id = client
+            .subscribe_events::<lnrpc::PeerEvent, lnrpc::PeerEventSubscription>(
+                crate::subscribePeerEvents,
+            )
+            .on_event(move |event| events.lock().unwrap().send(event).unwrap())
+            .with_request(lnrpc::PeerEventSubscription::default())
+            .resubscribe(ResubscribePolicy {
+                initial_delay: delay,
+                jitter: 0.0,
+                max_attempts: Some(3),
+                ..Default::default()
+            })
+            .rewrite_request(move |_| {
+                rewrite_count.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_lifecycle(move |event| lifecycle.lock().unwrap().send(event).unwrap())
+            .subscribe()
+            .unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            event_receiver.recv_timeout(timeout).unwrap(),
+            Ok(event("first"))
+        );
+        assert_eq!(
+            event_receiver.recv_timeout(timeout).unwrap(),
+            Ok(event("second"))
+        );
+        let expected = [
+            StreamLifecycle::Connected,
+            StreamLifecycle::Disconnected("EOF".to_string()),
+            StreamLifecycle::Retrying { attempt: 1, delay },
+            StreamLifecycle::Connected,
+        ];
+        for expected in expected {
+            assert_eq!(lifecycle_receiver.recv_timeout(timeout).unwrap(), expected);
+        }
+        assert_eq!(rewrites.load(Ordering::SeqCst), 1);
+        // The subscription keeps its id across reconnects.
+        assert!(client.unsubscribe(id));
+    }
+
+    #[test]
+    fn test_channel_manager_open_until_active() {
+        use lnrpc::open_status_update::Update;
+        let txid = vec![0xab; 32];
+        let channel_point = format!("{}:1", "ab".repeat(32));
+        let mut session = Session::new();
+        session
+            .add_stream(
+                "openChannel",
+                &[
+                    lnrpc::OpenStatusUpdate {
+                        update: Some(Update::ChanPending(lnrpc::PendingUpdate {
+                            txid: txid.clone(),
+                            output_index: 1,
+                        })),
+                        ..Default::default()
+                    },
+                    lnrpc::OpenStatusUpdate {
+                        update: Some(Update::ChanOpen(lnrpc::ChannelOpenUpdate {
+                            channel_point: Some(
+                                crate::parse_channel_point(&channel_point).unwrap(),
+                            ),
+                        })),
+                        ..Default::default()
+                    },
+                ],
+                None,
+            )
+            .add_stream(
+                "subscribeChannelEvents",
+                &[lnrpc::ChannelEventUpdate {
+                    channel: Some(lnrpc::channel_event_update::Channel::ActiveChannel(
+                        crate::parse_channel_point(&channel_point).unwrap(),
+                    )),
+                    ..Default::default()
+                }],
+                None,
+            );
+        let channels = ChannelManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let channel = channels.open(lnrpc::OpenChannelRequest::default()).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            channel
+                .wait_until(timeout, |state| *state == ChannelState::Active)
+                .unwrap(),
+            ChannelState::Active
+        );
+        assert_eq!(channel.channel_point(), Some(channel_point.clone()));
+        assert!(channels.get(&channel_point).is_some());
+    }
+
+    #[test]
+    fn test_channel_manager_recovers_pending_channels() {
+        let channel_point = format!("{}:0", "cd".repeat(32));
+        let mut session = Session::new();
+        session
+            .add_stream(
+                "subscribeChannelEvents",
+                &[lnrpc::ChannelEventUpdate {
+                    channel: Some(lnrpc::channel_event_update::Channel::OpenChannel(
+                        lnrpc::Channel {
+                            active: true,
+                            channel_point: channel_point.clone(),
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                }],
+                None,
+            )
+            .add_response(
+                "pendingChannels",
+                &lnrpc::PendingChannelsResponse {
+                    pending_open_channels: vec![
+                        lnrpc::pending_channels_response::PendingOpenChannel {
+                            channel: Some(lnrpc::pending_channels_response::PendingChannel {
+                                channel_point: channel_point.clone(),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                },
+            );
+        let channels = ChannelManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let recovered = channels.recover().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].channel_point(), Some(channel_point));
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            recovered[0]
+                .wait_until(timeout, |state| *state == ChannelState::Active)
+                .unwrap(),
+            ChannelState::Active
+        );
+    }
+
+    #[test]
+    fn test_channel_manager_close_untracked_channel() {
+        use lnrpc::close_status_update::Update;
+        let channel_point = format!("{}:0", "ef".repeat(32));
+        let mut session = Session::new();
+        session
+            .add_stream::<lnrpc::ChannelEventUpdate>("subscribeChannelEvents", &[], None)
+            .add_response(
+                "listChannels",
+                &lnrpc::ListChannelsResponse {
+                    channels: vec![lnrpc::Channel {
+                        active: false,
+                        channel_point: channel_point.clone(),
+                        ..Default::default()
+                    }],
+                },
+            )
+            .add_stream(
+                "closeChannel",
+                &[
+                    lnrpc::CloseStatusUpdate {
+                        update: Some(Update::ClosePending(lnrpc::PendingUpdate {
+                            txid: vec![0x12; 32],
+                            output_index: 0,
+                        })),
+                    },
+                    lnrpc::CloseStatusUpdate {
+                        update: Some(Update::ChanClose(lnrpc::ChannelCloseUpdate {
+                            closing_txid: vec![0x12; 32],
+                            success: true,
+                        })),
+                    },
+                ],
+                None,
+            );
+        let channels = ChannelManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let channel = channels
+            .close(lnrpc::CloseChannelRequest {
+                channel_point: Some(crate::parse_channel_point(&channel_point).unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            channel.wait_until(timeout, ChannelState::is_final).unwrap(),
+            ChannelState::Closed {
+                closing_txid: "12".repeat(32)
+            }
+        );
+        // Closed channels are no longer followed.
+        let deadline = std::time::Instant::now() + timeout;
+        while channels.get(&channel_point).is_some() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(channels.get(&channel_point).is_none());
+    }
+
+    #[test]
+    fn test_channel_manager_failed_close_keeps_channel() {
+        let channel_point = format!("{}:0", "fe".repeat(32));
+        let mut session = Session::new();
+        session
+            .add_stream::<lnrpc::ChannelEventUpdate>("subscribeChannelEvents", &[], None)
+            .add_response(
+                "listChannels",
+                &lnrpc::ListChannelsResponse {
+                    channels: vec![lnrpc::Channel {
+                        active: true,
+                        channel_point: channel_point.clone(),
+                        ..Default::default()
+                    }],
+                },
+            )
+            .add_stream::<lnrpc::CloseStatusUpdate>("closeChannel", &[], Some("peer is offline"));
+        let channels = ChannelManager::new(LndClient::with_replay(Replay::new(session)));
+
+        let channel = channels
+            .close(lnrpc::CloseChannelRequest {
+                channel_point: Some(crate::parse_channel_point(&channel_point).unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let error = channel
+            .wait_until(timeout, ChannelState::is_final)
+            .unwrap_err();
+        assert!(error.to_string().contains("peer is offline"));
+        // The channel is still open and followed.
+        assert_eq!(channel.state(), ChannelState::Active);
+        assert_eq!(channel.close_error(), Some("peer is offline".to_string()));
+        assert!(channels.get(&channel_point).is_some());
+    }
+
+    fn psbt_funding_session() -> Session {
+        use lnrpc::open_status_update::Update;
+        let mut session = Session::new();
+        session.add_stream(
+            "openChannel",
+            &[
+                lnrpc::OpenStatusUpdate {
+                    update: Some(Update::PsbtFund(lnrpc::ReadyForPsbtFunding {
+                        funding_address: "bcrt1qfunding".to_string(),
+                        funding_amount: 100_000,
+                        psbt: b"template".to_vec(),
+                    })),
+                    ..Default::default()
+                },
+                lnrpc::OpenStatusUpdate {
+                    update: Some(Update::ChanPending(lnrpc::PendingUpdate {
+                        txid: vec![0xef; 32],
+                        output_index: 0,
+                    })),
+                    ..Default::default()
+                },
+            ],
+            None,
+        );
+        session.add_stream::<lnrpc::ChannelEventUpdate>("subscribeChannelEvents", &[], None);
+        session
+    }
+
+    #[test]
+    fn test_psbt_channel_funding_finish() {
+        let mut session = psbt_funding_session();
+        session
+            // Verify and finalize.
+            .add_response("fundingStateStep", &lnrpc::FundingStateStepResp::default())
+            .add_response("fundingStateStep", &lnrpc::FundingStateStepResp::default());
+        let client = LndClient::with_replay(Replay::new(session));
+        let funding = PsbtChannelFunding::new(ChannelManager::new(client));
+
+        let timeout = std::time::Duration::from_secs(5);
+        let pending = funding
+            .start(lnrpc::OpenChannelRequest::default(), timeout)
+            .unwrap();
+        assert_eq!(pending.template(), b"template");
+        assert_eq!(pending.outputs()[0].amount_sat, 100_000);
+
+        let channels = pending.finish(b"signed".to_vec()).unwrap();
+        let state = channels[0]
+            .wait_until(timeout, |state| {
+                matches!(state, ChannelState::Pending { .. })
+            })
+            .unwrap();
+        assert_eq!(
+            state,
+            ChannelState::Pending {
+                txid: "ef".repeat(32)
+            }
+        );
+    }
+
+    #[test]
+    fn test_psbt_channel_funding_cancels_on_failure() {
+        let mut session = psbt_funding_session();
+        session
+            .add_error("fundingStateStep", "invalid PSBT")
+            // The cancel.
+            .add_response("fundingStateStep", &lnrpc::FundingStateStepResp::default());
+        let client = LndClient::with_replay(Replay::new(session));
+        let funding = PsbtChannelFunding::new(ChannelManager::new(client.clone()));
+
+        let timeout = std::time::Duration::from_secs(5);
+        let pending = funding
+            .start(lnrpc::OpenChannelRequest::default(), timeout)
+            .unwrap();
+        assert!(pending.finish(b"signed".to_vec()).is_err());
+
+        // The scripted cancel was used up, and nothing else was sent.
+        let unscripted: anyhow::Result<lnrpc::FundingStateStepResp> = client.call_lnd_method(
+            lnrpc::FundingTransitionMsg::default(),
+            crate::fundingStateStep,
+        );
+        assert!(unscripted.is_err());
+    }
+
+    #[test]
+    fn test_backup_manager_verifies_and_stores() {
+        let snapshot = |backup: &[u8]| lnrpc::ChanBackupSnapshot {
+            multi_chan_backup: Some(lnrpc::MultiChanBackup {
+                multi_chan_backup: backup.to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_stream(
+                "subscribeChannelBackups",
+                &[snapshot(b"corrupt"), snapshot(b"changed")],
+                None,
+            )
+            .add_response("exportAllChannelBackups", &snapshot(b"initial"))
+            .add_response("verifyChanBackup", &lnrpc::VerifyChanBackupResponse {})
+            .add_error("verifyChanBackup", "invalid backup")
+            .add_response("verifyChanBackup", &lnrpc::VerifyChanBackupResponse {});
+
+        let (stored, stored_receiver) = mpsc::channel();
+        let stored = Mutex::new(stored);
+        let (results, result_receiver) = mpsc::channel();
+        let results = Mutex::new(results);
+        let manager = BackupManager::new(
+            LndClient::with_replay(Replay::new(session)),
+            CallbackBackupSink::new(move |backup| {
+                stored
+                    .lock()
+                    .unwrap()
+                    .send(backup.multi_chan_backup.clone())
+                    .unwrap();
+                Ok(())
+            }),
+            move |result| results.lock().unwrap().send(result.is_ok()).unwrap(),
+        );
+        manager.start().unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        let mut outcomes: Vec<bool> = (0..3)
+            .map(|_| result_receiver.recv_timeout(timeout).unwrap())
+            .collect();
+        outcomes.sort();
+        assert_eq!(outcomes, vec![false, true, true]);
+        // Exactly one backup failed verification and was never stored.
+        assert_eq!(stored_receiver.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_file_backup_sink_rotates() {
+        let dir = std::env::temp_dir().join(format!("embedded-lnd-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sink = FileBackupSink::new(dir.join("channel.backup")).keep(2);
+        for backup in [b"one", b"two", b"six", b"ten"] {
+            sink.store(&lnrpc::MultiChanBackup {
+                multi_chan_backup: backup.to_vec(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+
+        assert_eq!(sink.load().unwrap(), Some(b"ten".to_vec()));
+        assert_eq!(std::fs::read(sink.rotated(1)).unwrap(), b"six");
+        assert_eq!(std::fs::read(sink.rotated(2)).unwrap(), b"two");
+        assert!(!sink.rotated(3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_event_bus_fans_out_with_filters() {
+        let mut session = Session::new();
+        session
+            .add_stream(
+                "subscribeInvoices",
+                &[
+                    lnrpc::Invoice {
+                        value: 10,
+                        ..Default::default()
+                    },
+                    lnrpc::Invoice {
+                        value: 5_000,
+                        ..Default::default()
+                    },
+                ],
+                None,
+            )
+            .add_stream(
+                "chainNotifierRegisterBlockEpochNtfn",
+                &[chainrpc::BlockEpoch {
+                    height: 800_000,
+                    ..Default::default()
+                }],
+                Some("EOF"),
+            );
+        let bus = EventBus::new(LndClient::with_replay(Replay::new(session)));
+
+        let (invoices, invoice_receiver) = mpsc::channel();
+        let invoices = Mutex::new(invoices);
+        bus.on::<lnrpc::Invoice, _>(move |invoice| {
+            invoices.lock().unwrap().send(invoice.value).unwrap()
+        });
+        let (large, large_receiver) = mpsc::channel();
+        let large = Mutex::new(large);
+        bus.listen(
+            EventFilter::sources(&[EventSource::Invoices])
+                .matching(|event| matches!(event, NodeEvent::Invoice(i) if i.value > 1_000)),
+            move |event| large.lock().unwrap().send(event.clone()).unwrap(),
+        );
+        let (blocks, block_receiver) = mpsc::channel();
+        let blocks = Mutex::new(blocks);
+        bus.listen(EventFilter::sources(&[EventSource::Blocks]), move |event| {
+            blocks.lock().unwrap().send(event.clone()).unwrap()
+        });
+        bus.start(&[EventSource::Invoices, EventSource::Blocks])
+            .unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(invoice_receiver.recv_timeout(timeout).unwrap(), 10);
+        assert_eq!(invoice_receiver.recv_timeout(timeout).unwrap(), 5_000);
+        match large_receiver.recv_timeout(timeout).unwrap() {
+            NodeEvent::Invoice(invoice) => assert_eq!(invoice.value, 5_000),
+            event => panic!("unexpected event {:?}", event),
+        }
+        match block_receiver.recv_timeout(timeout).unwrap() {
+            NodeEvent::Block(block) => assert_eq!(block.height, 800_000),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(
+            block_receiver.recv_timeout(timeout).unwrap(),
+            NodeEvent::StreamError {
+                source: EventSource::Blocks,
+                error: "EOF".to_string()
+            }
+        );
+        // The failed stream is no longer followed, however early it failed.
+        assert_eq!(bus.sources(), vec![EventSource::Invoices]);
+        assert!(large_receiver
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn test_chain_notifier_reports_reorgs() {
+        use chainrpc::conf_event::Event;
+        let txid = "ab".repeat(32);
+        let conf = |height: u32| chainrpc::ConfEvent {
+            event: Some(Event::Conf(chainrpc::ConfDetails {
+                block_height: height,
+                ..Default::default()
+            })),
+        };
+        let block = |height: u32, hash: u8| chainrpc::BlockEpoch {
+            hash: vec![hash; 32],
+            height,
+        };
+        let mut session = Session::new();
+        session
+            .add_stream(
+                "chainNotifierRegisterConfirmationsNtfn",
+                &[
+                    conf(100),
+                    chainrpc::ConfEvent {
+                        event: Some(Event::Reorg(chainrpc::Reorg {})),
+                    },
+                    conf(101),
+                ],
+                None,
+            )
+            .add_stream(
+                "chainNotifierRegisterBlockEpochNtfn",
+                &[block(100, 1), block(101, 2), block(101, 3)],
+                None,
+            );
+        let notifier = ChainNotifier::new(LndClient::with_replay(Replay::new(session)));
+
+        let watch = notifier
+            .wait_for_confirmations(&txid, b"script", 1, 90)
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        watch.wait(timeout).unwrap();
+        let reorged = watch.reorgs() == 1 || watch.wait_for_reorg(timeout);
+        assert!(reorged);
+        let confirmed = || match watch.state() {
+            WatchState::Done(confirmation) => confirmation.block_height == 101,
+            _ => false,
+        };
+        let deadline = std::time::Instant::now() + timeout;
+        while !confirmed() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(confirmed());
+
+        let (events, receiver) = mpsc::channel();
+        let events = Mutex::new(events);
+        let watcher = notifier
+            .watch_blocks(move |event| events.lock().unwrap().send(event.unwrap()).unwrap())
+            .unwrap();
+        let received: Vec<BlockEvent> = (0..3)
+            .map(|_| receiver.recv_timeout(timeout).unwrap())
+            .collect();
+        assert!(matches!(
+            received[1],
+            BlockEvent::Connected { height: 101, .. }
+        ));
+        assert_eq!(
+            received[2],
+            BlockEvent::Reorg {
+                previous_height: 101,
+                height: 101,
+                hash: "03".repeat(32)
+            }
+        );
+        assert_eq!(watcher.tip().map(|(height, _)| height), Some(101));
+    }
+
+    #[test]
+    fn test_neutrino_manager_rotates_peers() {
+        let status = |peers: &[&str]| neutrinorpc::StatusResponse {
+            active: true,
+            block_height: 100,
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            ..Default::default()
+        };
+        let banned = |banned: bool| neutrinorpc::IsBannedResponse { banned };
+        let mut session = Session::new();
+        session
+            .add_response("neutrinoKitStatus", &status(&[]))
+            .add_response("getInfo", &lnrpc::GetInfoResponse::default())
+            .add_response("neutrinoKitIsBanned", &banned(true))
+            .add_response("neutrinoKitIsBanned", &banned(false))
+            .add_response("neutrinoKitAddPeer", &neutrinorpc::AddPeerResponse {})
+            // The height did not move since the first check.
+            .add_response("neutrinoKitStatus", &status(&["b:18333"]))
+            .add_response("getInfo", &lnrpc::GetInfoResponse::default())
+            .add_response(
+                "neutrinoKitDisconnectPeer",
+                &neutrinorpc::DisconnectPeerResponse {},
+            )
+            .add_response("neutrinoKitIsBanned", &banned(false))
+            .add_response("neutrinoKitAddPeer", &neutrinorpc::AddPeerResponse {});
+        let manager = NeutrinoManager::new(
+            LndClient::with_replay(Replay::new(session)),
+            NeutrinoConfig {
+                peers: vec!["a:18333".to_string(), "b:18333".to_string()],
+                stall_timeout: std::time::Duration::ZERO,
+                ..Default::default()
+            },
+        );
+
+        let events = manager.check().unwrap();
+        assert_eq!(
+            events[1..],
+            [
+                NeutrinoEvent::Banned("a:18333".to_string()),
+                NeutrinoEvent::PeerAdded("b:18333".to_string()),
+            ]
+        );
+        let events = manager.check().unwrap();
+        assert_eq!(
+            events[1..],
+            [
+                NeutrinoEvent::Stalled { block_height: 100 },
+                NeutrinoEvent::PeerDisconnected("b:18333".to_string()),
+                NeutrinoEvent::PeerAdded("a:18333".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_neutrino_manager_keeps_going_after_peer_error() {
+        let status = neutrinorpc::StatusResponse {
+            active: true,
+            block_height: 100,
+            peers: vec!["a:18333".to_string(), "b:18333".to_string()],
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response("neutrinoKitStatus", &status)
+            .add_response("getInfo", &lnrpc::GetInfoResponse::default())
+            .add_response("neutrinoKitStatus", &status)
+            .add_response("getInfo", &lnrpc::GetInfoResponse::default())
+            .add_error("neutrinoKitDisconnectPeer", "peer not found")
+            .add_response(
+                "neutrinoKitDisconnectPeer",
+                &neutrinorpc::DisconnectPeerResponse {},
+            );
+        let manager = NeutrinoManager::new(
+            LndClient::with_replay(Replay::new(session)),
+            NeutrinoConfig {
+                peers: vec!["a:18333".to_string(), "b:18333".to_string()],
+                min_peers: 1,
+                stall_timeout: std::time::Duration::ZERO,
+                ..Default::default()
+            },
+        );
+
+        manager.check().unwrap();
+        let events = manager.check().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1], NeutrinoEvent::Stalled { block_height: 100 });
+        assert!(matches!(&events[2], NeutrinoEvent::Error(error) if error.contains("a:18333")));
+        assert_eq!(
+            events[3],
+            NeutrinoEvent::PeerDisconnected("b:18333".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watchtower_health_check() {
+        let pubkey = format!("02{}", "11".repeat(32));
+        let uri: TowerUri = format!("{}@tower.example.com", pubkey).parse().unwrap();
+        assert_eq!(uri.address, "tower.example.com:9911");
+        assert_eq!(
+            uri.to_string(),
+            format!("{}@tower.example.com:9911", pubkey)
+        );
+        assert!(format!("{}@[::1]:9912", pubkey).parse::<TowerUri>().is_ok());
+        assert!("not-a-key@host".parse::<TowerUri>().is_err());
+
+        let session = |num_backups: u32| wtclientrpc::TowerSession {
+            num_backups,
+            max_backups: 1024,
+            id: vec![num_backups as u8],
+            ..Default::default()
+        };
+        let mut session_log = Session::new();
+        session_log
+            .add_response(
+                "watchtowerClientListTowers",
+                &wtclientrpc::ListTowersResponse {
+                    towers: vec![wtclientrpc::Tower {
+                        pubkey: hex::decode(&pubkey).unwrap(),
+                        addresses: vec![uri.address.clone()],
+                        session_info: vec![wtclientrpc::TowerSessionInfo {
+                            active_session_candidate: true,
+                            num_sessions: 2,
+                            sessions: vec![session(1024), session(1000)],
+                            policy_type: wtclientrpc::PolicyType::Anchor as i32,
+                        }],
+                        ..Default::default()
+                    }],
+                },
+            )
+            .add_response(
+                "watchtowerClientStats",
+                &wtclientrpc::StatsResponse {
+                    num_backups: 2024,
+                    num_pending_backups: 24,
+                    num_failed_backups: 2,
+                    ..Default::default()
+                },
+            );
+        let towers = WatchtowerClient::new(LndClient::with_replay(Replay::new(session_log)));
+
+        let report = towers.health_check().unwrap();
+        assert_eq!(report.towers[0].sessions.len(), 2);
+        assert!(report.towers[0].sessions[0].is_exhausted());
+        // The second session still has room, so only the failed backups are flagged.
+        assert_eq!(report.issues, vec![TowerIssue::FailedBackups(2)]);
+        assert!(report.stats.coverage() > 0.98);
+    }
+
+    #[test]
+    fn test_macaroon_permissions_and_rotation() {
+        let permissions = |list: &[(&str, &str)]| lnrpc::MacaroonPermissionList {
+            permissions: list
+                .iter()
+                .map(|(entity, action)| lnrpc::MacaroonPermission {
+                    entity: entity.to_string(),
+                    action: action.to_string(),
+                })
+                .collect(),
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listPermissions",
+                &lnrpc::ListPermissionsResponse {
+                    method_permissions: [
+                        (
+                            "/lnrpc.Lightning/AddInvoice".to_string(),
+                            permissions(&[("invoices", "write")]),
+                        ),
+                        (
+                            "/lnrpc.Lightning/SendPaymentSync".to_string(),
+                            permissions(&[("offchain", "write")]),
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+            )
+            .add_response(
+                "checkMacaroonPermissions",
+                &lnrpc::CheckMacPermResponse { valid: true },
+            )
+            .add_response(
+                "listMacaroonIDs",
+                &lnrpc::ListMacaroonIDsResponse {
+                    root_key_ids: vec![0, 7],
+                },
+            )
+            .add_response(
+                "bakeMacaroon",
+                &lnrpc::BakeMacaroonResponse {
+                    macaroon: "0201".to_string(),
+                },
+            )
+            .add_response(
+                "deleteMacaroonID",
+                &lnrpc::DeleteMacaroonIdResponse { deleted: true },
+            );
+        let macaroons = Macaroons::new(LndClient::with_replay(Replay::new(session)));
+
+        let invoices = PermissionSet::invoice_only();
+        assert!(invoices.contains_all(&PermissionSet::new().allow(entity::INVOICES, action::WRITE)));
+        assert!(macaroons
+            .allows(&invoices, "/lnrpc.Lightning/AddInvoice")
+            .unwrap());
+        assert!(!macaroons
+            .allows(&invoices, "/lnrpc.Lightning/SendPaymentSync")
+            .unwrap());
+        assert!(macaroons
+            .allows(
+                &PermissionSet::new().allow_uri("/lnrpc.Lightning/SendPaymentSync"),
+                "/lnrpc.Lightning/SendPaymentSync"
+            )
+            .unwrap());
+        // The permissions are cached, so only the check reaches lnd.
+        assert!(macaroons
+            .check("0201", "/lnrpc.Lightning/AddInvoice")
+            .unwrap());
+        assert!(macaroons.check("0201", "/unknown.Method").is_err());
+
+        assert_eq!(
+            macaroons.rotate(&invoices, 7).unwrap(),
+            (8, "0201".to_string())
+        );
+    }
+
+    #[test]
+    fn test_add_first_party_caveat() {
+        let signature = [7u8; 32];
+        let mut macaroon = vec![2, 2, 2, b'i', b'd', 0, 0, 6, 32];
+        macaroon.extend_from_slice(&signature);
+
+        let caveat = "lnd-custom payment-limit 1000";
+        let restricted =
+            hex::decode(add_first_party_caveat(&hex::encode(&macaroon), caveat).unwrap()).unwrap();
+        let mut expected = vec![2, 2, 2, b'i', b'd', 0, 2, caveat.len() as u8];
+        expected.extend_from_slice(caveat.as_bytes());
+        expected.extend_from_slice(&[0, 0, 6, 32]);
+        assert_eq!(&restricted[..expected.len()], &expected[..]);
+        assert_eq!(restricted.len(), expected.len() + 32);
+        assert_ne!(&restricted[expected.len()..], &signature[..]);
+
+        // A second caveat goes after the first.
+        let twice = hex::decode(
+            add_first_party_caveat(
+                &hex::encode(&restricted),
+                "time-before 2030-01-01T00:00:00Z",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            &twice[..expected.len() - 4],
+            &expected[..expected.len() - 4]
+        );
+        assert_eq!(twice[expected.len() - 3], 2);
+        assert!(add_first_party_caveat("0102", caveat).is_err());
+    }
+
+    /// The coinbase transaction of the genesis block.
+    const GENESIS_TX: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+    const GENESIS_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    #[test]
+    fn test_onchain_balance_and_coin_control() {
+        let txid = "aa".repeat(32);
+        let mut session = Session::new();
+        session
+            .add_response(
+                "walletBalance",
+                &lnrpc::WalletBalanceResponse {
+                    confirmed_balance: 50_000,
+                    unconfirmed_balance: 1_000,
+                    locked_balance: 2_000,
+                    ..Default::default()
+                },
+            )
+            .add_response(
+                "walletKitRequiredReserve",
+                &walletrpc::RequiredReserveResponse {
+                    required_reserve: 10_000,
+                },
+            )
+            .add_response(
+                "sendCoins",
+                &lnrpc::SendCoinsResponse {
+                    txid: "bb".repeat(32),
+                },
+            );
+        for publish_error in ["", "already spent"] {
+            session
+                .add_response(
+                    "walletKitFundPsbt",
+                    &walletrpc::FundPsbtResponse {
+                        funded_psbt: b"funded".to_vec(),
+                        change_output_index: 2,
+                        locked_utxos: vec![walletrpc::UtxoLease {
+                            id: vec![1; 32],
+                            outpoint: Some(lnrpc::OutPoint {
+                                txid_str: txid.clone(),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }],
+                    },
+                )
+                .add_response(
+                    "walletKitFinalizePsbt",
+                    &walletrpc::FinalizePsbtResponse {
+                        signed_psbt: b"signed".to_vec(),
+                        raw_final_tx: hex::decode(GENESIS_TX).unwrap(),
+                    },
+                )
+                .add_response(
+                    "walletKitPublishTransaction",
+                    &walletrpc::PublishResponse {
+                        publish_error: publish_error.to_string(),
+                    },
+                );
+        }
+        session.add_response(
+            "walletKitReleaseOutput",
+            &walletrpc::ReleaseOutputResponse::default(),
+        );
+        let wallet = OnchainWallet::new(LndClient::with_replay(Replay::new(session))).unwrap();
+
+        let balance = wallet.balance().unwrap();
+        assert_eq!(
+            balance,
+            WalletBalance {
+                confirmed: 50_000,
+                unconfirmed: 1_000,
+                locked: 2_000,
+                reserved: 10_000,
+            }
+        );
+        assert_eq!(balance.spendable(), 40_000);
+
+        let mut request = SendRequest::new("bcrt1qdest", 25_000, FeePreference::SatPerVbyte(2));
+        request.inputs = vec![format!("{}:0", txid)];
+        assert_eq!(wallet.send(&request).unwrap(), "bb".repeat(32));
+
+        // Invalid inputs fail before anything is sent.
+        request.inputs = vec![txid.clone()];
+        let error = wallet.send(&request).unwrap_err();
+        assert!(error.to_string().contains("Invalid outpoint"));
+
+        // Several outputs are funded, signed and published separately.
+        request.inputs = vec![format!("{}:0", txid)];
+        request.outputs.push(request.outputs[0].clone());
+        assert_eq!(wallet.send(&request).unwrap(), GENESIS_TXID);
+
+        let error = wallet.send(&request).unwrap_err();
+        assert!(error.to_string().contains("already spent"));
+
+        // The witness is left out of the txid.
+        let raw_tx = hex::decode(GENESIS_TX).unwrap();
+        let mut segwit = raw_tx[..4].to_vec();
+        segwit.extend_from_slice(&[0, 1]);
+        segwit.extend_from_slice(&raw_tx[4..raw_tx.len() - 4]);
+        segwit.extend_from_slice(&[1, 2, 0xaa, 0xbb]);
+        segwit.extend_from_slice(&raw_tx[raw_tx.len() - 4..]);
+        assert_eq!(crate::onchain::raw_txid(&segwit).unwrap(), GENESIS_TXID);
+        assert!(crate::onchain::raw_txid(&raw_tx[..raw_tx.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_fee_bumper_caps_sweep_bumps() {
+        let sweep = |byte: &str, sat_per_vbyte| walletrpc::PendingSweep {
+            outpoint: Some(lnrpc::OutPoint {
+                txid_str: byte.repeat(32),
+                output_index: 1,
+                ..Default::default()
+            }),
+            sat_per_vbyte,
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "walletKitEstimateFee",
+                &walletrpc::EstimateFeeResponse {
+                    sat_per_kw: 2_500,
+                    ..Default::default()
+                },
+            )
+            .add_response(
+                "walletKitPendingSweeps",
+                &walletrpc::PendingSweepsResponse {
+                    pending_sweeps: vec![sweep("aa", 5), sweep("bb", 9)],
+                },
+            )
+            .add_response(
+                "walletKitBumpFee",
+                &walletrpc::BumpFeeResponse {
+                    status: "Successfully registered CPFP-tx with the sweeper".to_string(),
+                },
+            );
+        let bumper = FeeBumper::new(
+            LndClient::with_replay(Replay::new(session)),
+            FeeBumpPolicy {
+                max_sat_per_vbyte: 8,
+                ..Default::default()
+            },
+        );
+
+        // The estimate of 10 sat/vbyte is capped at 8, which only the first sweep is
+        // below.
+        assert_eq!(
+            bumper.check().unwrap(),
+            vec![FeeBumpEvent::Bumped {
+                outpoint: format!("{}:1", "aa".repeat(32)),
+                sat_per_vbyte: 8,
+                capped: true,
+                status: "Successfully registered CPFP-tx with the sweeper".to_string(),
+            }]
+        );
+        assert!(bumper
+            .bump(
+                &format!("{}:1", "bb".repeat(32)),
+                FeePreference::SatPerVbyte(20)
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_fee_policy_engine_applies_liquidity_curve() {
+        let channel = |chan_id, local_balance| lnrpc::Channel {
+            chan_id,
+            channel_point: format!("{}:0", format!("{:02x}", chan_id).repeat(32)),
+            remote_pubkey: "02".repeat(33),
+            capacity: 1_000_000,
+            local_balance,
+            ..Default::default()
+        };
+        // The peer is node 1, so our policy is node 2's.
+        let edge = |channel_id, time_lock_delta| lnrpc::ChannelEdge {
+            channel_id,
+            node1_pub: "02".repeat(33),
+            node2_pub: "03".repeat(33),
+            node2_policy: Some(lnrpc::RoutingPolicy {
+                time_lock_delta,
+                min_htlc: 1000,
+                max_htlc_msat: 990_000_000,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let fees = |chan_id, fee_per_mil| lnrpc::ChannelFeeReport {
+            chan_id,
+            base_fee_msat: 1000,
+            fee_per_mil,
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listChannels",
+                &lnrpc::ListChannelsResponse {
+                    channels: vec![channel(1, 250_000), channel(2, 500_000)],
+                },
+            )
+            .add_response(
+                "feeReport",
+                &lnrpc::FeeReportResponse {
+                    channel_fees: vec![fees(1, 500), fees(2, 545)],
+                    ..Default::default()
+                },
+            )
+            .add_response(
+                "forwardingHistory",
+                &lnrpc::ForwardingHistoryResponse {
+                    forwarding_events: vec![lnrpc::ForwardingEvent {
+                        chan_id_out: 1,
+                        amt_out_msat: 5_000_000,
+                        fee_msat: 3_000,
+                        ..Default::default()
+                    }],
+                    last_offset_index: 1,
+                },
+            )
+            .add_response("getChanInfo", &edge(1, 80))
+            .add_response(
+                "updateChannelPolicy",
+                &lnrpc::PolicyUpdateResponse::default(),
+            )
+            .add_response("getChanInfo", &edge(2, 40))
+            .add_response(
+                "updateChannelPolicy",
+                &lnrpc::PolicyUpdateResponse::default(),
+            );
+        assert!(FeePolicyEngine::new(
+            LndClient::with_replay(Replay::new(Session::new())),
+            FeePolicyConfig {
+                rules: vec![PolicyRule::LiquidityCurve {
+                    min_ppm: 100,
+                    max_ppm: 1000,
+                    exponent: -1.0,
+                }],
+                ..Default::default()
+            },
+        )
+        .is_err());
+        let engine = FeePolicyEngine::new(
+            LndClient::with_replay(Replay::new(session)),
+            FeePolicyConfig {
+                rules: vec![PolicyRule::LiquidityCurve {
+                    min_ppm: 100,
+                    max_ppm: 1000,
+                    exponent: 1.0,
+                }],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Channel 1 is 25% local, so 100 + 900 * 0.75 ppm. Channel 2 would move from
+        // 545 to 550 ppm, which is below the minimum change, but its time-lock delta
+        // differs from the configured one.
+        let applied = engine.run_once().unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[0].chan_id, 1);
+        assert_eq!(applied[0].previous.fee_rate_ppm, 500);
+        assert_eq!(applied[0].previous.min_htlc_msat, Some(1000));
+        assert_eq!(applied[0].policy.fee_rate_ppm, 775);
+        assert_eq!(applied[0].policy.base_fee_msat, 1000);
+        assert!(!applied[0].dry_run);
+        assert_eq!(applied[0].error, None);
+        assert_eq!(applied[1].chan_id, 2);
+        assert_eq!(applied[1].previous.time_lock_delta, 40);
+        assert_eq!(applied[1].policy.time_lock_delta, 80);
+        assert_eq!(engine.history(), applied);
+    }
+
+    #[test]
+    fn test_forwarding_history_pages_and_exports() {
+        let event = |seconds: u64, chan_id_out, fee_msat| lnrpc::ForwardingEvent {
+            timestamp_ns: seconds * 1_000_000_000,
+            chan_id_in: 9,
+            chan_id_out,
+            amt_out_msat: 100_000,
+            fee_msat,
+            ..Default::default()
+        };
+        let page = |events: Vec<lnrpc::ForwardingEvent>, last_offset_index| {
+            lnrpc::ForwardingHistoryResponse {
+                forwarding_events: events,
+                last_offset_index,
+            }
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "forwardingHistory",
+                &page(vec![event(3_600, 1, 10), event(3_700, 2, 30)], 2),
+            )
+            .add_response("forwardingHistory", &page(vec![event(7_300, 1, 5)], 3));
+        let client = LndClient::with_replay(Replay::new(session));
+
+        // The short second page ends the iteration without a third call.
+        let events: Vec<lnrpc::ForwardingEvent> = ForwardingHistory::new(client)
+            .page_size(2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(events.len(), 3);
+
+        let channels = fees_by_channel(&events);
+        assert_eq!(
+            channels
+                .iter()
+                .map(|channel| (channel.chan_id, channel.forwards, channel.fee_msat))
+                .collect::<Vec<_>>(),
+            vec![(2, 1, 30), (1, 2, 15)]
+        );
+        let buckets = volume_by_bucket(&events, std::time::Duration::from_secs(3_600));
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|bucket| (bucket.start, bucket.forwards))
+                .collect::<Vec<_>>(),
+            vec![(3_600, 2), (7_200, 1)]
+        );
+
+        assert_eq!(
+            to_csv(&channels),
+            "chan_id,forwards,volume_msat,fee_msat\n2,1,100000,30\n1,2,200000,15\n"
+        );
+        let failed = FailedForwards {
+            incoming_chan_id: 1,
+            outgoing_chan_id: 2,
+            link_failures: 1,
+            last_failure: "insufficient \"balance\", retry".to_string(),
+            ..Default::default()
+        };
+        assert!(to_csv(std::slice::from_ref(&failed))
+            .ends_with(",\"insufficient \"\"balance\"\", retry\"\n"));
+        assert_eq!(
+            to_json(&[failed]),
+            "[{\"incoming_chan_id\":1,\"outgoing_chan_id\":2,\"link_failures\":1,\
+             \"downstream_failures\":0,\"last_failure\":\"insufficient \\\"balance\\\", retry\"}]"
+        );
+    }
+
+    #[test]
+    fn test_failed_forward_counter() {
+        let forward = |event| routerrpc::HtlcEvent {
+            incoming_channel_id: 1,
+            outgoing_channel_id: 2,
+            event_type: routerrpc::htlc_event::EventType::Forward as i32,
+            event: Some(event),
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session.add_stream(
+            "routerSubscribeHtlcEvents",
+            &[
+                forward(routerrpc::htlc_event::Event::LinkFailEvent(
+                    routerrpc::LinkFailEvent {
+                        failure_string: "insufficient bandwidth".to_string(),
+                        ..Default::default()
+                    },
+                )),
+                forward(routerrpc::htlc_event::Event::ForwardFailEvent(
+                    routerrpc::ForwardFailEvent {},
+                )),
+                forward(routerrpc::htlc_event::Event::SettleEvent(
+                    routerrpc::SettleEvent::default(),
+                )),
+            ],
+            None,
+        );
+        let counter = FailedForwardCounter::new(LndClient::with_replay(Replay::new(session)));
+        counter.start().unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while counter.counts().first().map_or(0, |failed| {
+            failed.link_failures + failed.downstream_failures
+        }) < 2
+        {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        counter.stop();
+        let counts = counter.counts();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].link_failures, 1);
+        assert_eq!(counts[0].downstream_failures, 1);
+        assert_eq!(counts[0].last_failure, "insufficient bandwidth");
+    }
+
+    #[test]
+    fn test_paginated_lists() {
+        let invoice = |add_index| lnrpc::Invoice {
+            add_index,
+            ..Default::default()
+        };
+        let transaction = |block_height| lnrpc::Transaction {
+            tx_hash: format!("{}", block_height),
+            block_height,
+            ..Default::default()
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "listInvoices",
+                &lnrpc::ListInvoiceResponse {
+                    invoices: vec![invoice(5), invoice(4)],
+                    first_index_offset: 4,
+                    last_index_offset: 5,
+                },
+            )
+            .add_response(
+                "listInvoices",
+                &lnrpc::ListInvoiceResponse {
+                    invoices: vec![invoice(3), invoice(2)],
+                    first_index_offset: 2,
+                    last_index_offset: 3,
+                },
+            )
+            .add_response(
+                "listInvoices",
+                &lnrpc::ListInvoiceResponse {
+                    invoices: vec![invoice(1)],
+                    first_index_offset: 1,
+                    last_index_offset: 1,
+                },
+            )
+            .add_response(
+                "getTransactions",
+                &lnrpc::TransactionDetails {
+                    transactions: vec![transaction(21), transaction(25)],
+                },
+            )
+            .add_response("getTransactions", &lnrpc::TransactionDetails::default())
+            .add_response(
+                "getTransactions",
+                &lnrpc::TransactionDetails {
+                    transactions: vec![transaction(3)],
+                },
+            )
+            .add_response(
+                "getTransactions",
+                &lnrpc::TransactionDetails {
+                    transactions: vec![transaction(12)],
+                },
+            )
+            .add_response("getTransactions", &lnrpc::TransactionDetails::default());
+        let client = LndClient::with_replay(Replay::new(session));
+
+        // The short last page ends the iteration, so no fourth call is made.
+        let invoices: Vec<u64> = InvoiceList::new(client.clone())
+            .page_size(2)
+            .reversed()
+            .map(|invoice| invoice.unwrap().add_index)
+            .collect();
+        assert_eq!(invoices, vec![5, 4, 3, 2, 1]);
+
+        // Blocks 0 to 25 in windows of 10, newest first, skipping the empty window.
+        let transactions: Vec<i32> = TransactionList::new(client.clone())
+            .blocks(0, 25)
+            .page_size(10)
+            .reversed()
+            .map(|tx| tx.unwrap().block_height)
+            .collect();
+        assert_eq!(transactions, vec![25, 21, 3]);
+
+        // The window of block 0 alone is not fetched, as lnd would read its end height
+        // as the unconfirmed tip.
+        let transactions: Vec<i32> = TransactionList::new(client)
+            .blocks(0, 20)
+            .page_size(10)
+            .reversed()
+            .map(|tx| tx.unwrap().block_height)
+            .collect();
+        assert_eq!(transactions, vec![12]);
+    }
+
+    /// Scripts the calls every rebalance of 100k sat out of channel 1 starts with, for a
+    /// strict replay.
+    fn script_rebalance_start(session: &mut Session, own: &str) {
+        session
+            .add_call(
+                "getInfo",
+                &lnrpc::GetInfoRequest {},
+                &lnrpc::GetInfoResponse {
+                    identity_pubkey: own.to_string(),
+                    ..Default::default()
+                },
+            )
+            .add_call(
+                "addInvoice",
+                &lnrpc::Invoice {
+                    memo: "Rebalance from 1".to_string(),
+                    value_msat: 100_000_000,
+                    ..Default::default()
+                },
+                &lnrpc::AddInvoiceResponse {
+                    r_hash: vec![7; 32],
+                    payment_addr: vec![8; 32],
+                    ..Default::default()
+                },
+            );
+    }
+
+    /// The `routerSendToRouteV2` request a rebalance started by `script_rebalance_start`
+    /// sends for `route`.
+    fn rebalance_send(route: &lnrpc::Route) -> routerrpc::SendToRouteRequest {
+        let mut route = route.clone();
+        if let Some(last) = route.hops.last_mut() {
+            last.mpp_record = Some(lnrpc::MppRecord {
+                payment_addr: vec![8; 32],
+                total_amt_msat: 100_000_000,
+            });
+        }
+        routerrpc::SendToRouteRequest {
+            payment_hash: vec![7; 32],
+            route: Some(route),
+            skip_temp_err: true,
+        }
+    }
+
+    fn rebalance_hop(chan_id: u64, pub_key: &str, fee_msat: i64) -> lnrpc::Hop {
+        lnrpc::Hop {
+            chan_id,
+            pub_key: pub_key.to_string(),
+            fee_msat,
+            ..Default::default()
+        }
+    }
+
+    fn rebalance_route(hops: Vec<lnrpc::Hop>) -> lnrpc::Route {
+        lnrpc::Route {
+            total_fees_msat: hops.iter().map(|hop| hop.fee_msat).sum(),
+            hops,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rebalancer_retries_alternative_route() {
+        let own = "02".repeat(33);
+        let hop = rebalance_hop;
+        let first = rebalance_route(vec![
+            hop(1, &"03".repeat(33), 1000),
+            hop(2, &"04".repeat(33), 1000),
+            hop(3, &own, 0),
+        ]);
+        let second = rebalance_route(vec![
+            hop(1, &"03".repeat(33), 1000),
+            hop(4, &"05".repeat(33), 500),
+            hop(3, &own, 0),
+        ]);
+        let expensive = rebalance_route(vec![
+            hop(1, &"03".repeat(33), 1000),
+            hop(5, &"06".repeat(33), 90_000),
+            hop(3, &own, 0),
+        ]);
+        let query = |ignored_pairs: Vec<lnrpc::NodePair>| lnrpc::QueryRoutesRequest {
+            pub_key: own.clone(),
+            amt: 100_000,
+            final_cltv_delta: 40,
+            fee_limit: Some(lnrpc::FeeLimit {
+                limit: Some(lnrpc::fee_limit::Limit::FixedMsat(5_000)),
+            }),
+            use_mission_control: true,
+            ignored_pairs,
+            outgoing_chan_id: 1,
+            last_hop_pubkey: hex::decode("05".repeat(33)).unwrap(),
+            ..Default::default()
+        };
+        let pair = |from: &str, to: &str| lnrpc::NodePair {
+            from: hex::decode(from).unwrap(),
+            to: hex::decode(to).unwrap(),
+        };
+        // 06 charges the 90k msat fee for forwarding to us, and 04 fails forwarding to
+        // us.
+        let too_expensive = pair(&"06".repeat(33), &own);
+        let failed = pair(&"04".repeat(33), &own);
+
+        let mut session = Session::new();
+        script_rebalance_start(&mut session, &own);
+        session
+            .add_call(
+                "queryRoutes",
+                &query(vec![]),
+                &lnrpc::QueryRoutesResponse {
+                    routes: vec![expensive],
+                    success_prob: 0.5,
+                },
+            )
+            .add_call(
+                "queryRoutes",
+                &query(vec![too_expensive.clone()]),
+                &lnrpc::QueryRoutesResponse {
+                    routes: vec![first.clone()],
+                    success_prob: 0.5,
+                },
+            )
+            .add_call(
+                "routerSendToRouteV2",
+                &rebalance_send(&first),
+                &lnrpc::HtlcAttempt {
+                    status: lnrpc::htlc_attempt::HtlcStatus::Failed as i32,
+                    failure: Some(lnrpc::Failure {
+                        code: lnrpc::failure::FailureCode::TemporaryChannelFailure as i32,
+                        failure_source_index: 2,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .add_call(
+                "queryRoutes",
+                &query(vec![too_expensive, failed]),
+                &lnrpc::QueryRoutesResponse {
+                    routes: vec![second.clone()],
+                    success_prob: 0.5,
+                },
+            )
+            .add_call(
+                "routerSendToRouteV2",
+                &rebalance_send(&second),
+                &lnrpc::HtlcAttempt {
+                    status: lnrpc::htlc_attempt::HtlcStatus::Succeeded as i32,
+                    ..Default::default()
+                },
+            );
+        let client = LndClient::with_replay(Replay::new(session).strict(true));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        let report = Rebalancer::new(client)
+            .rebalance(
+                &RebalanceRequest {
+                    outgoing_chan_id: 1,
+                    last_hop_pubkey: "05".repeat(33),
+                    amount_sat: 100_000,
+                    max_fee_msat: 5_000,
+                    max_attempts: 5,
+                    routes: RouteSource::Query,
+                },
+                move |attempt| seen_clone.lock().unwrap().push(attempt.number),
+            )
+            .unwrap();
+
+        assert!(report.succeeded);
+        assert_eq!(report.fee_msat, Some(1500));
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(report.attempts[0].outcome, AttemptOutcome::TooExpensive);
+        assert_eq!(
+            report.attempts[1].outcome,
+            AttemptOutcome::Failed {
+                code: lnrpc::failure::FailureCode::TemporaryChannelFailure,
+                source_index: 2,
+            }
+        );
+        assert_eq!(report.attempts[2].chan_ids, vec![1, 4, 3]);
+    }
+
+    #[test]
+    fn test_rebalancer_builds_routes_from_hops() {
+        let own = "02".repeat(33);
+        let hop = rebalance_hop;
+        let built = rebalance_route(vec![
+            hop(1, &"03".repeat(33), 1000),
+            hop(2, &"04".repeat(33), 1000),
+            hop(3, &"05".repeat(33), 500),
+            hop(4, &own, 0),
+        ]);
+
+        let mut session = Session::new();
+        script_rebalance_start(&mut session, &own);
+        session
+            .add_call(
+                "listChannels",
+                &lnrpc::ListChannelsRequest::default(),
+                &lnrpc::ListChannelsResponse {
+                    channels: vec![
+                        lnrpc::Channel {
+                            chan_id: 7,
+                            remote_pubkey: "09".repeat(33),
+                            ..Default::default()
+                        },
+                        lnrpc::Channel {
+                            chan_id: 1,
+                            remote_pubkey: "03".repeat(33),
+                            ..Default::default()
+                        },
+                    ],
+                },
+            )
+            // The route starts at the outgoing channel's peer and ends with us.
+            .add_call(
+                "routerBuildRoute",
+                &routerrpc::BuildRouteRequest {
+                    amt_msat: 100_000_000,
+                    final_cltv_delta: 40,
+                    outgoing_chan_id: 1,
+                    hop_pubkeys: ["03", "04", "05", "02"]
+                        .iter()
+                        .map(|byte| hex::decode(byte.repeat(33)).unwrap())
+                        .collect(),
+                    payment_addr: vec![8; 32],
+                },
+                &routerrpc::BuildRouteResponse {
+                    route: Some(built.clone()),
+                },
+            )
+            .add_call(
+                "routerSendToRouteV2",
+                &rebalance_send(&built),
+                &lnrpc::HtlcAttempt {
+                    status: lnrpc::htlc_attempt::HtlcStatus::Succeeded as i32,
+                    ..Default::default()
+                },
+            );
+        let client = LndClient::with_replay(Replay::new(session).strict(true));
+
+        let report = Rebalancer::new(client)
+            .rebalance(
+                &RebalanceRequest {
+                    outgoing_chan_id: 1,
+                    last_hop_pubkey: "05".repeat(33),
+                    amount_sat: 100_000,
+                    max_fee_msat: 5_000,
+                    max_attempts: 5,
+                    routes: RouteSource::Hops(vec![vec!["04".repeat(33)]]),
+                },
+                |_| {},
+            )
+            .unwrap();
+
+        assert!(report.succeeded);
+        assert_eq!(report.fee_msat, Some(2500));
+        assert_eq!(report.attempts.len(), 1);
+        assert_eq!(report.attempts[0].chan_ids, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_prober_imports_failures_and_caches() {
+        let dest = "05".repeat(33);
+        let route = lnrpc::Route {
+            total_fees_msat: 2000,
+            hops: vec![
+                lnrpc::Hop {
+                    chan_id: 1,
+                    pub_key: "03".repeat(33),
+                    amt_to_forward_msat: 10_001_000,
+                    fee_msat: 1000,
+                    ..Default::default()
+                },
+                lnrpc::Hop {
+                    chan_id: 2,
+                    pub_key: "04".repeat(33),
+                    amt_to_forward_msat: 10_000_000,
+                    fee_msat: 1000,
+                    ..Default::default()
+                },
+                lnrpc::Hop {
+                    chan_id: 3,
+                    pub_key: dest.clone(),
+                    amt_to_forward_msat: 10_000_000,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let probability = |probability: f64| routerrpc::QueryProbabilityResponse {
+            probability,
+            history: None,
+        };
+
+        let mut session = Session::new();
+        session
+            .add_response(
+                "routerEstimateRouteFee",
+                &routerrpc::RouteFeeResponse {
+                    routing_fee_msat: 1500,
+                    time_lock_delay: 120,
+                    ..Default::default()
+                },
+            )
+            .add_response(
+                "queryRoutes",
+                &lnrpc::QueryRoutesResponse {
+                    routes: vec![route],
+                    success_prob: 0.4,
+                },
+            )
+            .add_response(
+                "getInfo",
+                &lnrpc::GetInfoResponse {
+                    identity_pubkey: "02".repeat(33),
+                    ..Default::default()
+                },
+            )
+            .add_response("routerQueryProbability", &probability(1.0))
+            .add_response("routerQueryProbability", &probability(0.5))
+            .add_response("routerQueryProbability", &probability(0.8))
+            .add_response(
+                "routerSendToRouteV2",
+                &lnrpc::HtlcAttempt {
+                    status: lnrpc::htlc_attempt::HtlcStatus::Failed as i32,
+                    failure: Some(lnrpc::Failure {
+                        code: lnrpc::failure::FailureCode::TemporaryChannelFailure as i32,
+                        failure_source_index: 2,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .add_response(
+                "routerXImportMissionControl",
+                &routerrpc::XImportMissionControlResponse {},
+            )
+            .add_error(
+                "routerEstimateRouteFee",
+                "unable to find a path to destination",
+            );
+        let client = LndClient::with_replay(Replay::new(session));
+        let prober = Prober::new(client);
+
+        let estimate = prober.estimate(&dest, 10_000).unwrap();
+        assert_eq!((estimate.min_fee_msat, estimate.max_fee_msat), (1500, 2000));
+        assert_eq!(estimate.time_lock_delay, 120);
+        assert!((estimate.probability - 0.4).abs() < 1e-9);
+        assert_eq!(
+            estimate.probe,
+            Some(ProbeOutcome::Failed {
+                code: lnrpc::failure::FailureCode::TemporaryChannelFailure,
+                source_index: 2,
+            })
+        );
+
+        // Served from the cache, as nothing more is scripted.
+        assert_eq!(prober.estimate(&dest, 10_000).unwrap(), estimate);
+        prober.clear_cache();
+
+        // Without a path there is nothing to query or probe.
+        let estimate = prober.estimate(&dest, 10_000).unwrap();
+        assert_eq!((estimate.min_fee_msat, estimate.max_fee_msat), (0, 0));
+        assert_eq!(estimate.probability, 0.0);
+        assert_eq!(estimate.probe, None);
+        assert!(prober.estimate(&dest, 20_000).is_err());
+    }
+
+    #[test]
+    fn test_mission_control_export_and_merge_import() {
+        let now = crate::pagination::unix_seconds(std::time::SystemTime::now()) as i64;
+        let pair = |from: &str, to: &str, fail_time: i64| routerrpc::PairHistory {
+            node_from: hex::decode(from.repeat(33)).unwrap(),
+            node_to: hex::decode(to.repeat(33)).unwrap(),
+            history: Some(routerrpc::PairData {
+                fail_time,
+                fail_amt_msat: 5_000_000,
+                ..Default::default()
+            }),
+        };
+        let mut session = Session::new();
+        session
+            .add_response(
+                "routerQueryMissionControl",
+                &routerrpc::QueryMissionControlResponse {
+                    pairs: vec![
+                        pair("02", "03", now - 10),
+                        pair("03", "04", now - 86_400 * 30),
+                    ],
+                },
+            )
+            .add_response(
+                "routerQueryMissionControl",
+                &routerrpc::QueryMissionControlResponse {
+                    pairs: vec![pair("02", "03", now)],
+                },
+            )
+            .add_response(
+                "routerXImportMissionControl",
+                &routerrpc::XImportMissionControlResponse {},
+            );
+        let client = LndClient::with_replay(Replay::new(session));
+        let mission_control = MissionControl::new(client);
+
+        let exported = mission_control.export().unwrap();
+        assert!(exported.starts_with("lnd-mission-control 1\n"));
+        let pairs = decode_pairs(&exported).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].from, "02".repeat(33));
+        assert_eq!(encode_pairs(&pairs), exported);
+        assert!(decode_pairs("lnd-mission-control 2\n").is_err());
+
+        // The first pair is older than what lnd has now, the second too old to keep.
+        let extra = PairRecord {
+            from: "05".repeat(33),
+            to: "06".repeat(33),
+            success_time: now - 60,
+            success_amt_msat: 1_000_000,
+            ..Default::default()
+        };
+        let text = encode_pairs(&[pairs[0].clone(), pairs[1].clone(), extra]);
+        let summary = mission_control
+            .import(
+                &text,
+                &ImportOptions {
+                    merge: true,
+                    max_age: Some(std::time::Duration::from_secs(86_400)),
+                },
+            )
+            .unwrap();
+        assert_eq!((summary.imported, summary.skipped), (1, 2));
+    }
 }
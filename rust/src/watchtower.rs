@@ -0,0 +1,398 @@
+use crate::{
+    watchtowerClientAddTower, watchtowerClientDeactivateTower, watchtowerClientGetTowerInfo,
+    watchtowerClientListTowers, watchtowerClientPolicy, watchtowerClientRemoveTower,
+    watchtowerClientStats, watchtowerClientTerminateSession, watchtowerGetInfo, LndClient,
+};
+use anyhow::{Context, Result};
+use lnd_grpc_rust::wtclientrpc::PolicyType;
+use lnd_grpc_rust::{watchtowerrpc, wtclientrpc};
+use std::fmt;
+use std::str::FromStr;
+
+/// The port a tower listens on when its URI does not name one.
+const DEFAULT_TOWER_PORT: u16 = 9911;
+
+/// A tower address in the `pubkey@host[:port]` form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TowerUri {
+    /// The tower's public key.
+    pub pubkey: Vec<u8>,
+    /// The tower's `host:port`.
+    pub address: String,
+}
+
+impl FromStr for TowerUri {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let (pubkey, host) = uri
+            .split_once('@')
+            .with_context(|| format!("Invalid tower URI {}, expected pubkey@host", uri))?;
+        let pubkey =
+            hex::decode(pubkey).with_context(|| format!("Invalid tower key in {}", uri))?;
+        if pubkey.len() != 33 || host.is_empty() {
+            return Err(anyhow::anyhow!("Invalid tower URI {}", uri));
+        }
+        let address = if host.starts_with('[') {
+            if host.contains("]:") {
+                host.to_string()
+            } else {
+                format!("{}:{}", host, DEFAULT_TOWER_PORT)
+            }
+        } else if host.matches(':').count() > 1 {
+            // A bare IPv6 address.
+            format!("[{}]:{}", host, DEFAULT_TOWER_PORT)
+        } else if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_TOWER_PORT)
+        };
+        Ok(Self { pubkey, address })
+    }
+}
+
+impl fmt::Display for TowerUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", hex::encode(&self.pubkey), self.address)
+    }
+}
+
+/// The kind of channels a session backs up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TowerPolicy {
+    /// Channels without anchor outputs.
+    Legacy,
+    /// Anchor channels.
+    Anchor,
+    /// Taproot channels.
+    Taproot,
+}
+
+impl TowerPolicy {
+    fn from_lnd(policy: PolicyType) -> Self {
+        match policy {
+            PolicyType::Legacy => TowerPolicy::Legacy,
+            PolicyType::Anchor => TowerPolicy::Anchor,
+            PolicyType::Taproot => TowerPolicy::Taproot,
+        }
+    }
+
+    fn to_lnd(self) -> PolicyType {
+        match self {
+            TowerPolicy::Legacy => PolicyType::Legacy,
+            TowerPolicy::Anchor => PolicyType::Anchor,
+            TowerPolicy::Taproot => PolicyType::Taproot,
+        }
+    }
+}
+
+/// A session negotiated with a tower.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The session id, hex encoded.
+    pub id: String,
+    /// The kind of channels the session backs up.
+    pub policy: TowerPolicy,
+    /// The backups acknowledged by the tower.
+    pub num_backups: u32,
+    /// The backups not acknowledged yet.
+    pub num_pending_backups: u32,
+    /// How many backups the session can hold.
+    pub max_backups: u32,
+    /// The fee rate the tower sweeps with, in sat/vbyte.
+    pub sweep_sat_per_vbyte: u32,
+}
+
+impl SessionInfo {
+    /// Returns `true` if the session cannot take any more backups.
+    pub fn is_exhausted(&self) -> bool {
+        self.num_backups.saturating_add(self.num_pending_backups) >= self.max_backups
+    }
+}
+
+/// A tower known to the watchtower client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TowerInfo {
+    /// The tower's public key, hex encoded.
+    pub pubkey: String,
+    /// The addresses the tower is reached at.
+    pub addresses: Vec<String>,
+    /// Whether new sessions may be negotiated with the tower.
+    pub active: bool,
+    /// The sessions with the tower.
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl TowerInfo {
+    #[allow(deprecated)]
+    fn from_lnd(tower: wtclientrpc::Tower) -> Self {
+        let session = |policy: TowerPolicy| {
+            move |session: wtclientrpc::TowerSession| SessionInfo {
+                id: hex::encode(&session.id),
+                policy,
+                num_backups: session.num_backups,
+                num_pending_backups: session.num_pending_backups,
+                max_backups: session.max_backups,
+                sweep_sat_per_vbyte: session.sweep_sat_per_vbyte,
+            }
+        };
+        // Older lnd versions only report the legacy `sessions` and activity fields.
+        let (active, sessions) = if tower.session_info.is_empty() {
+            let sessions = tower
+                .sessions
+                .into_iter()
+                .map(session(TowerPolicy::Legacy))
+                .collect();
+            (tower.active_session_candidate, sessions)
+        } else {
+            let active = tower
+                .session_info
+                .iter()
+                .any(|info| info.active_session_candidate);
+            let sessions = tower
+                .session_info
+                .into_iter()
+                .flat_map(|info| {
+                    let policy = TowerPolicy::from_lnd(info.policy_type());
+                    info.sessions.into_iter().map(session(policy))
+                })
+                .collect();
+            (active, sessions)
+        };
+        Self {
+            pubkey: hex::encode(&tower.pubkey),
+            addresses: tower.addresses,
+            active,
+            sessions,
+        }
+    }
+}
+
+/// How well the channel states are backed up, across all towers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoverageStats {
+    /// The backups acknowledged by a tower.
+    pub num_backups: u32,
+    /// The backups not acknowledged yet.
+    pub num_pending_backups: u32,
+    /// The backups that were rejected by a tower.
+    pub num_failed_backups: u32,
+    /// The sessions negotiated.
+    pub num_sessions_acquired: u32,
+    /// The sessions that are full.
+    pub num_sessions_exhausted: u32,
+}
+
+impl CoverageStats {
+    /// Returns the fraction of backups acknowledged by a tower, or 1 if there were none.
+    pub fn coverage(&self) -> f64 {
+        let total = self.num_backups as f64
+            + self.num_pending_backups as f64
+            + self.num_failed_backups as f64;
+        if total == 0.0 {
+            1.0
+        } else {
+            self.num_backups as f64 / total
+        }
+    }
+}
+
+/// The backup parameters of a policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TowerPolicyInfo {
+    /// How many updates a new session can hold.
+    pub max_updates: u32,
+    /// The fee rate towers sweep with, in sat/vbyte.
+    pub sweep_sat_per_vbyte: u32,
+}
+
+/// A problem found by `WatchtowerClient::health_check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TowerIssue {
+    /// No tower is active, so nothing is backed up.
+    NoActiveTower,
+    /// Every session with the tower is full.
+    SessionsExhausted {
+        /// The tower's public key, hex encoded.
+        pubkey: String,
+    },
+    /// Towers rejected backups.
+    FailedBackups(u32),
+}
+
+/// The result of `WatchtowerClient::health_check`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// The towers as they were checked.
+    pub towers: Vec<TowerInfo>,
+    /// The backup statistics.
+    pub stats: CoverageStats,
+    /// The problems found.
+    pub issues: Vec<TowerIssue>,
+}
+
+impl HealthReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Manages the towers lnd backs up channel states to.
+#[derive(Clone)]
+pub struct WatchtowerClient {
+    client: LndClient,
+}
+
+impl WatchtowerClient {
+    /// Creates a watchtower client.
+    pub fn new(client: LndClient) -> Self {
+        Self { client }
+    }
+
+    /// Adds a tower from a `pubkey@host[:port]` URI. The port defaults to 9911.
+    pub fn add_tower(&self, uri: &str) -> Result<TowerUri> {
+        let uri: TowerUri = uri.parse()?;
+        let _: wtclientrpc::AddTowerResponse = self.client.call_lnd_method(
+            wtclientrpc::AddTowerRequest {
+                pubkey: uri.pubkey.clone(),
+                address: uri.address.clone(),
+            },
+            watchtowerClientAddTower,
+        )?;
+        Ok(uri)
+    }
+
+    /// Removes a tower, or only one of its addresses if `address` is given.
+    pub fn remove_tower(&self, pubkey: &str, address: Option<&str>) -> Result<()> {
+        let _: wtclientrpc::RemoveTowerResponse = self.client.call_lnd_method(
+            wtclientrpc::RemoveTowerRequest {
+                pubkey: decode_pubkey(pubkey)?,
+                address: address.unwrap_or_default().to_string(),
+            },
+            watchtowerClientRemoveTower,
+        )?;
+        Ok(())
+    }
+
+    /// Stops negotiating new sessions with a tower, keeping the existing ones.
+    pub fn deactivate_tower(&self, pubkey: &str) -> Result<String> {
+        let response: wtclientrpc::DeactivateTowerResponse = self.client.call_lnd_method(
+            wtclientrpc::DeactivateTowerRequest {
+                pubkey: decode_pubkey(pubkey)?,
+            },
+            watchtowerClientDeactivateTower,
+        )?;
+        Ok(response.status)
+    }
+
+    /// Stops using a session, e.g. one with a misbehaving tower.
+    pub fn terminate_session(&self, session_id: &str) -> Result<String> {
+        let response: wtclientrpc::TerminateSessionResponse = self.client.call_lnd_method(
+            wtclientrpc::TerminateSessionRequest {
+                session_id: hex::decode(session_id)
+                    .with_context(|| format!("Invalid session id {}", session_id))?,
+            },
+            watchtowerClientTerminateSession,
+        )?;
+        Ok(response.status)
+    }
+
+    /// Lists the towers with their sessions.
+    pub fn list_towers(&self) -> Result<Vec<TowerInfo>> {
+        let response: wtclientrpc::ListTowersResponse = self.client.call_lnd_method(
+            wtclientrpc::ListTowersRequest {
+                include_sessions: true,
+                exclude_exhausted_sessions: false,
+            },
+            watchtowerClientListTowers,
+        )?;
+        Ok(response
+            .towers
+            .into_iter()
+            .map(TowerInfo::from_lnd)
+            .collect())
+    }
+
+    /// Returns one tower with its sessions.
+    pub fn tower(&self, pubkey: &str) -> Result<TowerInfo> {
+        let tower: wtclientrpc::Tower = self.client.call_lnd_method(
+            wtclientrpc::GetTowerInfoRequest {
+                pubkey: decode_pubkey(pubkey)?,
+                include_sessions: true,
+                exclude_exhausted_sessions: false,
+            },
+            watchtowerClientGetTowerInfo,
+        )?;
+        Ok(TowerInfo::from_lnd(tower))
+    }
+
+    /// Returns the backup statistics.
+    pub fn stats(&self) -> Result<CoverageStats> {
+        let stats: wtclientrpc::StatsResponse = self
+            .client
+            .call_lnd_method(wtclientrpc::StatsRequest {}, watchtowerClientStats)?;
+        Ok(CoverageStats {
+            num_backups: stats.num_backups,
+            num_pending_backups: stats.num_pending_backups,
+            num_failed_backups: stats.num_failed_backups,
+            num_sessions_acquired: stats.num_sessions_acquired,
+            num_sessions_exhausted: stats.num_sessions_exhausted,
+        })
+    }
+
+    /// Returns the parameters new sessions of the given policy are negotiated with.
+    pub fn policy(&self, policy: TowerPolicy) -> Result<TowerPolicyInfo> {
+        let response: wtclientrpc::PolicyResponse = self.client.call_lnd_method(
+            wtclientrpc::PolicyRequest {
+                policy_type: policy.to_lnd() as i32,
+            },
+            watchtowerClientPolicy,
+        )?;
+        Ok(TowerPolicyInfo {
+            max_updates: response.max_updates,
+            sweep_sat_per_vbyte: response.sweep_sat_per_vbyte,
+        })
+    }
+
+    /// Returns the URIs of the node's own tower, if it runs one.
+    pub fn own_tower_uris(&self) -> Result<Vec<String>> {
+        let info: watchtowerrpc::GetInfoResponse = self
+            .client
+            .call_lnd_method(watchtowerrpc::GetInfoRequest {}, watchtowerGetInfo)?;
+        Ok(info.uris)
+    }
+
+    /// Checks that backups are being made.
+    ///
+    /// Flags when no tower is active, towers whose sessions are all full, and backups
+    /// rejected by towers.
+    pub fn health_check(&self) -> Result<HealthReport> {
+        let towers = self.list_towers()?;
+        let stats = self.stats()?;
+
+        let mut issues = Vec::new();
+        if !towers.iter().any(|tower| tower.active) {
+            issues.push(TowerIssue::NoActiveTower);
+        }
+        for tower in towers.iter().filter(|tower| tower.active) {
+            if !tower.sessions.is_empty() && tower.sessions.iter().all(SessionInfo::is_exhausted) {
+                issues.push(TowerIssue::SessionsExhausted {
+                    pubkey: tower.pubkey.clone(),
+                });
+            }
+        }
+        if stats.num_failed_backups > 0 {
+            issues.push(TowerIssue::FailedBackups(stats.num_failed_backups));
+        }
+        Ok(HealthReport {
+            towers,
+            stats,
+            issues,
+        })
+    }
+}
+
+fn decode_pubkey(pubkey: &str) -> Result<Vec<u8>> {
+    hex::decode(pubkey).with_context(|| format!("Invalid tower key {}", pubkey))
+}